@@ -9,10 +9,25 @@
 
 mod tls;
 
-use crate::tls::TlsString;
-use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
-use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+pub use crate::tls::TlsString;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+use tls_codec::{DeserializeBytes as _, TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+/// The member count constraint of a role that was violated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinMaxKind {
+    Min,
+    Max,
+    /// Reserved for the minimum number of active holders. Not reported until the room state tracks which members are active.
+    ActiveMin,
+    /// Reserved for the maximum number of active holders. Not reported until the room state tracks which members are active.
+    ActiveMax,
+}
 
 #[derive(Debug, PartialEq, thiserror::Error)]
 pub enum Error {
@@ -33,8 +48,13 @@ pub enum Error {
     RoleDependencyViolated,
 
     /// Too few or too many users would have a role.
-    #[error("Role minimum or maximum member count violated")]
-    RoleMinMaxViolated,
+    #[error("Role {role:?} violates its {kind:?} member count of {limit} with {actual} members")]
+    RoleMinMaxViolated {
+        role: RoleIndex,
+        kind: MinMaxKind,
+        limit: u32,
+        actual: u32,
+    },
 
     /// The user does not have the required capability or the target is protected from the user.
     #[error("User did not have a required capability")]
@@ -48,6 +68,10 @@ pub enum Error {
     #[error("Role is special and must have special properties")]
     SpecialRole,
 
+    /// The upload exceeds the size limit or has a MIME type the room does not allow.
+    #[error("Media policy violated")]
+    MediaPolicyViolated,
+
     /// A string value could not be set, because it is too long.
     #[error("String too long")]
     StringTooLong,
@@ -71,10 +95,137 @@ pub enum Error {
         source_role: RoleIndex,
         target_role: RoleIndex,
     },
+
+    /// The target state cannot be reached by applying proposals to the current state.
+    #[error("Target state is irreconcilable")]
+    Irreconcilable,
+
+    /// The change would leave the room without an owner, although the policy requires one.
+    #[error("Would remove the last owner")]
+    WouldRemoveLastOwner,
+
+    /// A bot with this name is already allowed in the room.
+    #[error("Duplicate bot")]
+    DuplicateBot,
+
+    /// The bot is allowed to do more than its role.
+    #[error("Bot definition invalid")]
+    InvalidBotDefinition,
+
+    /// Users cannot be added or removed, because the membership of the room is fixed.
+    #[error("Membership is fixed")]
+    MembershipFixed,
+
+    /// The chain of parent rooms is longer than [`MAX_PARENT_DEPTH`].
+    #[error("Parent room chain too deep")]
+    ParentChainTooDeep,
+
+    /// The room depends on a parent room that was not provided.
+    #[error("Parent room missing")]
+    ParentRoomMissing,
+
+    /// The input could not be decoded.
+    #[error("Decoding error")]
+    DecodingError,
+
+    /// A room state or the value of a policy extension could not be decoded.
+    #[error("Decoding error: {0}")]
+    Decode(String),
+
+    /// The role inherits its capabilities from itself through a chain of parent roles.
+    #[error("Role {role:?} inherits from itself")]
+    InheritanceCycle { role: RoleIndex },
+
+    /// Holders of the role can move themselves to a role with strictly more capabilities.
+    #[error("Role {from:?} can escalate itself to {to:?}")]
+    SelfEscalation { from: RoleIndex, to: RoleIndex },
+
+    /// A user id is empty or does not have the expected format.
+    #[error("Invalid user id")]
+    InvalidUserId,
+
+    /// No role can transition into the custom role, so no user can ever hold it.
+    #[error("Role {role:?} is unreachable")]
+    UnreachableRole { role: RoleIndex },
+
+    /// A call could not be started, because another call is already active.
+    #[error("Call already in progress")]
+    CallInProgress,
+
+    /// The call is not active in the room.
+    #[error("Call not found")]
+    CallNotFound,
+
+    /// The policy proposal is not pending in the room.
+    #[error("Policy proposal not found")]
+    ProposalNotFound,
+
+    /// Policy changes need the approval of several users, see [`VerifiedRoomState::propose_policy`].
+    #[error("Policy change requires approval")]
+    ApprovalRequired,
+
+    /// There are [`MAX_PENDING_POLICY_PROPOSALS`] pending policy proposals already, or no unused proposal id is left.
+    #[error("Too many policy proposals")]
+    TooManyProposals,
+
+    /// Users with the role can never leave the room by changing their own role.
+    #[error("Role {role:?} has no exit path")]
+    NoExitPath { role: RoleIndex },
+
+    /// The room was destroyed and does not accept proposals.
+    #[error("Room destroyed")]
+    RoomDestroyed,
+
+    /// A policy extension is larger than [`MAX_EXTENSION_SIZE`].
+    #[error("Policy extension invalid")]
+    InvalidExtension,
+
+    /// The policy extensions together are larger than [`MAX_EXTENSIONS_SIZE`].
+    #[error("Policy too large")]
+    PolicyTooLarge,
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Maximum length of role names in bytes.
+pub const MAX_ROLE_NAME_LENGTH: usize = 256;
+
+/// Maximum length of role descriptions in bytes.
+pub const MAX_ROLE_DESCRIPTION_LENGTH: usize = 4096;
+
+/// Maximum length of all other strings in the room policy in bytes.
+///
+/// Independently of this limit, the TLS encoding cannot represent strings of 2^30 bytes or more.
+pub const MAX_POLICY_STRING_LENGTH: usize = 4096;
+
+/// Maximum number of parent rooms that are followed when resolving a [`MembershipStyle::ParentDependent`] room.
+pub const MAX_PARENT_DEPTH: usize = 8;
+
+/// Maximum length of the room name in bytes.
+pub const MAX_ROOM_NAME_LENGTH: usize = 256;
+
+/// Maximum length of the room description in bytes.
+pub const MAX_ROOM_DESCRIPTION_LENGTH: usize = 4096;
+
+/// Maximum length of the room avatar URI in bytes.
+pub const MAX_ROOM_AVATAR_LENGTH: usize = 2048;
+
+/// Maximum size of the value of a single policy extension in bytes.
+pub const MAX_EXTENSION_SIZE: usize = 16 * 1024;
+
+/// Maximum size of the values of all policy extensions together in bytes.
+pub const MAX_EXTENSIONS_SIZE: usize = 64 * 1024;
+
+/// Maximum number of policy proposals that can be pending at the same time.
+pub const MAX_PENDING_POLICY_PROPOSALS: usize = 16;
+
+fn check_length(value: &str, max_length: usize) -> Result<()> {
+    if value.len() > max_length {
+        return Err(Error::StringTooLong);
+    }
+    Ok(())
+}
+
 /// The specified roles have a special features in the room policy.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u32)]
@@ -122,6 +273,18 @@ impl RoleIndex {
             u => RoleIndex::Custom(u),
         }
     }
+
+    /// Rank used to pick the primary role of a user holding several roles. Higher is more privileged.
+    fn privilege(&self) -> u8 {
+        match self {
+            RoleIndex::Outsider => 0,
+            RoleIndex::Banned => 1,
+            RoleIndex::Custom(_) => 2,
+            RoleIndex::Regular => 3,
+            RoleIndex::Admin => 4,
+            RoleIndex::Owner => 5,
+        }
+    }
 }
 impl tls_codec::Serialize for RoleIndex {
     fn tls_serialize<W: std::io::Write>(
@@ -159,6 +322,8 @@ impl<'de> Deserialize<'de> for RoleIndex {
 }
 
 /// The definition of a role for the room policy.
+///
+/// The serde keys are pinned with `rename` and are independent of the field names.
 #[derive(
     Debug,
     Clone,
@@ -172,17 +337,266 @@ impl<'de> Deserialize<'de> for RoleIndex {
     TlsDeserializeBytes,
 )]
 pub struct RoleInfo {
+    #[serde(rename = "name")]
     role_name: TlsString,
+    #[serde(rename = "description")]
     role_description: TlsString,
-    role_capabilities: Vec<Capability>, // TODO: This could also be a bitvector
+    #[serde(rename = "caps")]
+    role_capabilities: CapabilitySet,
 
+    #[serde(rename = "min")]
     min_participants_constraint: u32,
+    #[serde(rename = "max")]
     max_participants_constraint: Option<u32>,
+    #[serde(rename = "min_active")]
     min_active_participants_constraint: u32,
+    #[serde(rename = "max_active")]
     max_active_participants_constraint: Option<u32>,
+    /// The role changes holders of this role can make for other users. Each key is the current role of the target, and the values are the roles the target can be moved to. A key cannot list itself as a value.
     #[tls_codec(with = "tls::btreemap")]
+    #[serde(rename = "role_changes")]
     authorized_role_changes: BTreeMap<RoleIndex, Vec<RoleIndex>>,
+    /// The roles holders of this role can move themselves to.
+    #[serde(rename = "self_role_changes")]
+    #[tls_codec(with = "tls::vec")]
     self_role_changes: Vec<RoleIndex>,
+    /// How long holders of the role can see the history in seconds, overriding [`HistoryPolicy`]'s `max_time_period`.
+    #[serde(rename = "retention")]
+    retention_override: Option<u32>,
+    /// Roles whose holders cannot be changed by holders of this role, even if [`RoleInfo`]'s `authorized_role_changes` allow it.
+    #[serde(rename = "protected")]
+    #[tls_codec(with = "tls::vec")]
+    protected_roles: Vec<RoleIndex>,
+    /// The role whose capabilities, including the ones it inherits, this role has as well.
+    #[serde(rename = "inherits")]
+    inherits_from: Option<RoleIndex>,
+    /// How many messages holders of this role can send, enforced by the application. No limit if `None`.
+    #[serde(rename = "send_rate_limit")]
+    send_rate_limit: Option<RateLimit>,
+    /// Whether holders of this role are counted for the participant constraints. Users holding a role that is not counted, like observers, are still counted for their other roles.
+    #[tls_codec(with = "tls::bool")]
+    #[serde(rename = "counts_toward_membership")]
+    counts_toward_membership: bool,
+}
+
+/// A limit of `max_messages` per `per_seconds` seconds. The window must not be empty.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct RateLimit {
+    pub max_messages: u32,
+    pub per_seconds: u32,
+}
+
+impl RateLimit {
+    /// Whether the limit allows a higher rate than the other limit.
+    fn allows_more_than(&self, other: &RateLimit) -> bool {
+        u64::from(self.max_messages) * u64::from(other.per_seconds)
+            > u64::from(other.max_messages) * u64::from(self.per_seconds)
+    }
+}
+
+impl RoleInfo {
+    /// A role without capabilities, participant constraints or role changes. The other properties can be set with the `with_*` methods.
+    ///
+    /// ```
+    /// use mimi_room_policy::{Capability, RoleIndex, RoleInfo};
+    ///
+    /// let moderator = RoleInfo::new("Moderator")
+    ///     .with_capabilities([Capability::SendMessage, Capability::DeleteAnyMessage])
+    ///     .with_max_participants(3)
+    ///     .with_self_role_changes([RoleIndex::Outsider]);
+    ///
+    /// assert_eq!(moderator.name(), "Moderator");
+    /// assert!(moderator.has_capability(&Capability::DeleteAnyMessage));
+    /// assert!(!moderator.has_capability(&Capability::ReceiveMessage));
+    /// ```
+    pub fn new(name: &str) -> Self {
+        Self {
+            role_name: TlsString(name.to_owned()),
+            role_description: TlsString("".to_owned()),
+            role_capabilities: CapabilitySet::default(),
+            min_participants_constraint: 0,
+            max_participants_constraint: None,
+            min_active_participants_constraint: 0,
+            max_active_participants_constraint: None,
+            authorized_role_changes: BTreeMap::new(),
+            self_role_changes: Vec::new(),
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
+        }
+    }
+
+    /// Keep only what both roles allow: the shared capabilities and role changes, the tighter participant constraints and the protected roles of both.
+    fn restrict_to(&mut self, other: &RoleInfo) {
+        self.role_capabilities = self
+            .role_capabilities
+            .iter()
+            .filter(|capability| other.role_capabilities.contains(capability.clone()))
+            .collect();
+
+        self.min_participants_constraint = self
+            .min_participants_constraint
+            .max(other.min_participants_constraint);
+        self.max_participants_constraint = match (
+            self.max_participants_constraint,
+            other.max_participants_constraint,
+        ) {
+            (Some(max), Some(other_max)) => Some(max.min(other_max)),
+            (max, other_max) => max.or(other_max),
+        };
+        self.min_active_participants_constraint = self
+            .min_active_participants_constraint
+            .max(other.min_active_participants_constraint);
+        self.max_active_participants_constraint = match (
+            self.max_active_participants_constraint,
+            other.max_active_participants_constraint,
+        ) {
+            (Some(max), Some(other_max)) => Some(max.min(other_max)),
+            (max, other_max) => max.or(other_max),
+        };
+
+        for (source, targets) in self.authorized_role_changes.iter_mut() {
+            let other_targets = other
+                .authorized_role_changes
+                .get(source)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            targets.retain(|target| other_targets.contains(target));
+        }
+        self.authorized_role_changes
+            .retain(|_, targets| !targets.is_empty());
+        self.self_role_changes
+            .retain(|target| other.self_role_changes.contains(target));
+        self.counts_toward_membership &= other.counts_toward_membership;
+
+        for role in &other.protected_roles {
+            if !self.protected_roles.contains(role) {
+                self.protected_roles.push(*role);
+            }
+        }
+        self.protected_roles.sort();
+
+        self.retention_override = match (self.retention_override, other.retention_override) {
+            (Some(retention), Some(other_retention)) => Some(retention.min(other_retention)),
+            (retention, other_retention) => retention.or(other_retention),
+        };
+        self.send_rate_limit = match (self.send_rate_limit, other.send_rate_limit) {
+            (Some(limit), Some(other_limit)) if limit.allows_more_than(&other_limit) => {
+                Some(other_limit)
+            }
+            (limit, other_limit) => limit.or(other_limit),
+        };
+    }
+
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.role_description = TlsString(description.to_owned());
+        self
+    }
+
+    /// Add the capabilities to the role.
+    pub fn with_capabilities(mut self, capabilities: impl IntoIterator<Item = Capability>) -> Self {
+        for capability in capabilities {
+            self.role_capabilities.insert(capability);
+        }
+        self
+    }
+
+    pub fn with_min_participants(mut self, min: u32) -> Self {
+        self.min_participants_constraint = min;
+        self
+    }
+
+    pub fn with_max_participants(mut self, max: u32) -> Self {
+        self.max_participants_constraint = Some(max);
+        self
+    }
+
+    /// Set the minimum number of active holders of the role. The room state does not track which members are active, so this is only checked against the maximum member counts of the role.
+    pub fn with_min_active_participants(mut self, min: u32) -> Self {
+        self.min_active_participants_constraint = min;
+        self
+    }
+
+    /// Set the maximum number of active holders of the role. Like the minimum, this is not checked against the room state.
+    pub fn with_max_active_participants(mut self, max: u32) -> Self {
+        self.max_active_participants_constraint = Some(max);
+        self
+    }
+
+    /// Allow holders of the role to move other users from the source role to the target roles.
+    pub fn with_role_changes(
+        mut self,
+        source_role: RoleIndex,
+        target_roles: impl IntoIterator<Item = RoleIndex>,
+    ) -> Self {
+        self.authorized_role_changes
+            .entry(source_role)
+            .or_default()
+            .extend(target_roles);
+        self
+    }
+
+    /// Allow holders of the role to move themselves to the target roles.
+    pub fn with_self_role_changes(
+        mut self,
+        target_roles: impl IntoIterator<Item = RoleIndex>,
+    ) -> Self {
+        self.self_role_changes.extend(target_roles);
+        self
+    }
+
+    pub fn with_retention_override(mut self, seconds: u32) -> Self {
+        self.retention_override = Some(seconds);
+        self
+    }
+
+    pub fn with_send_rate_limit(mut self, max_messages: u32, per_seconds: u32) -> Self {
+        self.send_rate_limit = Some(RateLimit {
+            max_messages,
+            per_seconds,
+        });
+        self
+    }
+
+    /// Exclude holders of the role from the participant constraints of all roles, e.g. for observers.
+    pub fn with_counts_toward_membership(mut self, counts: bool) -> Self {
+        self.counts_toward_membership = counts;
+        self
+    }
+
+    /// Let the role have all capabilities of the parent role.
+    pub fn with_inherits_from(mut self, parent: RoleIndex) -> Self {
+        self.inherits_from = Some(parent);
+        self
+    }
+
+    /// Forbid holders of the role to change users holding the protected roles.
+    pub fn with_protected_roles(mut self, roles: impl IntoIterator<Item = RoleIndex>) -> Self {
+        self.protected_roles.extend(roles);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.role_name
+    }
+
+    pub fn has_capability(&self, capability: &Capability) -> bool {
+        self.role_capabilities.contains(capability.clone())
+    }
 }
 
 #[derive(
@@ -201,30 +615,23 @@ pub struct RoleInfo {
 )]
 #[repr(u8)]
 pub enum Capability {
-    // AddParticipant,
-    // RemoveParticipant,
     // AddOwnClient,
     // RemoveSelf,
     // AddSelf,
     // CreateJoinCode, // reserved for future use
     // UseJoinCode,
     // Ban,
-    // UnBan,
     // Kick,
     // Knock,
     // AcceptKnock,
     // ChangeUserRole,
-    // ChangeOwnRole,
-    // CreateSubgroup,
     SendMessage,
     ReceiveMessage,
     // CopyMessage,
-    // ReportAbuse,
     ReactToMessage,
     // EditReaction,
     DeleteReaction,
     EditOwnMessage,
-    // EditOtherMessage,
     DeleteOwnMessage,
     DeleteAnyMessage,
     // StartTopic,
@@ -261,10 +668,187 @@ pub enum Capability {
     // ViewSharedScreen,
     // ChangeRoomMembershipStyle,
     ChangeRoleDefinitions,
-    // ChangePreauthorizedUserList,
     // ChangeMlsOperationalPolicies,
-    // DestroyRoom,
     // SendMLSReinitProposal,
+
+    // New capabilities are appended to keep the encoding of existing ones stable.
+    EditOtherMessage,
+    UnBan,
+    ChangePreauthorizedUserList,
+    DestroyRoom,
+    ReportAbuse,
+    ResolveIdentity,
+    CreateSubgroup,
+    AddParticipant,
+    RemoveParticipant,
+    MuteUser,
+    ChangeOwnRole,
+}
+
+impl Capability {
+    /// All capabilities, in the order of their encoding.
+    pub const ALL: &[Capability] = &[
+        Capability::SendMessage,
+        Capability::ReceiveMessage,
+        Capability::ReactToMessage,
+        Capability::DeleteReaction,
+        Capability::EditOwnMessage,
+        Capability::DeleteOwnMessage,
+        Capability::DeleteAnyMessage,
+        Capability::UploadImage,
+        Capability::UploadVideo,
+        Capability::UploadAttachment,
+        Capability::ChangeRoomName,
+        Capability::ChangeRoomDescription,
+        Capability::ChangeRoomAvatar,
+        Capability::StartCall,
+        Capability::JoinCall,
+        Capability::ChangeRoleDefinitions,
+        Capability::EditOtherMessage,
+        Capability::UnBan,
+        Capability::ChangePreauthorizedUserList,
+        Capability::DestroyRoom,
+        Capability::ReportAbuse,
+        Capability::ResolveIdentity,
+        Capability::CreateSubgroup,
+        Capability::AddParticipant,
+        Capability::RemoveParticipant,
+        Capability::MuteUser,
+        Capability::ChangeOwnRole,
+    ];
+
+    /// Whether the capability is high-risk and roles holding it should be reviewed by moderators.
+    ///
+    /// These are [`Capability::ChangeRoleDefinitions`], which allows changing the rules of the room, [`Capability::DeleteAnyMessage`], which allows deleting the messages of other users, and [`Capability::DestroyRoom`].
+    pub fn is_sensitive(&self) -> bool {
+        matches!(
+            self,
+            Capability::ChangeRoleDefinitions
+                | Capability::DeleteAnyMessage
+                | Capability::DestroyRoom
+        )
+    }
+
+    /// Whether the capability only lets the holder observe the room. These are the only capabilities [`RoleIndex::Outsider`] can have, e.g. to preview a public room before joining it.
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, Capability::ReceiveMessage)
+    }
+}
+
+/// A set of capabilities, e.g. those granted to a role or those of a user as returned by [`VerifiedRoomState::capability_set`]. It is stored as a bitset indexed by the encoding of [`Capability`].
+///
+/// On the wire this is a single `u64`, not the vector of capabilities that was used before. Policies encoded with the old format cannot be decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "u64", into = "u64")]
+pub struct CapabilitySet(u64);
+
+// Every capability needs its own bit of the `u64`
+const _: () = assert!(Capability::ALL.len() <= 64);
+
+impl CapabilitySet {
+    fn bit(capability: &Capability) -> u64 {
+        1 << (capability.clone() as u8)
+    }
+
+    fn insert(&mut self, capability: Capability) {
+        self.0 |= Self::bit(&capability);
+    }
+
+    fn remove(&mut self, capability: &Capability) {
+        self.0 &= !Self::bit(capability);
+    }
+
+    /// Whether the set holds the capability.
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.0 & Self::bit(&capability) != 0
+    }
+
+    /// The number of capabilities in the set.
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Whether the set holds no capabilities.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn is_strict_superset(&self, other: &CapabilitySet) -> bool {
+        self.0 & other.0 == other.0 && self.0 != other.0
+    }
+
+    /// The capabilities in the set, in the order of their encoding.
+    pub fn iter(&self) -> impl Iterator<Item = Capability> + '_ {
+        Capability::ALL
+            .iter()
+            .filter(|&capability| self.contains(capability.clone()))
+            .cloned()
+    }
+}
+
+impl Extend<Capability> for CapabilitySet {
+    fn extend<T: IntoIterator<Item = Capability>>(&mut self, iter: T) {
+        for capability in iter {
+            self.insert(capability);
+        }
+    }
+}
+
+impl FromIterator<Capability> for CapabilitySet {
+    fn from_iter<T: IntoIterator<Item = Capability>>(iter: T) -> Self {
+        let mut set = Self::default();
+        for capability in iter {
+            set.insert(capability);
+        }
+        set
+    }
+}
+
+impl<const N: usize> From<[Capability; N]> for CapabilitySet {
+    fn from(capabilities: [Capability; N]) -> Self {
+        capabilities.into_iter().collect()
+    }
+}
+
+impl TryFrom<u64> for CapabilitySet {
+    type Error = Error;
+
+    /// Fails if a bit does not correspond to a known capability.
+    fn try_from(bits: u64) -> Result<Self> {
+        let set = Self(bits);
+        if set.iter().count() != bits.count_ones() as usize {
+            return Err(Error::DecodingError);
+        }
+        Ok(set)
+    }
+}
+
+impl From<CapabilitySet> for u64 {
+    fn from(set: CapabilitySet) -> Self {
+        set.0
+    }
+}
+
+impl tls_codec::Size for CapabilitySet {
+    fn tls_serialized_len(&self) -> usize {
+        self.0.tls_serialized_len()
+    }
+}
+
+impl tls_codec::Serialize for CapabilitySet {
+    fn tls_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, tls_codec::Error> {
+        self.0.tls_serialize(writer)
+    }
+}
+
+impl tls_codec::DeserializeBytes for CapabilitySet {
+    fn tls_deserialize_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), tls_codec::Error> {
+        let (bits, rest) = u64::tls_deserialize_bytes(bytes)?;
+        let set = Self::try_from(bits).map_err(|_| {
+            tls_codec::Error::DecodingError("Unknown capability in set.".to_owned())
+        })?;
+        Ok((set, rest))
+    }
 }
 
 #[derive(
@@ -284,7 +868,174 @@ pub enum MimiProposal {
     //
     // Join a room, leave a room, kick a user, ban a user.
     //
-    ChangeRole { target: Vec<u8>, role: RoleIndex },
+    ChangeRole {
+        #[tls_codec(with = "tls::vec")]
+        target: Vec<u8>,
+        role: RoleIndex,
+    },
+    /// Change the role of the target until `expires_at`, after which the role is replaced by `fallback_role`.
+    ChangeRoleUntil {
+        #[tls_codec(with = "tls::vec")]
+        target: Vec<u8>,
+        role: RoleIndex,
+        expires_at: u64,
+        fallback_role: RoleIndex,
+    },
+    /// Remove the capabilities from the target, regardless of their roles. The mute survives leaving the room. An empty list lifts the mute, also for users that left the room in the meantime.
+    MuteUser {
+        #[tls_codec(with = "tls::vec")]
+        target: Vec<u8>,
+        #[tls_codec(with = "tls::vec")]
+        capabilities: Vec<Capability>,
+    },
+}
+
+/// Proposals that change the room policy. Changes of the preauthorized users require [`Capability::ChangePreauthorizedUserList`], all others require [`Capability::ChangeRoleDefinitions`].
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+#[repr(u8)]
+pub enum PolicyProposal {
+    AddBot {
+        name: TlsString,
+        bot: Bot,
+    },
+    RemoveBot {
+        name: TlsString,
+    },
+    SetPreauthorized {
+        #[tls_codec(with = "tls::vec")]
+        user: Vec<u8>,
+        role: RoleIndex,
+    },
+    RemovePreauthorized {
+        #[tls_codec(with = "tls::vec")]
+        user: Vec<u8>,
+    },
+    RenameRole {
+        index: RoleIndex,
+        name: TlsString,
+        description: TlsString,
+    },
+    ChangeSetting(RoomSetting),
+    /// Add the extension, replacing an extension with the same name.
+    SetExtension(PolicyExtension),
+    RemoveExtension {
+        name: TlsString,
+    },
+}
+
+/// A room-wide setting of the policy that can be changed with [`PolicyProposal::ChangeSetting`].
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+#[repr(u8)]
+pub enum RoomSetting {
+    MembershipStyle(MembershipStyle),
+    MultiDevice(#[tls_codec(with = "tls::bool")] bool),
+    ParentRoomUri(TlsString),
+    PersistentRoom(#[tls_codec(with = "tls::bool")] bool),
+    DeliveryNotifications(Optionality),
+    ReadReceipts(Optionality),
+    SemiAnonymousIds(#[tls_codec(with = "tls::bool")] bool),
+    Discoverable(#[tls_codec(with = "tls::bool")] bool),
+    AllowConcurrentCalls(#[tls_codec(with = "tls::bool")] bool),
+    RequiredApprovals(u32),
+}
+
+impl PolicyProposal {
+    fn required_capability(&self) -> Capability {
+        match self {
+            PolicyProposal::AddBot { .. }
+            | PolicyProposal::RemoveBot { .. }
+            | PolicyProposal::RenameRole { .. }
+            | PolicyProposal::ChangeSetting(_)
+            | PolicyProposal::SetExtension(_)
+            | PolicyProposal::RemoveExtension { .. } => Capability::ChangeRoleDefinitions,
+            PolicyProposal::SetPreauthorized { .. }
+            | PolicyProposal::RemovePreauthorized { .. } => Capability::ChangePreauthorizedUserList,
+        }
+    }
+}
+
+impl MimiProposal {
+    /// Whether the proposal could be undone after it is applied to the state, either by the target itself or by another member of the room. The reverse proposal is checked like any other proposal, see [`VerifiedRoomState::classify_proposal`]. Proposals that no member can apply are not reversible.
+    ///
+    /// For rooms with [`MembershipStyle::ParentDependent`] membership, `parent` is the state of the parent room.
+    pub fn is_reversible(
+        &self,
+        state: &VerifiedRoomState,
+        parent: Option<&VerifiedRoomState>,
+    ) -> bool {
+        let original = state.unverified();
+        let (target, reverse) = match self {
+            MimiProposal::ChangeRole { target, .. }
+            | MimiProposal::ChangeRoleUntil { target, .. } => (
+                target,
+                MimiProposal::ChangeRole {
+                    target: target.clone(),
+                    role: original.user_role(target),
+                },
+            ),
+            MimiProposal::MuteUser { target, .. } => (
+                target,
+                MimiProposal::MuteUser {
+                    target: target.clone(),
+                    capabilities: original
+                        .capability_overrides
+                        .get(target)
+                        .map(|capability_override| capability_override.removed.iter().collect())
+                        .unwrap_or_default(),
+                },
+            ),
+        };
+
+        // The state after the proposal, applied by any user that may apply it
+        let Some(applied) = original.users.keys().chain([target]).find_map(|sender| {
+            let mut applied = original.clone();
+            applied
+                .try_regular_proposals(
+                    sender,
+                    std::slice::from_ref(self),
+                    parent.map(|parent| &parent.0),
+                )
+                .ok()?;
+            VerifiedRoomState::verify(applied).ok()
+        }) else {
+            return false;
+        };
+        if applied == *state {
+            return true;
+        }
+
+        let reversible = applied
+            .unverified()
+            .users
+            .keys()
+            .chain([target])
+            .any(|sender| {
+                applied.classify_proposal(sender, &reverse, parent) == ProposalOutcome::WouldApply
+            });
+        reversible
+    }
 }
 
 #[derive(
@@ -329,6 +1080,33 @@ pub struct LinkPolicy {
     link_requests: TlsString,
 }
 
+impl LinkPolicy {
+    /// Whether users can request to join through the link instead of joining directly.
+    pub fn on_request(&self) -> bool {
+        self.on_request
+    }
+
+    /// The URI users join the room through. Empty if the room has no join link.
+    pub fn join_link(&self) -> &str {
+        &self.join_link
+    }
+
+    /// Whether the link can be used by more than one user.
+    pub fn multiuser(&self) -> bool {
+        self.multiuser
+    }
+
+    /// How long the join link stays valid after it was created in seconds.
+    pub fn expiration(&self) -> u32 {
+        self.expiration
+    }
+
+    /// The URI that join requests made through the link are sent to, if [`LinkPolicy::on_request`] is set.
+    pub fn link_requests(&self) -> &str {
+        &self.link_requests
+    }
+}
+
 #[derive(
     Debug,
     Clone,
@@ -341,13 +1119,36 @@ pub struct LinkPolicy {
     TlsSerialize,
     TlsDeserializeBytes,
 )]
-struct LoggingPolicy {
+pub struct LoggingPolicy {
     logging: Optionality,
+    #[tls_codec(with = "tls::vec")]
     logging_clients: Vec<TlsString>,
     machine_readable_policy: TlsString,
     human_readable_policy: TlsString,
 }
 
+impl LoggingPolicy {
+    /// Whether messages in the room may, must or must not be logged.
+    pub fn logging(&self) -> &Optionality {
+        &self.logging
+    }
+
+    /// The clients that are allowed to log the room.
+    pub fn logging_clients(&self) -> impl Iterator<Item = &str> + '_ {
+        self.logging_clients.iter().map(|client| client.as_str())
+    }
+
+    /// The URI of the logging policy in a machine-readable format.
+    pub fn machine_readable_policy(&self) -> &str {
+        &self.machine_readable_policy
+    }
+
+    /// The URI of the logging policy in a human-readable format.
+    pub fn human_readable_policy(&self) -> &str {
+        &self.human_readable_policy
+    }
+}
+
 #[derive(
     Debug,
     Clone,
@@ -360,27 +1161,50 @@ struct LoggingPolicy {
     TlsSerialize,
     TlsDeserializeBytes,
 )]
-struct HistoryPolicy {
+pub struct HistoryPolicy {
     history_sharing: Optionality,
+    #[tls_codec(with = "tls::vec")]
     who_can_share: Vec<RoleIndex>,
     #[tls_codec(with = "tls::bool")]
     automatically_share: bool,
     max_time_period: u32,
 }
 
-#[derive(
-    Debug,
-    Clone,
-    PartialEq,
-    Eq,
-    Hash,
-    Serialize,
+impl HistoryPolicy {
+    /// Whether the history of the room may, must or must not be shared with new members.
+    pub fn history_sharing(&self) -> &Optionality {
+        &self.history_sharing
+    }
+
+    /// The roles whose holders can share the history with new members.
+    pub fn who_can_share(&self) -> &[RoleIndex] {
+        &self.who_can_share
+    }
+
+    /// Whether the history is shared with new members without an explicit action by a member.
+    pub fn automatically_share(&self) -> bool {
+        self.automatically_share
+    }
+
+    /// How far back the history can be shared in seconds.
+    pub fn max_time_period(&self) -> u32 {
+        self.max_time_period
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
     Deserialize,
     TlsSize,
     TlsSerialize,
     TlsDeserializeBytes,
 )]
-struct Bot {
+pub struct Bot {
     description: TlsString,
     homepage: TlsString,
     bot_role: RoleIndex,
@@ -394,6 +1218,29 @@ struct Bot {
     per_user_content: bool,
 }
 
+impl Bot {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        description: &str,
+        homepage: &str,
+        bot_role: RoleIndex,
+        can_read: bool,
+        can_write: bool,
+        can_target_message_in_group: bool,
+        per_user_content: bool,
+    ) -> Result<Self> {
+        Ok(Self {
+            description: TlsString::try_new(description, MAX_POLICY_STRING_LENGTH)?,
+            homepage: TlsString::try_new(homepage, MAX_POLICY_STRING_LENGTH)?,
+            bot_role,
+            can_read,
+            can_write,
+            can_target_message_in_group,
+            per_user_content,
+        })
+    }
+}
+
 #[derive(
     Debug,
     Clone,
@@ -408,10 +1255,148 @@ struct Bot {
 )]
 pub struct PolicyExtension {
     name: TlsString,
-    value_type: (),
+    value_type: ExtensionType,
+    /// Values larger than [`MAX_EXTENSION_SIZE`] are rejected when decoding.
+    #[tls_codec(with = "tls::extension_value")]
     value: Vec<u8>,
 }
 
+impl PolicyExtension {
+    /// An extension with an opaque value.
+    pub fn bytes(name: &str, value: Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            name: TlsString::try_new(name, MAX_POLICY_STRING_LENGTH)?,
+            value_type: ExtensionType::Bytes,
+            value,
+        })
+    }
+
+    /// An extension with a text value.
+    pub fn utf8(name: &str, value: &str) -> Result<Self> {
+        Ok(Self {
+            name: TlsString::try_new(name, MAX_POLICY_STRING_LENGTH)?,
+            value_type: ExtensionType::Utf8,
+            value: value.as_bytes().to_vec(),
+        })
+    }
+
+    /// An extension with a CBOR-encoded value.
+    pub fn cbor<T: Serialize>(name: &str, value: &T) -> Result<Self> {
+        let mut encoded = Vec::new();
+        ciborium::ser::into_writer(value, &mut encoded)
+            .map_err(|error| Error::Decode(error.to_string()))?;
+        Ok(Self {
+            name: TlsString::try_new(name, MAX_POLICY_STRING_LENGTH)?,
+            value_type: ExtensionType::Cbor,
+            value: encoded,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value_type(&self) -> ExtensionType {
+        self.value_type
+    }
+
+    /// The raw value, regardless of its type.
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// The text value. Fails with [`Error::Decode`] if the extension is not a valid [`ExtensionType::Utf8`] extension.
+    pub fn as_utf8(&self) -> Result<&str> {
+        self.expect_type(ExtensionType::Utf8)?;
+        std::str::from_utf8(&self.value).map_err(|error| Error::Decode(error.to_string()))
+    }
+
+    /// Decode the CBOR value. Fails with [`Error::Decode`] if the extension is not an [`ExtensionType::Cbor`] extension or the value is not a valid `T`.
+    pub fn as_cbor<T: DeserializeOwned>(&self) -> Result<T> {
+        self.expect_type(ExtensionType::Cbor)?;
+        ciborium::de::from_reader(self.value.as_slice())
+            .map_err(|error| Error::Decode(error.to_string()))
+    }
+
+    fn expect_type(&self, value_type: ExtensionType) -> Result<()> {
+        if self.value_type != value_type {
+            return Err(Error::Decode(format!(
+                "extension {} has type {:?}, not {value_type:?}",
+                self.name, self.value_type
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// How the value of a [`PolicyExtension`] is encoded.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+#[repr(u8)]
+pub enum ExtensionType {
+    /// Opaque bytes.
+    Bytes = 0,
+    /// A UTF-8 string.
+    Utf8 = 1,
+    /// A CBOR-encoded value.
+    Cbor = 2,
+}
+
+/// Limits on the attachments members can upload.
+#[derive(
+    Debug,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct MediaPolicy {
+    /// The maximum size of an upload in bytes.
+    max_attachment_bytes: Option<u64>,
+    /// The MIME type prefixes uploads must match, e.g. `image/`. All types are allowed if empty.
+    #[tls_codec(with = "tls::vec")]
+    allowed_mime_prefixes: Vec<TlsString>,
+}
+
+impl MediaPolicy {
+    pub fn new(max_attachment_bytes: Option<u64>, allowed_mime_prefixes: &[&str]) -> Result<Self> {
+        Ok(Self {
+            max_attachment_bytes,
+            allowed_mime_prefixes: allowed_mime_prefixes
+                .iter()
+                .map(|prefix| TlsString::try_new(prefix, MAX_POLICY_STRING_LENGTH))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    fn allows(&self, mime: &str, bytes: u64) -> bool {
+        let mime = mime.to_ascii_lowercase();
+        self.max_attachment_bytes.is_none_or(|max| bytes <= max)
+            && (self.allowed_mime_prefixes.is_empty()
+                || self
+                    .allowed_mime_prefixes
+                    .iter()
+                    .any(|prefix| mime.starts_with(&prefix.to_ascii_lowercase())))
+    }
+}
+
 /// A value to indicate preference of a feature.
 #[derive(
     Debug,
@@ -438,6 +1423,8 @@ pub enum Optionality {
 }
 
 /// The set of rules that the room will follow.
+///
+/// The serde keys are pinned with `rename` and are independent of the field names.
 #[derive(
     Debug,
     Clone,
@@ -452,26 +1439,304 @@ pub enum Optionality {
 )]
 pub struct RoomPolicy {
     #[tls_codec(with = "tls::btreemap")]
+    #[serde(rename = "roles")]
     roles: BTreeMap<RoleIndex, RoleInfo>,
 
+    #[serde(rename = "membership")]
     membership_style: MembershipStyle,
     #[tls_codec(with = "tls::bool")]
+    #[serde(rename = "multi_device")]
     multi_device: bool,
+    #[serde(rename = "parent_room")]
     parent_room_uri: TlsString,
     #[tls_codec(with = "tls::bool")]
+    #[serde(rename = "persistent")]
     persistent_room: bool,
+    #[serde(rename = "delivery_notifications")]
     delivery_notifications: Optionality,
+    #[serde(rename = "read_receipts")]
     read_receipts: Optionality,
     #[tls_codec(with = "tls::bool")]
+    #[serde(rename = "semi_anonymous_ids")]
     semi_anonymous_ids: bool,
     #[tls_codec(with = "tls::bool")]
+    #[serde(rename = "discoverable")]
     discoverable: bool,
+    #[serde(rename = "links")]
     link_policy: LinkPolicy,
+    #[serde(rename = "logging")]
     logging_policy: LoggingPolicy,
+    #[serde(rename = "history")]
     history_sharing: HistoryPolicy,
     #[tls_codec(with = "tls::btreemap")]
+    #[serde(rename = "bots")]
     allowed_bots: BTreeMap<TlsString, Bot>,
+    #[serde(rename = "extensions")]
+    #[tls_codec(with = "tls::vec")]
     policy_extensions: Vec<PolicyExtension>,
+    /// Whether a call can be started while another call is active.
+    #[tls_codec(with = "tls::bool")]
+    #[serde(rename = "concurrent_calls")]
+    allow_concurrent_calls: bool,
+    /// Users that receive the given role instead of [`RoleIndex::Regular`] when they join.
+    #[tls_codec(with = "tls::btreemap")]
+    #[serde(rename = "preauthorized")]
+    preauthorized: BTreeMap<Vec<u8>, RoleIndex>,
+    #[serde(rename = "media")]
+    media_policy: MediaPolicy,
+    /// How many users must approve a policy proposal before it is applied. A value of 0 behaves like 1.
+    #[serde(rename = "required_approvals")]
+    required_approvals: u32,
+}
+
+/// The fields of a [`RoomPolicy`] besides the role definitions.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+#[repr(u8)]
+pub enum PolicyField {
+    MembershipStyle,
+    MultiDevice,
+    ParentRoomUri,
+    PersistentRoom,
+    DeliveryNotifications,
+    ReadReceipts,
+    SemiAnonymousIds,
+    Discoverable,
+    LinkPolicy,
+    LoggingPolicy,
+    HistorySharing,
+    AllowedBots,
+    PolicyExtensions,
+    AllowConcurrentCalls,
+    Preauthorized,
+    MediaPolicy,
+    RequiredApprovals,
+}
+
+/// The changes to a role that is defined in both policies of a [`PolicyDiff`].
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct RoleDiff {
+    pub role: RoleIndex,
+    #[tls_codec(with = "tls::vec")]
+    pub added_capabilities: Vec<Capability>,
+    #[tls_codec(with = "tls::vec")]
+    pub removed_capabilities: Vec<Capability>,
+}
+
+/// The differences between two room policies, as computed by [`RoomPolicy::diff`]. All lists are sorted.
+#[derive(
+    Debug,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct PolicyDiff {
+    #[tls_codec(with = "tls::vec")]
+    pub added_roles: Vec<RoleIndex>,
+    #[tls_codec(with = "tls::vec")]
+    pub removed_roles: Vec<RoleIndex>,
+    /// Roles whose definitions differ. The capability lists are empty if only other properties of the role changed.
+    #[tls_codec(with = "tls::vec")]
+    pub changed_roles: Vec<RoleDiff>,
+    #[tls_codec(with = "tls::vec")]
+    pub changed_fields: Vec<PolicyField>,
+}
+
+impl PolicyDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A single change in a [`PolicyPatch`].
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+#[repr(u8)]
+pub enum PolicyChange {
+    SetRole {
+        role: RoleIndex,
+        role_info: RoleInfo,
+    },
+    RemoveRole {
+        role: RoleIndex,
+    },
+    MembershipStyle(MembershipStyle),
+    MultiDevice(#[tls_codec(with = "tls::bool")] bool),
+    ParentRoomUri(TlsString),
+    PersistentRoom(#[tls_codec(with = "tls::bool")] bool),
+    DeliveryNotifications(Optionality),
+    ReadReceipts(Optionality),
+    SemiAnonymousIds(#[tls_codec(with = "tls::bool")] bool),
+    Discoverable(#[tls_codec(with = "tls::bool")] bool),
+    LinkPolicy(LinkPolicy),
+    LoggingPolicy(LoggingPolicy),
+    HistorySharing(HistoryPolicy),
+    AllowedBots(#[tls_codec(with = "tls::btreemap")] BTreeMap<TlsString, Bot>),
+    PolicyExtensions(#[tls_codec(with = "tls::vec")] Vec<PolicyExtension>),
+    AllowConcurrentCalls(#[tls_codec(with = "tls::bool")] bool),
+    Preauthorized(#[tls_codec(with = "tls::btreemap")] BTreeMap<Vec<u8>, RoleIndex>),
+    MediaPolicy(MediaPolicy),
+    RequiredApprovals(u32),
+}
+
+/// The changes between two room policies, as computed by [`RoomPolicy::patch`]. Unlike a full policy, a patch only contains the fields that changed.
+#[derive(
+    Debug,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct PolicyPatch {
+    #[tls_codec(with = "tls::vec")]
+    changes: Vec<PolicyChange>,
+}
+
+impl PolicyPatch {
+    pub fn changes(&self) -> &[PolicyChange] {
+        &self.changes
+    }
+}
+
+/// Capability requirements a host imposes on the policies of the rooms it hosts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyRequirements {
+    /// Capabilities that at least one role for members of the room must have.
+    pub required: Vec<Capability>,
+    /// Capabilities that no role may have.
+    pub forbidden: Vec<Capability>,
+}
+
+/// A requirement that a policy does not meet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unmet {
+    /// No role for members of the room has the required capability.
+    Missing(Capability),
+    /// The role has a forbidden capability.
+    Forbidden {
+        role: RoleIndex,
+        capability: Capability,
+    },
+}
+
+/// What applying a single proposal would do, as computed by [`VerifiedRoomState::classify_proposal`].
+#[derive(Debug, PartialEq)]
+pub enum ProposalOutcome {
+    /// The proposal is allowed, but does not change the state.
+    NoOp,
+    /// The proposal is allowed and changes the state.
+    WouldApply,
+    /// The proposal is not allowed.
+    Denied(Error),
+}
+
+/// Whether a user is in the room, as computed by [`VerifiedRoomState::membership_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MembershipStatus {
+    /// The user is in the room. This is their most privileged role.
+    Member(RoleIndex),
+    /// The user holds [`RoleIndex::Banned`].
+    Banned,
+    /// The user is not in the room.
+    Outsider,
+}
+
+/// What removing a capability from a role would change for the members of a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemovalImpact {
+    /// The number of members that would no longer have the capability.
+    pub members_losing: usize,
+    /// Whether no role would grant the capability anymore.
+    pub ungranted: bool,
+}
+
+/// Size measures of a policy, as computed by [`RoomPolicy::complexity_metrics`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyMetrics {
+    /// The number of defined roles.
+    pub roles: usize,
+    /// The number of authorized and self role changes over all roles.
+    pub transitions: usize,
+    /// The number of capabilities granted over all roles.
+    pub capabilities: usize,
+    /// The number of allowed bots.
+    pub bots: usize,
+    /// The number of policy extensions.
+    pub extensions: usize,
+}
+
+/// How [`RoomPolicy::merge`] resolves fields on which the two policies disagree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MergeStrategy {
+    /// Take the more restrictive value and intersect the capabilities and role changes of roles defined in both policies. Values that cannot be ordered are taken from the own policy.
+    MostRestrictive,
+    /// Take the own value and unite the capabilities of roles defined in both policies.
+    PreferSelf,
+}
+
+impl Optionality {
+    fn restrictiveness(&self) -> u8 {
+        match self {
+            Optionality::Optional => 0,
+            Optionality::Required => 1,
+            Optionality::Forbidden => 2,
+        }
+    }
+
+    fn most_restrictive(&self, other: &Optionality) -> Optionality {
+        if other.restrictiveness() > self.restrictiveness() {
+            other.clone()
+        } else {
+            self.clone()
+        }
+    }
 }
 
 impl RoomPolicy {
@@ -481,37 +1746,60 @@ impl RoomPolicy {
         let outsider_role = RoleInfo {
             role_name: TlsString("Outsider".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: Vec::new(),
+            role_capabilities: CapabilitySet::default(),
             min_participants_constraint: 0,
             max_participants_constraint: Some(0),
             min_active_participants_constraint: 0,
             max_active_participants_constraint: Some(0),
             authorized_role_changes: BTreeMap::new(),
             self_role_changes: Vec::new(),
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         let regular_role = RoleInfo {
             role_name: TlsString("User".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: vec![Capability::ReceiveMessage, Capability::SendMessage],
+            role_capabilities: CapabilitySet::from([
+                Capability::ReceiveMessage,
+                Capability::SendMessage,
+                Capability::ReportAbuse,
+            ]),
             min_participants_constraint: 0,
             max_participants_constraint: None,
             min_active_participants_constraint: 0,
             max_active_participants_constraint: None,
             authorized_role_changes: BTreeMap::new(),
             self_role_changes: Vec::new(),
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         let owner_role = RoleInfo {
             role_name: TlsString("Owner".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: vec![Capability::ReceiveMessage, Capability::SendMessage],
+            role_capabilities: CapabilitySet::from([
+                Capability::ReceiveMessage,
+                Capability::SendMessage,
+                Capability::ReportAbuse,
+            ]),
             min_participants_constraint: 1,
             max_participants_constraint: Some(1),
             min_active_participants_constraint: 1,
             max_active_participants_constraint: Some(1),
             authorized_role_changes: BTreeMap::new(),
             self_role_changes: Vec::new(),
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         roles.insert(RoleIndex::Outsider, outsider_role);
@@ -548,37 +1836,71 @@ impl RoomPolicy {
         let outsider_role = RoleInfo {
             role_name: TlsString("Outsider".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: Vec::new(),
+            role_capabilities: CapabilitySet::default(),
             min_participants_constraint: 0,
             max_participants_constraint: Some(0),
             min_active_participants_constraint: 0,
             max_active_participants_constraint: Some(0),
             authorized_role_changes: BTreeMap::new(),
             self_role_changes: Vec::new(),
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         let regular_role = RoleInfo {
             role_name: TlsString("Regular user".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: vec![Capability::ReceiveMessage, Capability::SendMessage],
+            role_capabilities: CapabilitySet::from([
+                Capability::ReceiveMessage,
+                Capability::SendMessage,
+                Capability::ReportAbuse,
+                Capability::AddParticipant,
+                Capability::RemoveParticipant,
+                Capability::ChangeRoomName,
+                Capability::ChangeRoomDescription,
+                Capability::ChangeRoomAvatar,
+            ]),
             min_participants_constraint: 0,
             max_participants_constraint: None,
             min_active_participants_constraint: 0,
             max_active_participants_constraint: None,
             authorized_role_changes: regular_role_changes,
             self_role_changes: vec![RoleIndex::Outsider],
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         let owner_role = RoleInfo {
             role_name: TlsString("Owner".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: vec![Capability::ReceiveMessage, Capability::SendMessage],
+            role_capabilities: CapabilitySet::from([
+                Capability::ReceiveMessage,
+                Capability::SendMessage,
+                Capability::ReportAbuse,
+                Capability::AddParticipant,
+                Capability::RemoveParticipant,
+                Capability::ChangeOwnRole,
+                Capability::ChangeRoomName,
+                Capability::ChangeRoomDescription,
+                Capability::ChangeRoomAvatar,
+            ]),
             min_participants_constraint: 0,
             max_participants_constraint: Some(1),
             min_active_participants_constraint: 0,
             max_active_participants_constraint: Some(1),
             authorized_role_changes: owner_role_changes,
             self_role_changes: vec![RoleIndex::Outsider, RoleIndex::Regular],
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         roles.insert(RoleIndex::Outsider, outsider_role);
@@ -616,6 +1938,10 @@ impl RoomPolicy {
             },
             allowed_bots: BTreeMap::new(),
             policy_extensions: Vec::new(),
+            allow_concurrent_calls: false,
+            preauthorized: BTreeMap::new(),
+            media_policy: MediaPolicy::default(),
+            required_approvals: 1,
         }
     }
 
@@ -658,49 +1984,99 @@ impl RoomPolicy {
         let outsider_role = RoleInfo {
             role_name: TlsString("Outsider".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: Vec::new(),
+            role_capabilities: CapabilitySet::default(),
             min_participants_constraint: 0,
             max_participants_constraint: Some(0),
             min_active_participants_constraint: 0,
             max_active_participants_constraint: Some(0),
             authorized_role_changes: BTreeMap::new(),
             self_role_changes: Vec::new(),
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         let regular_role = RoleInfo {
             role_name: TlsString("Regular user".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: vec![Capability::ReceiveMessage, Capability::SendMessage],
+            role_capabilities: CapabilitySet::from([
+                Capability::ReceiveMessage,
+                Capability::SendMessage,
+                Capability::ReportAbuse,
+                Capability::AddParticipant,
+            ]),
             min_participants_constraint: 0,
             max_participants_constraint: None,
             min_active_participants_constraint: 0,
             max_active_participants_constraint: None,
             authorized_role_changes: regular_role_changes,
             self_role_changes: vec![RoleIndex::Outsider],
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         let admin_role = RoleInfo {
             role_name: TlsString("Admin".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: vec![Capability::ReceiveMessage, Capability::SendMessage],
+            role_capabilities: CapabilitySet::from([
+                Capability::ReceiveMessage,
+                Capability::SendMessage,
+                Capability::ReportAbuse,
+                Capability::AddParticipant,
+                Capability::RemoveParticipant,
+                Capability::CreateSubgroup,
+                Capability::MuteUser,
+                Capability::ChangeOwnRole,
+                Capability::ChangeRoomName,
+                Capability::ChangeRoomDescription,
+                Capability::ChangeRoomAvatar,
+            ]),
             min_participants_constraint: 0,
             max_participants_constraint: None,
             min_active_participants_constraint: 0,
             max_active_participants_constraint: None,
             authorized_role_changes: admin_role_changes,
             self_role_changes: vec![RoleIndex::Outsider, RoleIndex::Regular],
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         let owner_role = RoleInfo {
             role_name: TlsString("Owner".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: vec![Capability::ReceiveMessage, Capability::SendMessage],
+            role_capabilities: CapabilitySet::from([
+                Capability::ReceiveMessage,
+                Capability::SendMessage,
+                Capability::ReportAbuse,
+                Capability::AddParticipant,
+                Capability::RemoveParticipant,
+                Capability::CreateSubgroup,
+                Capability::MuteUser,
+                Capability::ChangeOwnRole,
+                Capability::ChangeRoomName,
+                Capability::ChangeRoomDescription,
+                Capability::ChangeRoomAvatar,
+                Capability::ChangeRoleDefinitions,
+            ]),
             min_participants_constraint: 1,
             max_participants_constraint: Some(1),
             min_active_participants_constraint: 1,
             max_active_participants_constraint: Some(1),
             authorized_role_changes: owner_role_changes,
             self_role_changes: vec![RoleIndex::Outsider, RoleIndex::Regular, RoleIndex::Admin],
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         roles.insert(RoleIndex::Outsider, outsider_role);
@@ -739,6 +2115,10 @@ impl RoomPolicy {
             },
             allowed_bots: BTreeMap::new(),
             policy_extensions: Vec::new(),
+            allow_concurrent_calls: false,
+            preauthorized: BTreeMap::new(),
+            media_policy: MediaPolicy::default(),
+            required_approvals: 1,
         }
     }
 
@@ -804,61 +2184,118 @@ impl RoomPolicy {
         let outsider_role = RoleInfo {
             role_name: TlsString("Outsider".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: Vec::new(),
+            role_capabilities: CapabilitySet::default(),
             min_participants_constraint: 0,
             max_participants_constraint: Some(0),
             min_active_participants_constraint: 0,
             max_active_participants_constraint: Some(0),
             authorized_role_changes: BTreeMap::new(),
             self_role_changes: vec![RoleIndex::Regular],
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         let banned_role = RoleInfo {
             role_name: TlsString("Banned".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: Vec::new(),
+            role_capabilities: CapabilitySet::default(),
             min_participants_constraint: 0,
             max_participants_constraint: None,
             min_active_participants_constraint: 0,
             max_active_participants_constraint: Some(0),
             authorized_role_changes: BTreeMap::new(),
             self_role_changes: Vec::new(),
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         let regular_role = RoleInfo {
             role_name: TlsString("Regular user".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: vec![Capability::ReceiveMessage, Capability::SendMessage],
+            role_capabilities: CapabilitySet::from([
+                Capability::ReceiveMessage,
+                Capability::SendMessage,
+                Capability::ReportAbuse,
+                Capability::AddParticipant,
+            ]),
             min_participants_constraint: 0,
             max_participants_constraint: None,
             min_active_participants_constraint: 0,
             max_active_participants_constraint: None,
             authorized_role_changes: regular_role_changes,
             self_role_changes: vec![RoleIndex::Outsider],
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         let admin_role = RoleInfo {
             role_name: TlsString("Admin".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: vec![Capability::ReceiveMessage, Capability::SendMessage],
+            role_capabilities: CapabilitySet::from([
+                Capability::ReceiveMessage,
+                Capability::SendMessage,
+                Capability::ReportAbuse,
+                Capability::AddParticipant,
+                Capability::RemoveParticipant,
+                Capability::CreateSubgroup,
+                Capability::MuteUser,
+                Capability::ChangeOwnRole,
+                Capability::ChangeRoomName,
+                Capability::ChangeRoomDescription,
+                Capability::ChangeRoomAvatar,
+                Capability::UnBan,
+            ]),
             min_participants_constraint: 0,
             max_participants_constraint: None,
             min_active_participants_constraint: 0,
             max_active_participants_constraint: None,
             authorized_role_changes: admin_role_changes,
             self_role_changes: vec![RoleIndex::Outsider, RoleIndex::Regular],
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         let owner_role = RoleInfo {
             role_name: TlsString("Owner".to_owned()),
             role_description: TlsString("".to_owned()),
-            role_capabilities: vec![Capability::ReceiveMessage, Capability::SendMessage],
+            role_capabilities: CapabilitySet::from([
+                Capability::ReceiveMessage,
+                Capability::SendMessage,
+                Capability::ReportAbuse,
+                Capability::AddParticipant,
+                Capability::RemoveParticipant,
+                Capability::CreateSubgroup,
+                Capability::MuteUser,
+                Capability::ChangeOwnRole,
+                Capability::ChangeRoomName,
+                Capability::ChangeRoomDescription,
+                Capability::ChangeRoomAvatar,
+                Capability::UnBan,
+                Capability::ChangeRoleDefinitions,
+            ]),
             min_participants_constraint: 1,
             max_participants_constraint: Some(1),
             min_active_participants_constraint: 1,
             max_active_participants_constraint: Some(1),
             authorized_role_changes: owner_role_changes,
             self_role_changes: vec![RoleIndex::Outsider, RoleIndex::Regular, RoleIndex::Admin],
+            retention_override: None,
+            protected_roles: Vec::new(),
+            inherits_from: None,
+            send_rate_limit: None,
+            counts_toward_membership: true,
         };
 
         roles.insert(RoleIndex::Outsider, outsider_role);
@@ -879,134 +2316,731 @@ impl RoomPolicy {
         }
     }
 
-    fn try_policy_proposals(&mut self, proposals: &[()]) -> Result<()> {
-        for _proposal in proposals {}
-        Ok(())
+    /// Like [`RoomPolicy::default_public`], but only admins and owners can send messages. Regular users can only read.
+    pub fn default_announcement() -> Self {
+        let mut policy = Self::default_public();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .expect("the public policy defines regular users")
+            .role_capabilities
+            .remove(&Capability::SendMessage);
+        policy
     }
-}
-
-/// The state of the room.
-#[derive(
-    Debug,
-    Clone,
-    PartialEq,
-    Eq,
-    Hash,
-    Serialize,
-    Deserialize,
-    TlsSize,
-    TlsSerialize,
-    TlsDeserializeBytes,
-)]
-pub struct RoomState {
-    /// The general rules for the room.
-    policy: RoomPolicy,
 
-    #[tls_codec(with = "tls::btreemap")]
-    users: BTreeMap<Vec<u8>, RoleIndex>,
-}
+    /// The join link settings of the room.
+    ///
+    /// ```
+    /// use mimi_room_policy::RoomPolicy;
+    ///
+    /// let policy = RoomPolicy::default_public();
+    /// let link_policy = policy.link_policy();
+    ///
+    /// assert_eq!(link_policy.join_link(), "");
+    /// assert!(link_policy.multiuser());
+    /// ```
+    pub fn link_policy(&self) -> &LinkPolicy {
+        &self.link_policy
+    }
 
-impl RoomState {
-    fn user_role(&self, user_id: &[u8]) -> RoleIndex {
-        self.users
-            .get(user_id)
-            .cloned()
-            .unwrap_or(RoleIndex::Outsider)
+    /// The rules for logging messages in the room.
+    pub fn logging_policy(&self) -> &LoggingPolicy {
+        &self.logging_policy
     }
 
-    fn user_capabilities(&self, user_id: &[u8]) -> &[Capability] {
-        &self.policy.roles[&self.user_role(user_id)].role_capabilities
+    /// The rules for sharing the history of the room with new members.
+    pub fn history_sharing(&self) -> &HistoryPolicy {
+        &self.history_sharing
     }
 
-    fn has_capability(&self, user_id: &[u8], capability: Capability) -> bool {
-        self.user_capabilities(user_id).contains(&capability)
+    /// The membership style that applies to the room. For [`MembershipStyle::ParentDependent`] rooms, this is the style of the closest ancestor that does not depend on its own parent.
+    ///
+    /// `ancestors` lists the policies of the parent rooms, starting with the direct parent. At most [`MAX_PARENT_DEPTH`] parents are followed.
+    pub fn effective_membership_style(&self, ancestors: &[&RoomPolicy]) -> Result<MembershipStyle> {
+        let mut style = &self.membership_style;
+        let mut depth = 0;
+        while *style == MembershipStyle::ParentDependent {
+            if depth == MAX_PARENT_DEPTH {
+                return Err(Error::ParentChainTooDeep);
+            }
+            let parent = ancestors.get(depth).ok_or(Error::ParentRoomMissing)?;
+            style = &parent.membership_style;
+            depth += 1;
+        }
+        Ok(style.clone())
     }
 
-    fn try_regular_proposals(&mut self, sender: &[u8], proposals: &[MimiProposal]) -> Result<()> {
-        for proposal in proposals {
-            match proposal {
-                MimiProposal::ChangeRole { target, role } => {
-                    let sender_user_role = self.user_role(sender);
-                    let target_user_role = self.user_role(target);
+    /// Compute what changes from this policy to the other policy.
+    pub fn diff(&self, other: &RoomPolicy) -> PolicyDiff {
+        let mut diff = PolicyDiff::default();
+
+        for (role_index, role_info) in &self.roles {
+            let Some(other_role_info) = other.roles.get(role_index) else {
+                diff.removed_roles.push(*role_index);
+                continue;
+            };
+            if role_info == other_role_info {
+                continue;
+            }
 
-                    // Do nothing if the role is already correct. This is required because a self-remove is applied twice: Once when submitted as a proposal and another time when the proposal is committed.
-                    if target_user_role == *role {
-                        continue;
-                    }
+            let capabilities: BTreeSet<_> = role_info.role_capabilities.iter().collect();
+            let other_capabilities: BTreeSet<_> =
+                other_role_info.role_capabilities.iter().collect();
+            diff.changed_roles.push(RoleDiff {
+                role: *role_index,
+                added_capabilities: other_capabilities
+                    .difference(&capabilities)
+                    .map(|capability| (*capability).clone())
+                    .collect(),
+                removed_capabilities: capabilities
+                    .difference(&other_capabilities)
+                    .map(|capability| (*capability).clone())
+                    .collect(),
+            });
+        }
+        diff.added_roles = other
+            .roles
+            .keys()
+            .filter(|role_index| !self.roles.contains_key(role_index))
+            .cloned()
+            .collect();
 
-                    let possible_roles = if sender == target {
-                        &*self.policy.roles[&sender_user_role].self_role_changes
-                    } else {
-                        self.policy.roles[&sender_user_role]
-                            .authorized_role_changes
-                            .get(&target_user_role)
-                            .map_or(&[][..], |x| x) // Default to empty list
-                    };
+        let fields = [
+            (
+                PolicyField::MembershipStyle,
+                self.membership_style == other.membership_style,
+            ),
+            (
+                PolicyField::MultiDevice,
+                self.multi_device == other.multi_device,
+            ),
+            (
+                PolicyField::ParentRoomUri,
+                self.parent_room_uri == other.parent_room_uri,
+            ),
+            (
+                PolicyField::PersistentRoom,
+                self.persistent_room == other.persistent_room,
+            ),
+            (
+                PolicyField::DeliveryNotifications,
+                self.delivery_notifications == other.delivery_notifications,
+            ),
+            (
+                PolicyField::ReadReceipts,
+                self.read_receipts == other.read_receipts,
+            ),
+            (
+                PolicyField::SemiAnonymousIds,
+                self.semi_anonymous_ids == other.semi_anonymous_ids,
+            ),
+            (
+                PolicyField::Discoverable,
+                self.discoverable == other.discoverable,
+            ),
+            (
+                PolicyField::LinkPolicy,
+                self.link_policy == other.link_policy,
+            ),
+            (
+                PolicyField::LoggingPolicy,
+                self.logging_policy == other.logging_policy,
+            ),
+            (
+                PolicyField::HistorySharing,
+                self.history_sharing == other.history_sharing,
+            ),
+            (
+                PolicyField::AllowedBots,
+                self.allowed_bots == other.allowed_bots,
+            ),
+            (
+                PolicyField::PolicyExtensions,
+                self.policy_extensions == other.policy_extensions,
+            ),
+            (
+                PolicyField::AllowConcurrentCalls,
+                self.allow_concurrent_calls == other.allow_concurrent_calls,
+            ),
+            (
+                PolicyField::Preauthorized,
+                self.preauthorized == other.preauthorized,
+            ),
+            (
+                PolicyField::MediaPolicy,
+                self.media_policy == other.media_policy,
+            ),
+            (
+                PolicyField::RequiredApprovals,
+                self.required_approvals == other.required_approvals,
+            ),
+        ];
+        diff.changed_fields = fields
+            .into_iter()
+            .filter(|(_, equal)| !equal)
+            .map(|(field, _)| field)
+            .collect();
+
+        diff
+    }
+
+    /// Reconcile this policy with the policy of a federated room. Roles, bots, policy extensions and preauthorized users that are only defined in one of the policies are kept, except that [`MergeStrategy::MostRestrictive`] only keeps bots, extensions and preauthorized users both policies agree on. [`MergeStrategy::MostRestrictive`] also only allows the attachment types both policies allow, and fails with [`Error::Irreconcilable`] if they have none in common. The result is validated.
+    pub fn merge(&self, other: &RoomPolicy, strategy: MergeStrategy) -> Result<RoomPolicy> {
+        let mut merged = self.clone();
+
+        for (role_index, other_role) in &other.roles {
+            let Some(role) = merged.roles.get_mut(role_index) else {
+                merged.roles.insert(*role_index, other_role.clone());
+                continue;
+            };
+            match strategy {
+                MergeStrategy::PreferSelf => {
+                    role.role_capabilities
+                        .extend(other_role.role_capabilities.iter());
+                }
+                MergeStrategy::MostRestrictive => role.restrict_to(other_role),
+            }
+        }
 
-                    if possible_roles.contains(role) {
-                        if *role == RoleIndex::Outsider {
-                            self.users.remove(target);
-                        } else {
-                            self.users.insert(target.clone(), *role);
+        match strategy {
+            MergeStrategy::PreferSelf => {
+                for (name, bot) in &other.allowed_bots {
+                    merged
+                        .allowed_bots
+                        .entry(name.clone())
+                        .or_insert_with(|| bot.clone());
+                }
+                for extension in &other.policy_extensions {
+                    if !merged
+                        .policy_extensions
+                        .iter()
+                        .any(|own| own.name == extension.name)
+                    {
+                        merged.policy_extensions.push(extension.clone());
+                    }
+                }
+                for (user, role) in &other.preauthorized {
+                    merged.preauthorized.entry(user.clone()).or_insert(*role);
+                }
+            }
+            MergeStrategy::MostRestrictive => {
+                merged.multi_device &= other.multi_device;
+                merged.delivery_notifications = merged
+                    .delivery_notifications
+                    .most_restrictive(&other.delivery_notifications);
+                merged.read_receipts = merged.read_receipts.most_restrictive(&other.read_receipts);
+                merged.semi_anonymous_ids |= other.semi_anonymous_ids;
+                merged.discoverable &= other.discoverable;
+                merged.allow_concurrent_calls &= other.allow_concurrent_calls;
+                merged.required_approvals = merged.required_approvals.max(other.required_approvals);
+
+                merged.logging_policy.logging = merged
+                    .logging_policy
+                    .logging
+                    .most_restrictive(&other.logging_policy.logging);
+
+                let history = &mut merged.history_sharing;
+                history.history_sharing = history
+                    .history_sharing
+                    .most_restrictive(&other.history_sharing.history_sharing);
+                history
+                    .who_can_share
+                    .retain(|role| other.history_sharing.who_can_share.contains(role));
+                history.automatically_share &= other.history_sharing.automatically_share;
+                history.max_time_period = history
+                    .max_time_period
+                    .min(other.history_sharing.max_time_period);
+
+                merged
+                    .allowed_bots
+                    .retain(|name, bot| other.allowed_bots.get(name) == Some(bot));
+                merged
+                    .policy_extensions
+                    .retain(|extension| other.policy_extensions.contains(extension));
+                merged
+                    .preauthorized
+                    .retain(|user, role| other.preauthorized.get(user) == Some(role));
+
+                let media = &mut merged.media_policy;
+                media.max_attachment_bytes = match (
+                    media.max_attachment_bytes,
+                    other.media_policy.max_attachment_bytes,
+                ) {
+                    (Some(max), Some(other_max)) => Some(max.min(other_max)),
+                    (max, other_max) => max.or(other_max),
+                };
+                let other_prefixes = &other.media_policy.allowed_mime_prefixes;
+                if media.allowed_mime_prefixes.is_empty() {
+                    media.allowed_mime_prefixes.clone_from(other_prefixes);
+                } else if !other_prefixes.is_empty() {
+                    // Of two nested prefixes, the longer one matches the types both allow
+                    let mut prefixes: Vec<TlsString> = Vec::new();
+                    for prefix in &media.allowed_mime_prefixes {
+                        for other_prefix in other_prefixes {
+                            let lowercase = prefix.to_ascii_lowercase();
+                            let other_lowercase = other_prefix.to_ascii_lowercase();
+                            let longer = if lowercase.starts_with(&other_lowercase) {
+                                prefix
+                            } else if other_lowercase.starts_with(&lowercase) {
+                                other_prefix
+                            } else {
+                                continue;
+                            };
+                            if !prefixes.contains(longer) {
+                                prefixes.push(longer.clone());
+                            }
                         }
-                    } else {
-                        return Err(Error::NotCapable);
                     }
+                    // An empty list would allow all types
+                    if prefixes.is_empty() {
+                        return Err(Error::Irreconcilable);
+                    }
+                    media.allowed_mime_prefixes = prefixes;
                 }
             }
         }
 
-        Ok(())
+        merged.validate()?;
+        Ok(merged)
     }
-}
 
-#[derive(
-    Debug,
-    Clone,
-    PartialEq,
-    Eq,
-    Hash,
-    Serialize,
-    Deserialize,
-    TlsSize,
-    TlsSerialize,
-    TlsDeserializeBytes,
-)]
-pub struct VerifiedRoomState(RoomState);
+    /// Check the policy against the requirements of a host. Returns all requirements that are not met.
+    pub fn satisfies_requirements(
+        &self,
+        requirements: &PolicyRequirements,
+    ) -> Result<(), Vec<Unmet>> {
+        let mut unmet = Vec::new();
+
+        for capability in &requirements.required {
+            let granted = self
+                .roles_with_capability(capability.clone())
+                .iter()
+                .any(|role| !matches!(role, RoleIndex::Outsider | RoleIndex::Banned));
+            if !granted {
+                unmet.push(Unmet::Missing(capability.clone()));
+            }
+        }
+        for capability in &requirements.forbidden {
+            for role in self.roles_with_capability(capability.clone()) {
+                unmet.push(Unmet::Forbidden {
+                    role,
+                    capability: capability.clone(),
+                });
+            }
+        }
 
-impl VerifiedRoomState {
-    pub fn verify(state: RoomState) -> Result<Self> {
-        // POLICY CHECKS
+        if unmet.is_empty() {
+            Ok(())
+        } else {
+            Err(unmet)
+        }
+    }
 
-        // No outsiders are explicitly listed
-        if state.users.values().any(|u| *u == RoleIndex::Outsider) {
-            return Err(Error::UserNotInRoom);
+    /// Compute the patch that turns this policy into the other policy. This is the compact counterpart of [`RoomPolicy::diff`].
+    pub fn patch(&self, other: &RoomPolicy) -> PolicyPatch {
+        let diff = self.diff(other);
+
+        let mut changes: Vec<_> = diff
+            .removed_roles
+            .into_iter()
+            .map(|role| PolicyChange::RemoveRole { role })
+            .collect();
+        let set_roles = diff.added_roles.into_iter().chain(
+            diff.changed_roles
+                .into_iter()
+                .map(|role_diff| role_diff.role),
+        );
+        for role in set_roles {
+            changes.push(PolicyChange::SetRole {
+                role,
+                role_info: other.roles[&role].clone(),
+            });
         }
 
-        // Outsider role must have name "Outsider" if it exists. And max_participants 0
-        let Some(outsider_role) = state.policy.roles.get(&RoleIndex::Outsider) else {
-            return Err(Error::SpecialRole);
+        changes.extend(diff.changed_fields.into_iter().map(|field| match field {
+            PolicyField::MembershipStyle => {
+                PolicyChange::MembershipStyle(other.membership_style.clone())
+            }
+            PolicyField::MultiDevice => PolicyChange::MultiDevice(other.multi_device),
+            PolicyField::ParentRoomUri => {
+                PolicyChange::ParentRoomUri(other.parent_room_uri.clone())
+            }
+            PolicyField::PersistentRoom => PolicyChange::PersistentRoom(other.persistent_room),
+            PolicyField::DeliveryNotifications => {
+                PolicyChange::DeliveryNotifications(other.delivery_notifications.clone())
+            }
+            PolicyField::ReadReceipts => PolicyChange::ReadReceipts(other.read_receipts.clone()),
+            PolicyField::SemiAnonymousIds => {
+                PolicyChange::SemiAnonymousIds(other.semi_anonymous_ids)
+            }
+            PolicyField::Discoverable => PolicyChange::Discoverable(other.discoverable),
+            PolicyField::LinkPolicy => PolicyChange::LinkPolicy(other.link_policy.clone()),
+            PolicyField::LoggingPolicy => PolicyChange::LoggingPolicy(other.logging_policy.clone()),
+            PolicyField::HistorySharing => {
+                PolicyChange::HistorySharing(other.history_sharing.clone())
+            }
+            PolicyField::AllowedBots => PolicyChange::AllowedBots(other.allowed_bots.clone()),
+            PolicyField::PolicyExtensions => {
+                PolicyChange::PolicyExtensions(other.policy_extensions.clone())
+            }
+            PolicyField::AllowConcurrentCalls => {
+                PolicyChange::AllowConcurrentCalls(other.allow_concurrent_calls)
+            }
+            PolicyField::Preauthorized => PolicyChange::Preauthorized(other.preauthorized.clone()),
+            PolicyField::MediaPolicy => PolicyChange::MediaPolicy(other.media_policy.clone()),
+            PolicyField::RequiredApprovals => {
+                PolicyChange::RequiredApprovals(other.required_approvals)
+            }
+        }));
+
+        PolicyPatch { changes }
+    }
+
+    /// Apply a patch computed by [`RoomPolicy::patch`]. The policy is only changed if the patched policy is consistent.
+    pub fn apply_patch(&mut self, patch: &PolicyPatch) -> Result<()> {
+        let mut policy = self.clone();
+
+        for change in &patch.changes {
+            match change.clone() {
+                PolicyChange::SetRole { role, role_info } => {
+                    policy.roles.insert(role, role_info);
+                }
+                PolicyChange::RemoveRole { role } => {
+                    if policy.roles.remove(&role).is_none() {
+                        return Err(Error::RoleNotDefined);
+                    }
+                }
+                PolicyChange::MembershipStyle(value) => policy.membership_style = value,
+                PolicyChange::MultiDevice(value) => policy.multi_device = value,
+                PolicyChange::ParentRoomUri(value) => policy.parent_room_uri = value,
+                PolicyChange::PersistentRoom(value) => policy.persistent_room = value,
+                PolicyChange::DeliveryNotifications(value) => policy.delivery_notifications = value,
+                PolicyChange::ReadReceipts(value) => policy.read_receipts = value,
+                PolicyChange::SemiAnonymousIds(value) => policy.semi_anonymous_ids = value,
+                PolicyChange::Discoverable(value) => policy.discoverable = value,
+                PolicyChange::LinkPolicy(value) => policy.link_policy = value,
+                PolicyChange::LoggingPolicy(value) => policy.logging_policy = value,
+                PolicyChange::HistorySharing(value) => policy.history_sharing = value,
+                PolicyChange::AllowedBots(value) => policy.allowed_bots = value,
+                PolicyChange::PolicyExtensions(value) => policy.policy_extensions = value,
+                PolicyChange::AllowConcurrentCalls(value) => policy.allow_concurrent_calls = value,
+                PolicyChange::Preauthorized(value) => policy.preauthorized = value,
+                PolicyChange::MediaPolicy(value) => policy.media_policy = value,
+                PolicyChange::RequiredApprovals(value) => policy.required_approvals = value,
+            }
+        }
+
+        policy.validate()?;
+        *self = policy;
+
+        Ok(())
+    }
+
+    /// The policy extension with the given name.
+    pub fn extension(&self, name: &str) -> Option<&PolicyExtension> {
+        self.policy_extensions
+            .iter()
+            .find(|extension| *extension.name == name)
+    }
+
+    /// Count the roles, transitions, granted capabilities, bots and extensions of the policy. Servers can use this to flag unusually complex policies.
+    pub fn complexity_metrics(&self) -> PolicyMetrics {
+        let transitions = self
+            .roles
+            .values()
+            .map(|role_info| {
+                role_info
+                    .authorized_role_changes
+                    .values()
+                    .map(Vec::len)
+                    .sum::<usize>()
+                    + role_info.self_role_changes.len()
+            })
+            .sum();
+
+        PolicyMetrics {
+            roles: self.roles.len(),
+            transitions,
+            capabilities: self
+                .roles
+                .values()
+                .map(|role_info| role_info.role_capabilities.iter().count())
+                .sum(),
+            bots: self.allowed_bots.len(),
+            extensions: self.policy_extensions.len(),
+        }
+    }
+
+    /// The capabilities of the role together with the ones it inherits. Undefined roles have no capabilities.
+    fn effective_capabilities(&self, role: RoleIndex) -> CapabilitySet {
+        let mut capabilities = CapabilitySet::default();
+        let mut visited = BTreeSet::new();
+        let mut current = Some(role);
+        while let Some(role) = current {
+            // Cycles are rejected when the policy is verified, but unverified states can contain them
+            if !visited.insert(role) {
+                break;
+            }
+            let Some(role_info) = self.roles.get(&role) else {
+                break;
+            };
+            capabilities.extend(role_info.role_capabilities.iter());
+            current = role_info.inherits_from;
+        }
+        capabilities
+    }
+
+    /// The roles that have the capability, either directly or through inheritance, sorted by [`RoleIndex`].
+    pub fn roles_with_capability(&self, capability: Capability) -> Vec<RoleIndex> {
+        self.roles
+            .keys()
+            .filter(|role_index| {
+                self.effective_capabilities(**role_index)
+                    .contains(capability.clone())
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Roles that the owner cannot assign to anyone, not even through a sequence of role changes starting with an invitation. Special roles are not reported.
+    pub fn governance_connectivity(&self) -> Vec<RoleIndex> {
+        let mut reachable = BTreeSet::from([RoleIndex::Outsider]);
+        if let Some(owner_role_info) = self.roles.get(&RoleIndex::Owner) {
+            let mut queue = vec![RoleIndex::Outsider];
+            while let Some(source_role) = queue.pop() {
+                let Some(targets) = owner_role_info.authorized_role_changes.get(&source_role)
+                else {
+                    continue;
+                };
+                for target in targets {
+                    if reachable.insert(*target) {
+                        queue.push(*target);
+                    }
+                }
+            }
+        }
+
+        self.roles
+            .keys()
+            .filter(|role| !matches!(role, RoleIndex::Outsider | RoleIndex::Banned))
+            .filter(|role| !reachable.contains(role))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether any role can move other users into [`RoleIndex::Banned`]. A policy that defines the banned role but returns `false` here can never ban anyone.
+    pub fn ban_reachability(&self) -> bool {
+        self.roles.values().any(|role_info| {
+            role_info
+                .authorized_role_changes
+                .values()
+                .any(|targets| targets.contains(&RoleIndex::Banned))
+        })
+    }
+
+    /// Role changes that custom roles may make to or from [`RoleIndex::Owner`] or [`RoleIndex::Admin`], as triples of the custom role, the source role and the target role.
+    ///
+    /// Custom roles with such changes can override the built-in hierarchy. In strict mode the first such change is returned as [`Error::InvalidRoleTransition`] instead.
+    pub fn custom_role_overreach(
+        &self,
+        strict: bool,
+    ) -> Result<Vec<(RoleIndex, RoleIndex, RoleIndex)>> {
+        let privileged = |role: &RoleIndex| matches!(role, RoleIndex::Owner | RoleIndex::Admin);
+
+        let mut overreach = Vec::new();
+        for (role_index, role_info) in &self.roles {
+            if !matches!(role_index, RoleIndex::Custom(_)) {
+                continue;
+            }
+            for (source_role, target_roles) in &role_info.authorized_role_changes {
+                for target_role in target_roles {
+                    if !privileged(source_role) && !privileged(target_role) {
+                        continue;
+                    }
+                    if strict {
+                        return Err(Error::InvalidRoleTransition {
+                            source_role: *source_role,
+                            target_role: *target_role,
+                        });
+                    }
+                    overreach.push((*role_index, *source_role, *target_role));
+                }
+            }
+        }
+        Ok(overreach)
+    }
+
+    /// Every pair of a role and a sensitive capability it holds, sorted by role.
+    pub fn sensitive_grants(&self) -> Vec<(RoleIndex, Capability)> {
+        let mut grants = Vec::new();
+        for role_index in self.roles.keys() {
+            let capabilities: BTreeSet<_> = self
+                .effective_capabilities(*role_index)
+                .iter()
+                .filter(|capability| capability.is_sensitive())
+                .collect();
+            grants.extend(
+                capabilities
+                    .into_iter()
+                    .map(|capability| (*role_index, capability.clone())),
+            );
+        }
+        grants
+    }
+
+    /// Capabilities listed by the role that it inherits anyway. Returns an empty list if the role is not defined.
+    pub fn redundant_capabilities(&self, role: RoleIndex) -> Vec<Capability> {
+        let Some(role_info) = self.roles.get(&role) else {
+            return Vec::new();
         };
+        let inherited = role_info
+            .inherits_from
+            .filter(|parent| *parent != role)
+            .map(|parent| self.effective_capabilities(parent))
+            .unwrap_or_default();
+
+        role_info
+            .role_capabilities
+            .iter()
+            .filter(|capability| inherited.contains(capability.clone()))
+            .collect()
+    }
+
+    /// The capabilities of `a` that `b` lacks, and the capabilities of `b` that `a` lacks, both in the order of their encoding. Inherited capabilities are included and undefined roles have no capabilities.
+    pub fn capability_delta(
+        &self,
+        a: RoleIndex,
+        b: RoleIndex,
+    ) -> (Vec<Capability>, Vec<Capability>) {
+        let a_capabilities = self.effective_capabilities(a);
+        let b_capabilities = self.effective_capabilities(b);
+
+        (
+            a_capabilities
+                .iter()
+                .filter(|capability| !b_capabilities.contains(capability.clone()))
+                .collect(),
+            b_capabilities
+                .iter()
+                .filter(|capability| !a_capabilities.contains(capability.clone()))
+                .collect(),
+        )
+    }
+
+    /// Bring the policy into a canonical form without changing its meaning. Lists that are used as sets are sorted and deduplicated, and empty role transition entries are removed.
+    pub fn canonicalize(&mut self) {
+        fn sort_dedup<T: Ord>(list: &mut Vec<T>) {
+            list.sort();
+            list.dedup();
+        }
+
+        for role_info in self.roles.values_mut() {
+            sort_dedup(&mut role_info.self_role_changes);
+            sort_dedup(&mut role_info.protected_roles);
+            role_info
+                .authorized_role_changes
+                .retain(|_, targets| !targets.is_empty());
+            for targets in role_info.authorized_role_changes.values_mut() {
+                sort_dedup(targets);
+            }
+        }
+        sort_dedup(&mut self.history_sharing.who_can_share);
+        sort_dedup(&mut self.logging_policy.logging_clients);
+    }
+
+    /// A fingerprint of the canonical form of the policy. Policies with the same meaning have the same fingerprint.
+    pub fn fingerprint(&self) -> [u8; 16] {
+        let mut canonical = self.clone();
+        canonical.canonicalize();
+
+        let mut hasher = Sha256::new();
+        tls_codec::Serialize::tls_serialize(&canonical, &mut hasher)
+            .expect("hashing does not fail for policies within the TLS size limits");
+        let digest: [u8; 32] = hasher.finalize().into();
+        let mut fingerprint = [0; 16];
+        fingerprint.copy_from_slice(&digest[..16]);
+        fingerprint
+    }
+
+    /// A stable identifier of the policy that can be used as a deduplication key. It is the hex encoding of [`RoomPolicy::fingerprint`].
+    pub fn canonical_id(&self) -> String {
+        self.fingerprint()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Check that the policy is consistent on its own, independent of the users in the room. This allows validating a draft policy before a room exists.
+    pub fn validate(&self) -> Result<()> {
+        match self.validate_all().into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Like [`RoomPolicy::validate`], but also rejects self role changes to a role with strictly more capabilities with [`Error::SelfEscalation`]. Such changes are allowed by the policy, but are often a misconfiguration. Joining from [`RoleIndex::Outsider`] is not an escalation.
+    pub fn validate_strict(&self) -> Result<()> {
+        self.validate()?;
+
+        for (role_index, role_info) in &self.roles {
+            if *role_index == RoleIndex::Outsider {
+                continue;
+            }
+            let capabilities = self.effective_capabilities(*role_index);
+            for target in &role_info.self_role_changes {
+                if self
+                    .effective_capabilities(*target)
+                    .is_strict_superset(&capabilities)
+                {
+                    return Err(Error::SelfEscalation {
+                        from: *role_index,
+                        to: *target,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
 
-        if *outsider_role.role_name != "Outsider"
-            || outsider_role.max_participants_constraint != Some(0)
+    /// Like [`RoomPolicy::validate`], but collects all problems instead of stopping at the first one. The first error is the one returned by [`RoomPolicy::validate`].
+    pub fn validate_all(&self) -> Vec<Error> {
+        let mut errors = Vec::new();
+
+        // Outsider role must have name "Outsider" if it exists. And max_participants 0 and only read-only capabilities
+        match self.roles.get(&RoleIndex::Outsider) {
+            Some(outsider_role)
+                if *outsider_role.role_name == "Outsider"
+                    && outsider_role.max_participants_constraint == Some(0)
+                    && outsider_role.authorized_role_changes.is_empty()
+                    && outsider_role.inherits_from.is_none()
+                    && outsider_role.counts_toward_membership
+                    && outsider_role
+                        .role_capabilities
+                        .iter()
+                        .all(|capability| capability.is_read_only()) => {}
+            _ => errors.push(Error::SpecialRole),
+        }
+
+        // Rooms that depend on a parent room must name it
+        if self.membership_style == MembershipStyle::ParentDependent
+            && self.parent_room_uri.is_empty()
         {
-            return Err(Error::SpecialRole);
+            errors.push(Error::ParentRoomMissing);
         }
 
         // Banned role must have name "Banned" if it exists. And max active participants 0
-        if let Some(banned_role) = state.policy.roles.get(&RoleIndex::Banned) {
+        if let Some(banned_role) = self.roles.get(&RoleIndex::Banned) {
             if *banned_role.role_name != "Banned"
                 || banned_role.max_active_participants_constraint != Some(0)
+                || !banned_role.counts_toward_membership
             {
-                return Err(Error::SpecialRole);
+                errors.push(Error::SpecialRole);
             }
         }
 
         // Role transitions all point to valid role ids that are not the same.
-        // TODO
-
-        for (role_index, role_info) in &state.policy.roles {
+        for (role_index, role_info) in &self.roles {
             if role_info.role_name.is_empty()
                 || role_info
                     .max_participants_constraint
@@ -1014,216 +3048,6307 @@ impl VerifiedRoomState {
                 || role_info
                     .max_active_participants_constraint
                     .is_some_and(|max| max < role_info.min_active_participants_constraint)
+                // There cannot be more active participants than participants
+                || role_info
+                    .max_participants_constraint
+                    .is_some_and(|max| max < role_info.min_active_participants_constraint)
             {
-                return Err(Error::InvalidMinMaxConstraints);
+                errors.push(Error::InvalidMinMaxConstraints);
             }
 
             for (source_role, targets) in &role_info.authorized_role_changes {
-                if !state.policy.roles.contains_key(source_role) {
-                    return Err(Error::RoleNotDefined);
+                if !self.roles.contains_key(source_role) {
+                    errors.push(Error::RoleNotDefined);
                 }
 
                 for target_role in targets {
                     if source_role == target_role {
-                        return Err(Error::InvalidRoleTransition {
+                        errors.push(Error::InvalidRoleTransition {
                             source_role: *source_role,
                             target_role: *target_role,
                         });
                     }
-                    if !state.policy.roles.contains_key(target_role) {
-                        return Err(Error::RoleNotDefined);
+                    if !self.roles.contains_key(target_role) {
+                        errors.push(Error::RoleNotDefined);
                     }
                 }
             }
 
             for target_role in &role_info.self_role_changes {
                 if role_index == target_role {
-                    return Err(Error::InvalidRoleTransition {
+                    errors.push(Error::InvalidRoleTransition {
                         source_role: *role_index,
                         target_role: *target_role,
                     });
                 }
-                if !state.policy.roles.contains_key(target_role) {
-                    return Err(Error::RoleNotDefined);
+                if !self.roles.contains_key(target_role) {
+                    errors.push(Error::RoleNotDefined);
                 }
             }
-        }
-
-        // ROOM STATE CHECKS
 
-        let mut role_member_count = BTreeMap::new();
-        for user_role in state.users.values() {
-            *role_member_count.entry(user_role).or_insert(0_u32) += 1;
+            if role_info
+                .protected_roles
+                .iter()
+                .any(|role| !self.roles.contains_key(role))
+            {
+                errors.push(Error::RoleNotDefined);
+            }
 
-            if !state.policy.roles.contains_key(user_role) {
-                return Err(Error::RoleNotDefined);
+            // Roles inherit from defined roles without coming back to themselves
+            if let Some(parent) = role_info.inherits_from {
+                if !self.roles.contains_key(&parent) {
+                    errors.push(Error::RoleNotDefined);
+                }
+                let mut visited = BTreeSet::new();
+                let mut current = Some(parent);
+                while let Some(ancestor) = current.filter(|ancestor| visited.insert(*ancestor)) {
+                    if ancestor == *role_index {
+                        errors.push(Error::InheritanceCycle { role: *role_index });
+                        break;
+                    }
+                    current = self
+                        .roles
+                        .get(&ancestor)
+                        .and_then(|role_info| role_info.inherits_from);
+                }
             }
         }
 
-        for (role_index, role_info) in &state.policy.roles {
-            let count = role_member_count.get(&role_index).unwrap_or(&0);
-            if role_info
-                .max_participants_constraint
-                .is_some_and(|max| *count > max)
-                || *count < role_info.min_participants_constraint
-            {
-                return Err(Error::RoleMinMaxViolated);
+        // Users can always leave, unless the membership is fixed
+        if self.membership_style != MembershipStyle::FixedMembership {
+            for role_index in self.roles.keys() {
+                if !matches!(role_index, RoleIndex::Outsider | RoleIndex::Banned)
+                    && !self.has_exit_path(*role_index)
+                {
+                    errors.push(Error::NoExitPath { role: *role_index });
+                }
             }
         }
 
-        // TODO: Active participants?
-        // TODO: How to make sure the user is removed from mls group
-
-        Ok(VerifiedRoomState(state))
-    }
+        // No string exceeds its maximum length
+        for role_info in self.roles.values() {
+            errors.extend(check_length(&role_info.role_name, MAX_ROLE_NAME_LENGTH).err());
+            errors.extend(
+                check_length(&role_info.role_description, MAX_ROLE_DESCRIPTION_LENGTH).err(),
+            );
+        }
+        for (bot_name, bot) in &self.allowed_bots {
+            errors.extend(check_length(bot_name, MAX_POLICY_STRING_LENGTH).err());
+            errors.extend(check_length(&bot.description, MAX_POLICY_STRING_LENGTH).err());
+            errors.extend(check_length(&bot.homepage, MAX_POLICY_STRING_LENGTH).err());
+        }
+        for logging_client in &self.logging_policy.logging_clients {
+            errors.extend(check_length(logging_client, MAX_POLICY_STRING_LENGTH).err());
+        }
+        for extension in &self.policy_extensions {
+            errors.extend(check_length(&extension.name, MAX_POLICY_STRING_LENGTH).err());
+        }
+        for prefix in &self.media_policy.allowed_mime_prefixes {
+            errors.extend(check_length(prefix, MAX_POLICY_STRING_LENGTH).err());
+        }
 
-    pub fn new(owner: Vec<u8>, policy: RoomPolicy) -> Result<Self> {
-        let mut users = BTreeMap::new();
-        users.insert(owner, RoleIndex::Owner);
+        // Extensions cannot carry large amounts of opaque data
+        if self
+            .policy_extensions
+            .iter()
+            .any(|extension| extension.value.len() > MAX_EXTENSION_SIZE)
+        {
+            errors.push(Error::InvalidExtension);
+        }
+        let extensions_size: usize = self
+            .policy_extensions
+            .iter()
+            .map(|extension| extension.value.len())
+            .sum();
+        if extensions_size > MAX_EXTENSIONS_SIZE {
+            errors.push(Error::PolicyTooLarge);
+        }
+        for value in [
+            &self.parent_room_uri,
+            &self.link_policy.join_link,
+            &self.link_policy.link_requests,
+            &self.logging_policy.machine_readable_policy,
+            &self.logging_policy.human_readable_policy,
+        ] {
+            errors.extend(check_length(value, MAX_POLICY_STRING_LENGTH).err());
+        }
 
-        let state = RoomState { users, policy };
+        // Every custom role can be reached through some role transition
+        let reachable: BTreeSet<_> = self
+            .roles
+            .values()
+            .flat_map(|role_info| {
+                role_info
+                    .authorized_role_changes
+                    .values()
+                    .flatten()
+                    .chain(&role_info.self_role_changes)
+            })
+            .collect();
+        for role_index in self.roles.keys() {
+            if matches!(role_index, RoleIndex::Custom(_)) && !reachable.contains(role_index) {
+                errors.push(Error::UnreachableRole { role: *role_index });
+            }
+        }
 
-        Self::verify(state)
-    }
+        // Preauthorized users join with a defined role
+        if self
+            .preauthorized
+            .values()
+            .any(|role| *role == RoleIndex::Outsider || !self.roles.contains_key(role))
+        {
+            errors.push(Error::RoleNotDefined);
+        }
 
-    pub fn fallback_room(members: Vec<Vec<u8>>) -> VerifiedRoomState {
-        let mut members_iter = members.into_iter();
-        let owner = members_iter.next().unwrap();
+        // Bots have a defined role that allows what the bot does
+        for bot in self.allowed_bots.values() {
+            if !self.roles.contains_key(&bot.bot_role) {
+                errors.push(Error::RoleNotDefined);
+                continue;
+            }
+            let capabilities = self.effective_capabilities(bot.bot_role);
+            if (bot.can_read && !capabilities.contains(Capability::ReceiveMessage))
+                || (bot.can_write && !capabilities.contains(Capability::SendMessage))
+            {
+                errors.push(Error::InvalidBotDefinition);
+            }
+        }
 
-        let mut room_state =
-            VerifiedRoomState::new(owner.clone(), RoomPolicy::default_trusted_private())
-                .expect("we know the policy, this cannot fail");
+        // Rate limits need a window
+        for role_info in self.roles.values() {
+            if role_info
+                .send_rate_limit
+                .is_some_and(|limit| limit.per_seconds == 0)
+            {
+                errors.push(Error::InvalidRoleDefinition);
+            }
+        }
 
-        for user in members_iter {
-            room_state
-                .apply_regular_proposals(
-                    &owner,
-                    &[MimiProposal::ChangeRole {
-                        target: user,
-                        role: RoleIndex::Regular,
-                    }],
-                )
-                .expect("we know the policy, this cannot fail");
+        // Roles that can share history must be able to receive messages
+        for role_index in &self.history_sharing.who_can_share {
+            if !self.roles.contains_key(role_index) {
+                errors.push(Error::RoleNotDefined);
+                continue;
+            }
+            if !self
+                .effective_capabilities(*role_index)
+                .contains(Capability::ReceiveMessage)
+            {
+                errors.push(Error::InvalidRoleDefinition);
+            }
         }
 
-        room_state
+        errors
     }
 
-    pub fn unverified(&self) -> &RoomState {
-        &self.0
+    /// Whether a user with the role can reach [`RoleIndex::Outsider`] through their own role changes.
+    fn has_exit_path(&self, role: RoleIndex) -> bool {
+        self.reachable_by_self_changes(role, RoleIndex::Outsider)
     }
 
-    pub fn has_capability(&self, user_id: &[u8], capability: Capability) -> bool {
-        self.0.has_capability(user_id, capability)
+    /// Whether a user with the role `from` can reach the role `to` through one or more of their own role changes.
+    fn reachable_by_self_changes(&self, from: RoleIndex, to: RoleIndex) -> bool {
+        let mut visited = BTreeSet::from([from]);
+        let mut queue = vec![from];
+        while let Some(current) = queue.pop() {
+            let Some(role_info) = self.roles.get(&current) else {
+                continue;
+            };
+            for target in &role_info.self_role_changes {
+                if *target == to {
+                    return true;
+                }
+                if visited.insert(*target) {
+                    queue.push(*target);
+                }
+            }
+        }
+        false
     }
 
-    pub fn can_apply_regular_proposals(
-        &self,
-        sender: &[u8],
-        proposals: &[MimiProposal],
-    ) -> Result<()> {
-        let mut state = self.0.clone();
+    /// Remove the role `from` and let every reference to it refer to `into` instead.
+    fn merge_role(&mut self, from: RoleIndex, into: RoleIndex) -> Result<()> {
+        let replace = |role: &mut RoleIndex| {
+            if *role == from {
+                *role = into;
+            }
+        };
 
-        state.try_regular_proposals(sender, proposals)?;
+        if self.roles.remove(&from).is_none() {
+            return Err(Error::RoleNotDefined);
+        }
 
-        Ok(())
-    }
+        for (role_index, role_info) in self.roles.iter_mut() {
+            if let Some(targets) = role_info.authorized_role_changes.remove(&from) {
+                role_info
+                    .authorized_role_changes
+                    .entry(into)
+                    .or_default()
+                    .extend(targets);
+            }
+            for (source_role, targets) in role_info.authorized_role_changes.iter_mut() {
+                targets.iter_mut().for_each(replace);
+                targets.retain(|target| target != source_role);
+                targets.sort();
+                targets.dedup();
+            }
 
-    pub fn apply_regular_proposals(
-        &mut self,
-        sender: &[u8],
-        proposals: &[MimiProposal],
-    ) -> Result<()> {
-        let mut state = self.0.clone();
+            role_info.self_role_changes.iter_mut().for_each(replace);
+            role_info
+                .self_role_changes
+                .retain(|target| target != role_index);
+            role_info.self_role_changes.sort();
+            role_info.self_role_changes.dedup();
 
-        state.try_regular_proposals(sender, proposals)?;
+            role_info.protected_roles.iter_mut().for_each(replace);
+            role_info.protected_roles.sort();
+            role_info.protected_roles.dedup();
 
-        *self = Self::verify(state)?;
+            if let Some(parent) = &mut role_info.inherits_from {
+                replace(parent);
+            }
+            if role_info.inherits_from == Some(*role_index) {
+                role_info.inherits_from = None;
+            }
+        }
+
+        self.history_sharing
+            .who_can_share
+            .iter_mut()
+            .for_each(replace);
+        self.history_sharing.who_can_share.sort();
+        self.history_sharing.who_can_share.dedup();
+        for bot in self.allowed_bots.values_mut() {
+            replace(&mut bot.bot_role);
+        }
+        self.preauthorized.values_mut().for_each(replace);
 
         Ok(())
     }
 
-    pub fn apply_policy_proposals(&mut self, _sender: &[u8], proposals: &[()]) -> Result<()> {
-        let mut state = self.0.clone();
-        state.policy.try_policy_proposals(proposals)?;
-
-        *self = Self::verify(state)?;
+    fn try_policy_proposals(&mut self, proposals: &[PolicyProposal]) -> Result<()> {
+        /// Set the field and return whether its value changed.
+        fn replace<T: PartialEq>(field: &mut T, value: T) -> bool {
+            let previous = std::mem::replace(field, value);
+            *field != previous
+        }
 
+        for proposal in proposals {
+            match proposal {
+                PolicyProposal::AddBot { name, bot } => {
+                    if self.allowed_bots.contains_key(name) {
+                        return Err(Error::DuplicateBot);
+                    }
+                    self.allowed_bots.insert(name.clone(), bot.clone());
+                }
+                PolicyProposal::RemoveBot { name } => {
+                    if self.allowed_bots.remove(name).is_none() {
+                        return Err(Error::NothingToDo);
+                    }
+                }
+                PolicyProposal::SetPreauthorized { user, role } => {
+                    if self.preauthorized.insert(user.clone(), *role) == Some(*role) {
+                        return Err(Error::NothingToDo);
+                    }
+                }
+                PolicyProposal::RemovePreauthorized { user } => {
+                    if self.preauthorized.remove(user).is_none() {
+                        return Err(Error::NothingToDo);
+                    }
+                }
+                PolicyProposal::SetExtension(extension) => {
+                    // The size limits are checked when the policy is verified
+                    match self
+                        .policy_extensions
+                        .iter_mut()
+                        .find(|existing| existing.name == extension.name)
+                    {
+                        Some(existing) => {
+                            if !replace(existing, extension.clone()) {
+                                return Err(Error::NothingToDo);
+                            }
+                        }
+                        None => self.policy_extensions.push(extension.clone()),
+                    }
+                }
+                PolicyProposal::RemoveExtension { name } => {
+                    let count = self.policy_extensions.len();
+                    self.policy_extensions
+                        .retain(|extension| extension.name != *name);
+                    if self.policy_extensions.len() == count {
+                        return Err(Error::NothingToDo);
+                    }
+                }
+                PolicyProposal::RenameRole {
+                    index,
+                    name,
+                    description,
+                } => {
+                    // The names of special roles are checked when the policy is verified
+                    let role_info = self.roles.get_mut(index).ok_or(Error::RoleNotDefined)?;
+                    role_info.role_name = name.clone();
+                    role_info.role_description = description.clone();
+                }
+                PolicyProposal::ChangeSetting(setting) => {
+                    // Whether the new setting is consistent with the rest of the policy is checked when the policy is verified
+                    let changed = match setting.clone() {
+                        RoomSetting::MembershipStyle(value) => {
+                            replace(&mut self.membership_style, value)
+                        }
+                        RoomSetting::MultiDevice(value) => replace(&mut self.multi_device, value),
+                        RoomSetting::ParentRoomUri(value) => {
+                            replace(&mut self.parent_room_uri, value)
+                        }
+                        RoomSetting::PersistentRoom(value) => {
+                            replace(&mut self.persistent_room, value)
+                        }
+                        RoomSetting::DeliveryNotifications(value) => {
+                            replace(&mut self.delivery_notifications, value)
+                        }
+                        RoomSetting::ReadReceipts(value) => replace(&mut self.read_receipts, value),
+                        RoomSetting::SemiAnonymousIds(value) => {
+                            replace(&mut self.semi_anonymous_ids, value)
+                        }
+                        RoomSetting::Discoverable(value) => replace(&mut self.discoverable, value),
+                        RoomSetting::AllowConcurrentCalls(value) => {
+                            replace(&mut self.allow_concurrent_calls, value)
+                        }
+                        RoomSetting::RequiredApprovals(value) => {
+                            replace(&mut self.required_approvals, value)
+                        }
+                    };
+                    if !changed {
+                        return Err(Error::NothingToDo);
+                    }
+                }
+            }
+        }
         Ok(())
     }
-
-    pub fn users(&self) -> &BTreeMap<Vec<u8>, RoleIndex> {
-        &self.0.users
-    }
 }
 
-#[cfg(test)]
-mod tests {
+/// Identifies a call in the room.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct CallId(u32);
+
+/// Identifies a pending policy proposal in the room.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct ProposalId(u32);
+
+/// A policy proposal that waits for approvals.
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+struct PendingPolicyProposal {
+    proposal: PolicyProposal,
+    /// The users that approved the proposal, including the proposer.
+    #[tls_codec(with = "tls::btreeset")]
+    approvers: BTreeSet<Vec<u8>>,
+}
+
+/// A scheduled change of a role held by a user.
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+struct RoleExpiry {
+    role: RoleIndex,
+    expires_at: u64,
+    fallback_role: RoleIndex,
+}
+
+/// A batch of proposals recorded in the audit log of a room. Every proposal of a batch gets its own entry.
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct AuditEntry {
+    #[tls_codec(with = "tls::vec")]
+    pub sender: Vec<u8>,
+    pub proposal: MimiProposal,
+    /// Whether the batch of the proposal was applied. The audit log of a room only contains applied batches, because rejected batches do not change the state. Applications that keep their own log can record rejected batches with `applied: false`.
+    #[tls_codec(with = "tls::bool")]
+    pub applied: bool,
+}
+
+/// Capabilities that are taken away from a user, regardless of their roles.
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+struct CapabilityOverride {
+    removed: CapabilitySet,
+}
+
+/// A problem that prevents a [`RoomState`] from being verified, together with the role and user it concerns.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    /// The problem, as [`VerifiedRoomState::verify`] would report it.
+    pub error: Error,
+    /// The role the problem concerns, if it concerns a single role.
+    pub role: Option<RoleIndex>,
+    /// The user the problem concerns, if it concerns a single user.
+    pub user: Option<Vec<u8>>,
+}
+
+impl Diagnostic {
+    fn new(error: Error) -> Self {
+        Self {
+            error,
+            role: None,
+            user: None,
+        }
+    }
+
+    fn role(mut self, role: RoleIndex) -> Self {
+        self.role = Some(role);
+        self
+    }
+
+    fn user(mut self, user: &[u8]) -> Self {
+        self.user = Some(user.to_vec());
+        self
+    }
+}
+
+/// The state of the room.
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct RoomState {
+    /// The general rules for the room.
+    policy: RoomPolicy,
+
+    /// The roles held by each user in the room. A user can hold several roles at once.
+    #[tls_codec(with = "tls::btreemap_btreeset")]
+    users: BTreeMap<Vec<u8>, BTreeSet<RoleIndex>>,
+
+    /// The name of the room. Empty if no name was set.
+    room_name: TlsString,
+
+    /// The description of the room. Empty if no description was set.
+    room_description: TlsString,
+
+    /// The URI of the room avatar. Empty if no avatar was set.
+    room_avatar: TlsString,
+
+    /// The active calls and their participants.
+    #[tls_codec(with = "tls::btreemap_btreeset")]
+    calls: BTreeMap<CallId, BTreeSet<Vec<u8>>>,
+
+    /// The id of the next call that is started.
+    next_call_id: CallId,
+
+    /// Roles that are replaced by a fallback role at a given time.
+    #[tls_codec(with = "tls::btreemap")]
+    role_expiries: BTreeMap<Vec<u8>, RoleExpiry>,
+
+    /// Capabilities that are taken away from users. Mutes persist when a user leaves and joins again.
+    #[tls_codec(with = "tls::btreemap")]
+    capability_overrides: BTreeMap<Vec<u8>, CapabilityOverride>,
+
+    /// The applied proposals, if the audit log is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[tls_codec(with = "tls::optional_vec")]
+    audit_log: Option<Vec<AuditEntry>>,
+
+    /// Policy proposals that do not have enough approvals yet.
+    #[tls_codec(with = "tls::btreemap")]
+    pending_policy_proposals: BTreeMap<ProposalId, PendingPolicyProposal>,
+
+    /// The id of the next policy proposal.
+    next_proposal_id: ProposalId,
+}
+
+/// A [`RoomState`] whose policy is stored as a patch against a base policy.
+#[derive(TlsSize, TlsSerialize, TlsDeserializeBytes)]
+struct RoomStateDelta {
+    patch: PolicyPatch,
+    #[tls_codec(with = "tls::btreemap_btreeset")]
+    users: BTreeMap<Vec<u8>, BTreeSet<RoleIndex>>,
+    room_name: TlsString,
+    room_description: TlsString,
+    room_avatar: TlsString,
+    #[tls_codec(with = "tls::btreemap_btreeset")]
+    calls: BTreeMap<CallId, BTreeSet<Vec<u8>>>,
+    next_call_id: CallId,
+    #[tls_codec(with = "tls::btreemap")]
+    role_expiries: BTreeMap<Vec<u8>, RoleExpiry>,
+    #[tls_codec(with = "tls::btreemap")]
+    capability_overrides: BTreeMap<Vec<u8>, CapabilityOverride>,
+    #[tls_codec(with = "tls::optional_vec")]
+    audit_log: Option<Vec<AuditEntry>>,
+    #[tls_codec(with = "tls::btreemap")]
+    pending_policy_proposals: BTreeMap<ProposalId, PendingPolicyProposal>,
+    next_proposal_id: ProposalId,
+}
+
+impl RoomState {
+    fn new(policy: RoomPolicy, users: BTreeMap<Vec<u8>, BTreeSet<RoleIndex>>) -> Self {
+        Self {
+            policy,
+            users,
+            room_name: TlsString("".to_owned()),
+            room_description: TlsString("".to_owned()),
+            room_avatar: TlsString("".to_owned()),
+            calls: BTreeMap::new(),
+            next_call_id: CallId(0),
+            role_expiries: BTreeMap::new(),
+            capability_overrides: BTreeMap::new(),
+            audit_log: None,
+            pending_policy_proposals: BTreeMap::new(),
+            next_proposal_id: ProposalId(0),
+        }
+    }
+
+    /// The most privileged role of the user, or [`RoleIndex::Outsider`] if the user is not in the room.
+    fn user_role(&self, user_id: &[u8]) -> RoleIndex {
+        self.users
+            .get(user_id)
+            .and_then(|roles| roles.iter().max_by_key(|role| role.privilege()))
+            .cloned()
+            .unwrap_or(RoleIndex::Outsider)
+    }
+
+    /// All roles held by the user. Users that are not in the room only hold [`RoleIndex::Outsider`].
+    fn user_roles(&self, user_id: &[u8]) -> BTreeSet<RoleIndex> {
+        self.users
+            .get(user_id)
+            .cloned()
+            .unwrap_or_else(|| BTreeSet::from([RoleIndex::Outsider]))
+    }
+
+    /// The union of the capabilities of all roles held by the user. Roles that are not defined in the policy have no capabilities.
+    fn user_capabilities(&self, user_id: &[u8]) -> CapabilitySet {
+        let mut capabilities = CapabilitySet::default();
+        for role in self.user_roles(user_id) {
+            capabilities.extend(self.policy.effective_capabilities(role).iter());
+        }
+        if let Some(capability_override) = self.capability_overrides.get(user_id) {
+            for capability in capability_override.removed.iter() {
+                capabilities.remove(&capability);
+            }
+        }
+        capabilities
+    }
+
+    fn has_capability(&self, user_id: &[u8], capability: Capability) -> bool {
+        self.user_capabilities(user_id).contains(capability)
+    }
+
+    /// Replace a string value of the room, if the sender has the required capability.
+    fn try_set_string(
+        sender_capable: bool,
+        field: &mut TlsString,
+        value: &str,
+        max_length: usize,
+    ) -> Result<()> {
+        if !sender_capable {
+            return Err(Error::NotCapable);
+        }
+
+        *field = TlsString::try_new(value, max_length)?;
+        Ok(())
+    }
+
+    /// The number of users holding each role. A user is counted once for every role they hold, except for roles that do not count toward the membership.
+    fn role_member_counts(&self) -> BTreeMap<RoleIndex, u32> {
+        let mut counts = BTreeMap::new();
+        for roles in self.users.values() {
+            for role in roles {
+                let counted = self
+                    .policy
+                    .roles
+                    .get(role)
+                    .is_none_or(|role_info| role_info.counts_toward_membership);
+                if counted {
+                    *counts.entry(*role).or_insert(0) += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    /// Whether the user is the only owner in a room whose policy requires an owner.
+    fn is_last_required_owner(&self, user_id: &[u8]) -> bool {
+        let owner_required = self
+            .policy
+            .roles
+            .get(&RoleIndex::Owner)
+            .is_some_and(|role_info| role_info.min_participants_constraint > 0);
+
+        owner_required
+            && self
+                .users
+                .iter()
+                .filter(|(_, roles)| roles.contains(&RoleIndex::Owner))
+                .all(|(user, _)| user == user_id)
+    }
+
+    /// Remove the user from all calls and end calls without participants.
+    fn leave_calls(&mut self, user_id: &[u8]) {
+        for participants in self.calls.values_mut() {
+            participants.remove(user_id);
+        }
+        self.calls
+            .retain(|_, participants| !participants.is_empty());
+    }
+
+    /// Remove the user from all calls if they can neither join nor start calls, e.g. because they left or were banned.
+    fn leave_calls_if_incapable(&mut self, user_id: &[u8]) {
+        if !self.has_capability(user_id, Capability::JoinCall)
+            && !self.has_capability(user_id, Capability::StartCall)
+        {
+            self.leave_calls(user_id);
+        }
+    }
+
+    /// All problems that prevent the state from being verified, in the order in which [`VerifiedRoomState::verify`] checks them. Every problem of the policy itself, as found by [`RoomPolicy::validate_all`], is reported as its own diagnostic.
+    pub fn diagnose(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+
+        // POLICY CHECKS
+
+        for error in self.policy.validate_all() {
+            let role = match error {
+                Error::RoleMinMaxViolated { role, .. }
+                | Error::InheritanceCycle { role }
+                | Error::UnreachableRole { role }
+                | Error::NoExitPath { role }
+                | Error::SelfEscalation { from: role, .. } => Some(role),
+                _ => None,
+            };
+            diagnostics.push(Diagnostic {
+                error,
+                role,
+                user: None,
+            });
+        }
+
+        // ROOM STATE CHECKS
+
+        // No outsiders are explicitly listed and every listed user holds at least one role
+        for (user, roles) in &self.users {
+            if user.is_empty() {
+                diagnostics.push(Diagnostic::new(Error::InvalidUserId).user(user));
+            }
+            if roles.is_empty() || roles.contains(&RoleIndex::Outsider) {
+                diagnostics.push(Diagnostic::new(Error::UserNotInRoom).user(user));
+            }
+        }
+
+        // Only members take part in calls
+        for participants in self.calls.values() {
+            for participant in participants {
+                if !self.users.contains_key(participant) {
+                    diagnostics.push(Diagnostic::new(Error::UserNotInRoom).user(participant));
+                }
+            }
+        }
+
+        // Mutes of users that left survive until they are lifted, which drops the override
+        for (user, capability_override) in &self.capability_overrides {
+            if user.is_empty() {
+                diagnostics.push(Diagnostic::new(Error::InvalidUserId).user(user));
+            }
+            if capability_override.removed.is_empty() && !self.users.contains_key(user) {
+                diagnostics.push(Diagnostic::new(Error::UserNotInRoom).user(user));
+            }
+        }
+
+        for (value, max_length) in [
+            (&self.room_name, MAX_ROOM_NAME_LENGTH),
+            (&self.room_description, MAX_ROOM_DESCRIPTION_LENGTH),
+            (&self.room_avatar, MAX_ROOM_AVATAR_LENGTH),
+        ] {
+            if let Err(error) = check_length(value, max_length) {
+                diagnostics.push(Diagnostic::new(error));
+            }
+        }
+
+        if self.pending_policy_proposals.len() > MAX_PENDING_POLICY_PROPOSALS {
+            diagnostics.push(Diagnostic::new(Error::TooManyProposals));
+        }
+
+        // Banned users cannot hold any other role
+        for (user, roles) in &self.users {
+            if roles.len() > 1 && roles.contains(&RoleIndex::Banned) {
+                diagnostics.push(
+                    Diagnostic::new(Error::Banned)
+                        .role(RoleIndex::Banned)
+                        .user(user),
+                );
+            }
+        }
+
+        // Scheduled changes apply to roles the user holds and fall back to defined roles
+        for (user, expiry) in &self.role_expiries {
+            if !self
+                .users
+                .get(user)
+                .is_some_and(|roles| roles.contains(&expiry.role))
+            {
+                diagnostics.push(
+                    Diagnostic::new(Error::UserNotInRoom)
+                        .role(expiry.role)
+                        .user(user),
+                );
+            }
+            if expiry.fallback_role != RoleIndex::Outsider
+                && !self.policy.roles.contains_key(&expiry.fallback_role)
+            {
+                diagnostics.push(
+                    Diagnostic::new(Error::RoleNotDefined)
+                        .role(expiry.fallback_role)
+                        .user(user),
+                );
+            }
+        }
+
+        let role_member_count = self.role_member_counts();
+        for (user, roles) in &self.users {
+            for role in roles {
+                if !self.policy.roles.contains_key(role) {
+                    diagnostics.push(
+                        Diagnostic::new(Error::RoleNotDefined)
+                            .role(*role)
+                            .user(user),
+                    );
+                }
+            }
+        }
+
+        for (role_index, role_info) in &self.policy.roles {
+            let count = *role_member_count.get(role_index).unwrap_or(&0);
+            if let Some(max) = role_info.max_participants_constraint {
+                if count > max {
+                    diagnostics.push(
+                        Diagnostic::new(Error::RoleMinMaxViolated {
+                            role: *role_index,
+                            kind: MinMaxKind::Max,
+                            limit: max,
+                            actual: count,
+                        })
+                        .role(*role_index),
+                    );
+                }
+            }
+            if count < role_info.min_participants_constraint {
+                diagnostics.push(
+                    Diagnostic::new(Error::RoleMinMaxViolated {
+                        role: *role_index,
+                        kind: MinMaxKind::Min,
+                        limit: role_info.min_participants_constraint,
+                        actual: count,
+                    })
+                    .role(*role_index),
+                );
+            }
+        }
+
+        // TODO: Active participants? The active member counts are stored, but not enforced until activity is tracked.
+
+        diagnostics
+    }
+
+    /// Panic if a verified state breaks basic invariants. This catches bugs in the checks of [`VerifiedRoomState::verify`] during development.
+    #[cfg(debug_assertions)]
+    fn assert_invariants(&self) {
+        for (user, roles) in &self.users {
+            assert!(
+                !roles.is_empty() && !roles.contains(&RoleIndex::Outsider),
+                "user {user:?} is listed as an outsider: {roles:?}"
+            );
+        }
+
+        if let Some(owner_role_info) = self.policy.roles.get(&RoleIndex::Owner) {
+            let owners = self
+                .users
+                .values()
+                .filter(|roles| roles.contains(&RoleIndex::Owner))
+                .count();
+            assert!(
+                owners >= owner_role_info.min_participants_constraint as usize,
+                "room has {owners} owners, but requires {}",
+                owner_role_info.min_participants_constraint
+            );
+        }
+    }
+
+    /// Whether the sender may change the role of the target from `from` to `to`. Any role held by the sender can authorize a change of another user. Adding and removing other users also requires [`Capability::AddParticipant`] and [`Capability::RemoveParticipant`].
+    fn may_change_role(
+        &self,
+        sender: &[u8],
+        target: &[u8],
+        from: RoleIndex,
+        to: RoleIndex,
+    ) -> bool {
+        if sender == target {
+            // Joining and leaving do not need a capability
+            let capable = from == RoleIndex::Outsider
+                || to == RoleIndex::Outsider
+                || self.has_capability(sender, Capability::ChangeOwnRole);
+            capable
+                && self
+                    .policy
+                    .roles
+                    .get(&from)
+                    .is_some_and(|role_info| role_info.self_role_changes.contains(&to))
+        } else {
+            let special = |role: RoleIndex| matches!(role, RoleIndex::Outsider | RoleIndex::Banned);
+            if from == RoleIndex::Outsider
+                && !special(to)
+                && !self.has_capability(sender, Capability::AddParticipant)
+            {
+                return false;
+            }
+            if to == RoleIndex::Outsider
+                && !special(from)
+                && !self.has_capability(sender, Capability::RemoveParticipant)
+            {
+                return false;
+            }
+
+            // Protection by any role of the sender wins over the role changes it allows
+            let sender_roles: Vec<_> = self
+                .user_roles(sender)
+                .iter()
+                .filter_map(|sender_role| self.policy.roles.get(sender_role))
+                .collect();
+            if sender_roles
+                .iter()
+                .any(|sender_role_info| sender_role_info.protected_roles.contains(&from))
+            {
+                return false;
+            }
+
+            sender_roles.iter().any(|sender_role_info| {
+                sender_role_info
+                    .authorized_role_changes
+                    .get(&from)
+                    .is_some_and(|roles| roles.contains(&to))
+            })
+        }
+    }
+
+    /// Whether any role of the sender allows moving members of the room to the role.
+    fn may_change_members_to(&self, sender: &[u8], role: RoleIndex) -> bool {
+        self.user_roles(sender)
+            .iter()
+            .filter_map(|sender_role| self.policy.roles.get(sender_role))
+            .any(|sender_role_info| {
+                sender_role_info
+                    .authorized_role_changes
+                    .iter()
+                    .any(|(from, targets)| *from != RoleIndex::Outsider && targets.contains(&role))
+            })
+    }
+
+    /// Apply the proposals without verifying the resulting state. `parent` is the state of the parent room, which is required to add users to [`MembershipStyle::ParentDependent`] rooms.
+    fn try_regular_proposals(
+        &mut self,
+        sender: &[u8],
+        proposals: &[MimiProposal],
+        parent: Option<&RoomState>,
+    ) -> Result<()> {
+        for proposal in proposals {
+            match proposal {
+                MimiProposal::ChangeRole { target, role } => {
+                    self.try_change_role(sender, target, *role, parent)?;
+                }
+                MimiProposal::ChangeRoleUntil {
+                    target,
+                    role,
+                    expires_at,
+                    fallback_role,
+                } => {
+                    // The sender must also be allowed to make the scheduled change
+                    if !self.may_change_role(sender, target, *role, *fallback_role)
+                        || (*role == RoleIndex::Banned
+                            && *fallback_role != RoleIndex::Banned
+                            && !self.has_capability(sender, Capability::UnBan))
+                    {
+                        return Err(Error::NotCapable);
+                    }
+
+                    self.try_change_role(sender, target, *role, parent)?;
+
+                    if *role != RoleIndex::Outsider {
+                        self.role_expiries.insert(
+                            target.clone(),
+                            RoleExpiry {
+                                role: *role,
+                                expires_at: *expires_at,
+                                fallback_role: *fallback_role,
+                            },
+                        );
+                    }
+                }
+                MimiProposal::MuteUser {
+                    target,
+                    capabilities,
+                } => {
+                    // Users cannot be muted by less privileged users
+                    if !self.has_capability(sender, Capability::MuteUser)
+                        || self.user_role(target).privilege() > self.user_role(sender).privilege()
+                    {
+                        return Err(Error::NotCapable);
+                    }
+                    if !capabilities.is_empty() && !self.users.contains_key(target) {
+                        return Err(Error::UserNotInRoom);
+                    }
+
+                    if capabilities.is_empty() {
+                        self.capability_overrides.remove(target);
+                    } else {
+                        self.capability_overrides.insert(
+                            target.clone(),
+                            CapabilityOverride {
+                                removed: capabilities.iter().cloned().collect(),
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn try_change_role(
+        &mut self,
+        sender: &[u8],
+        target: &[u8],
+        role: RoleIndex,
+        parent: Option<&RoomState>,
+    ) -> Result<()> {
+        let target_user_role = self.user_role(target);
+
+        // Other users cannot be kicked once they left
+        if sender != target
+            && target_user_role == RoleIndex::Outsider
+            && role == RoleIndex::Outsider
+        {
+            return Err(Error::UserNotInRoom);
+        }
+
+        // Do nothing if the role is already correct. This is required because a self-remove is applied twice: Once when submitted as a proposal and another time when the proposal is committed.
+        if target_user_role == role {
+            return Ok(());
+        }
+
+        // The change applies to the primary role of the target
+        if !self.may_change_role(sender, target, target_user_role, role) {
+            // Changes the sender could make for members, like bans and demotions, fail because the target is absent
+            if target_user_role == RoleIndex::Outsider && self.may_change_members_to(sender, role) {
+                return Err(Error::UserNotInRoom);
+            }
+            return Err(Error::NotCapable);
+        }
+
+        // Preauthorized users receive their role when they join by themselves
+        let role = match self.policy.preauthorized.get(target) {
+            Some(preauthorized_role)
+                if sender == target
+                    && target_user_role == RoleIndex::Outsider
+                    && role == RoleIndex::Regular =>
+            {
+                *preauthorized_role
+            }
+            _ => role,
+        };
+
+        // Bans are only lifted by users that can unban
+        if target_user_role == RoleIndex::Banned && !self.has_capability(sender, Capability::UnBan)
+        {
+            return Err(Error::NotCapable);
+        }
+
+        // Users cannot join or leave through proposals if the membership is fixed
+        if self.policy.membership_style == MembershipStyle::FixedMembership
+            && (target_user_role == RoleIndex::Outsider || role == RoleIndex::Outsider)
+        {
+            return Err(Error::MembershipFixed);
+        }
+
+        // Users can only join if they are members of the parent room
+        if self.policy.membership_style == MembershipStyle::ParentDependent
+            && target_user_role == RoleIndex::Outsider
+            && role != RoleIndex::Outsider
+        {
+            let parent = parent.ok_or(Error::ParentRoomMissing)?;
+            if matches!(
+                parent.user_role(target),
+                RoleIndex::Outsider | RoleIndex::Banned
+            ) {
+                return Err(Error::UserNotInRoom);
+            }
+        }
+
+        if target_user_role == RoleIndex::Owner && self.is_last_required_owner(target) {
+            return Err(Error::WouldRemoveLastOwner);
+        }
+
+        // A scheduled change no longer applies once the role changed
+        self.role_expiries.remove(target);
+
+        if role == RoleIndex::Outsider {
+            self.users.remove(target);
+        } else {
+            let roles = self.users.entry(target.to_vec()).or_default();
+            roles.remove(&target_user_role);
+            roles.insert(role);
+        }
+        self.leave_calls_if_incapable(target);
+
+        Ok(())
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+#[serde(try_from = "RoomState")]
+pub struct VerifiedRoomState(RoomState);
+
+impl TryFrom<RoomState> for VerifiedRoomState {
+    type Error = Error;
+
+    fn try_from(state: RoomState) -> Result<Self> {
+        Self::verify(state)
+    }
+}
+
+impl VerifiedRoomState {
+    pub fn verify(state: RoomState) -> Result<Self> {
+        if let Some(diagnostic) = state.diagnose().into_iter().next() {
+            return Err(diagnostic.error);
+        }
+
+        Ok(VerifiedRoomState(state))
+    }
+
+    /// Decode and verify a TLS-encoded room state received from an untrusted peer. Malformed input is reported as [`Error::Decode`].
+    pub fn try_from_tls_bytes(bytes: &[u8]) -> Result<Self> {
+        let state = RoomState::tls_deserialize_exact_bytes(bytes)
+            .map_err(|error| Error::Decode(error.to_string()))?;
+        Self::verify(state)
+    }
+
+    /// Decode and verify a CBOR-encoded room state received from an untrusted peer. Malformed input is reported as [`Error::Decode`].
+    pub fn try_from_cbor_bytes(bytes: &[u8]) -> Result<Self> {
+        let state: RoomState =
+            ciborium::de::from_reader(bytes).map_err(|error| Error::Decode(error.to_string()))?;
+        Self::verify(state)
+    }
+
+    /// Decode and verify a room state that is encoded either as TLS or as CBOR. Input that neither decodes is reported as [`Error::Decode`].
+    pub fn from_bytes_autodetect(bytes: &[u8]) -> Result<Self> {
+        // CBOR-encoded states are maps. A TLS-encoded state starting with the same byte would need a policy of more than 512 MiB.
+        if bytes
+            .first()
+            .is_some_and(|byte| (0xa0..=0xbf).contains(byte))
+        {
+            return Self::try_from_cbor_bytes(bytes);
+        }
+        match Self::try_from_tls_bytes(bytes) {
+            Err(Error::Decode(_)) => Self::try_from_cbor_bytes(bytes),
+            result => result,
+        }
+    }
+
+    pub fn new(owner: Vec<u8>, policy: RoomPolicy) -> Result<Self> {
+        let mut users = BTreeMap::new();
+        users.insert(owner, BTreeSet::from([RoleIndex::Owner]));
+
+        Self::verify(RoomState::new(policy, users))
+    }
+
+    /// Reconstruct a room from a TLS-encoded policy and the role of every member.
+    pub fn assemble(policy_bytes: &[u8], members: &BTreeMap<Vec<u8>, RoleIndex>) -> Result<Self> {
+        let policy = RoomPolicy::tls_deserialize_exact_bytes(policy_bytes)
+            .map_err(|_| Error::DecodingError)?;
+
+        let users = members
+            .iter()
+            .map(|(user, role)| (user.clone(), BTreeSet::from([*role])))
+            .collect();
+
+        Self::verify(RoomState::new(policy, users))
+    }
+
+    /// Restore a room from a policy and the roles of every member, keyed by the raw user ids returned by [`VerifiedRoomState::raw_member_keys`].
+    pub fn restore<'a>(
+        policy: RoomPolicy,
+        members: impl IntoIterator<Item = (&'a [u8], BTreeSet<RoleIndex>)>,
+    ) -> Result<Self> {
+        let users = members
+            .into_iter()
+            .map(|(user, roles)| (user.to_vec(), roles))
+            .collect();
+
+        Self::verify(RoomState::new(policy, users))
+    }
+
+    pub fn fallback_room(members: Vec<Vec<u8>>) -> VerifiedRoomState {
+        let mut members_iter = members.into_iter();
+        let owner = members_iter.next().unwrap();
+
+        let mut room_state =
+            VerifiedRoomState::new(owner.clone(), RoomPolicy::default_trusted_private())
+                .expect("we know the policy, this cannot fail");
+
+        for user in members_iter {
+            room_state
+                .apply_regular_proposals(
+                    &owner,
+                    &[MimiProposal::ChangeRole {
+                        target: user,
+                        role: RoleIndex::Regular,
+                    }],
+                )
+                .expect("we know the policy, this cannot fail");
+        }
+
+        room_state
+    }
+
+    pub fn unverified(&self) -> &RoomState {
+        &self.0
+    }
+
+    pub fn has_capability(&self, user_id: &[u8], capability: Capability) -> bool {
+        self.0.has_capability(user_id, capability)
+    }
+
+    /// Whether the actor can delete a message by the author. Deleting own messages requires [`Capability::DeleteOwnMessage`], deleting messages of others requires [`Capability::DeleteAnyMessage`].
+    pub fn can_delete_message(&self, actor: &[u8], author: &[u8]) -> bool {
+        if actor == author {
+            self.has_capability(actor, Capability::DeleteOwnMessage)
+        } else {
+            self.has_capability(actor, Capability::DeleteAnyMessage)
+        }
+    }
+
+    /// Whether the actor can edit a message by the author. Editing own messages requires [`Capability::EditOwnMessage`], editing messages of others requires [`Capability::EditOtherMessage`].
+    pub fn can_edit_message(&self, actor: &[u8], author: &[u8]) -> bool {
+        if actor == author {
+            self.has_capability(actor, Capability::EditOwnMessage)
+        } else {
+            self.has_capability(actor, Capability::EditOtherMessage)
+        }
+    }
+
+    /// What removing the capability from the role would change for the members of the room.
+    pub fn capability_removal_impact(
+        &self,
+        role: RoleIndex,
+        capability: Capability,
+    ) -> RemovalImpact {
+        let mut state = self.0.clone();
+        if let Some(role_info) = state.policy.roles.get_mut(&role) {
+            role_info.role_capabilities.remove(&capability);
+        }
+
+        let members_losing = self
+            .0
+            .users
+            .keys()
+            .filter(|user| {
+                self.0.has_capability(user, capability.clone())
+                    && !state.has_capability(user, capability.clone())
+            })
+            .count();
+
+        RemovalImpact {
+            members_losing,
+            ungranted: state.policy.roles_with_capability(capability).is_empty(),
+        }
+    }
+
+    pub fn can_apply_regular_proposals(
+        &self,
+        sender: &[u8],
+        proposals: &[MimiProposal],
+    ) -> Result<()> {
+        let mut state = self.0.clone();
+
+        state.try_regular_proposals(sender, proposals, None)?;
+
+        Ok(())
+    }
+
+    /// Whether the proposal would be applied, would be denied, or would not change the state. Clients can use this to avoid sending commits that do nothing.
+    ///
+    /// For rooms with [`MembershipStyle::ParentDependent`] membership, `parent` is the state of the parent room, as for [`VerifiedRoomState::apply_regular_proposals_with_parent`].
+    pub fn classify_proposal(
+        &self,
+        sender: &[u8],
+        proposal: &MimiProposal,
+        parent: Option<&VerifiedRoomState>,
+    ) -> ProposalOutcome {
+        let mut state = self.0.clone();
+
+        if let Err(error) = state.try_regular_proposals(
+            sender,
+            std::slice::from_ref(proposal),
+            parent.map(|parent| &parent.0),
+        ) {
+            return ProposalOutcome::Denied(error);
+        }
+        if state == self.0 {
+            return ProposalOutcome::NoOp;
+        }
+        match Self::verify(state) {
+            Ok(_) => ProposalOutcome::WouldApply,
+            Err(error) => ProposalOutcome::Denied(error),
+        }
+    }
+
+    /// Check whether the sender may move the target to the role, without changing the state. Returns the first error [`VerifiedRoomState::apply_regular_proposals`] would fail with, checking first that the role is defined. Assigning the role the target already has is allowed.
+    ///
+    /// `parent` is passed on to [`VerifiedRoomState::classify_proposal`].
+    pub fn validate_role_assignment(
+        &self,
+        sender: &[u8],
+        target: &[u8],
+        new_role: RoleIndex,
+        parent: Option<&VerifiedRoomState>,
+    ) -> Result<()> {
+        if !self.0.policy.roles.contains_key(&new_role) {
+            return Err(Error::RoleNotDefined);
+        }
+        let proposal = MimiProposal::ChangeRole {
+            target: target.to_vec(),
+            role: new_role,
+        };
+        match self.classify_proposal(sender, &proposal, parent) {
+            ProposalOutcome::NoOp | ProposalOutcome::WouldApply => Ok(()),
+            ProposalOutcome::Denied(error) => Err(error),
+        }
+    }
+
+    /// The roles the sender may move the target to, sorted by [`RoleIndex`]. This takes all checks of [`VerifiedRoomState::apply_regular_proposals`] into account, including capabilities and participant constraints.
+    ///
+    /// `parent` is passed on to [`VerifiedRoomState::classify_proposal`].
+    pub fn allowed_transitions(
+        &self,
+        sender: &[u8],
+        target: &[u8],
+        parent: Option<&VerifiedRoomState>,
+    ) -> Vec<RoleIndex> {
+        let current_role = self.0.user_role(target);
+
+        self.0
+            .policy
+            .roles
+            .keys()
+            .filter(|role| **role != current_role)
+            .filter(|role| {
+                let proposal = MimiProposal::ChangeRole {
+                    target: target.to_vec(),
+                    role: **role,
+                };
+                self.classify_proposal(sender, &proposal, parent) == ProposalOutcome::WouldApply
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The capability that is checked when the sender makes the proposal, if any. Clients can use this to explain why a proposal is denied.
+    ///
+    /// Kicks need [`Capability::RemoveParticipant`], invites [`Capability::AddParticipant`], unbans [`Capability::UnBan`] and changes of the own role [`Capability::ChangeOwnRole`]. Other role changes, including bans, are only authorized by the role changes of the sender's roles and have no capability.
+    pub fn required_capability(
+        &self,
+        sender: &[u8],
+        proposal: &MimiProposal,
+    ) -> Option<Capability> {
+        let (target, role) = match proposal {
+            MimiProposal::ChangeRole { target, role } => (target, *role),
+            MimiProposal::ChangeRoleUntil {
+                target,
+                role,
+                fallback_role,
+                ..
+            } => {
+                // A scheduled unban is checked when the proposal is made
+                if let Some(capability) = self.required_capability(
+                    sender,
+                    &MimiProposal::ChangeRole {
+                        target: target.clone(),
+                        role: *role,
+                    },
+                ) {
+                    return Some(capability);
+                }
+                return (*role == RoleIndex::Banned && *fallback_role != RoleIndex::Banned)
+                    .then_some(Capability::UnBan);
+            }
+            MimiProposal::MuteUser { .. } => return Some(Capability::MuteUser),
+        };
+
+        let from = self.0.user_role(target);
+        let special = |role: RoleIndex| matches!(role, RoleIndex::Outsider | RoleIndex::Banned);
+        if from == role {
+            None
+        } else if from == RoleIndex::Banned {
+            Some(Capability::UnBan)
+        } else if sender == target.as_slice() {
+            (from != RoleIndex::Outsider && role != RoleIndex::Outsider)
+                .then_some(Capability::ChangeOwnRole)
+        } else if from == RoleIndex::Outsider && !special(role) {
+            Some(Capability::AddParticipant)
+        } else if role == RoleIndex::Outsider && !special(from) {
+            Some(Capability::RemoveParticipant)
+        } else {
+            None
+        }
+    }
+
+    /// Like [`VerifiedRoomState::apply_regular_proposals`], but returns [`Error::NothingToDo`] if the proposals would leave the state unchanged.
+    pub fn apply_regular_proposals_strict(
+        &mut self,
+        sender: &[u8],
+        proposals: &[MimiProposal],
+    ) -> Result<()> {
+        self.apply_logged(sender, proposals, None, true)
+    }
+
+    pub fn apply_regular_proposals(
+        &mut self,
+        sender: &[u8],
+        proposals: &[MimiProposal],
+    ) -> Result<()> {
+        self.apply_logged(sender, proposals, None, false)
+    }
+
+    /// Apply each proposal on its own and return the result for every proposal. Unlike [`VerifiedRoomState::apply_regular_proposals`], this is not atomic: failed proposals are skipped, and the proposals that succeed stay applied. Each proposal is checked against the state left by the proposals before it.
+    pub fn apply_regular_proposals_partial(
+        &mut self,
+        sender: &[u8],
+        proposals: &[MimiProposal],
+    ) -> Vec<Result<()>> {
+        proposals
+            .iter()
+            .map(|proposal| self.apply_regular_proposals(sender, std::slice::from_ref(proposal)))
+            .collect()
+    }
+
+    /// Apply the proposals and record them in the audit log, if it is enabled. Rejected proposals leave the state, including the log, unchanged. In strict mode, proposals that leave the state unchanged fail with [`Error::NothingToDo`].
+    fn apply_logged(
+        &mut self,
+        sender: &[u8],
+        proposals: &[MimiProposal],
+        parent: Option<&RoomState>,
+        strict: bool,
+    ) -> Result<()> {
+        self.try_apply(sender, proposals, parent, strict)?;
+
+        if let Some(audit_log) = &mut self.0.audit_log {
+            audit_log.extend(proposals.iter().map(|proposal| AuditEntry {
+                sender: sender.to_vec(),
+                proposal: proposal.clone(),
+                applied: true,
+            }));
+        }
+
+        Ok(())
+    }
+
+    fn try_apply(
+        &mut self,
+        sender: &[u8],
+        proposals: &[MimiProposal],
+        parent: Option<&RoomState>,
+        strict: bool,
+    ) -> Result<()> {
+        let mut state = self.0.clone();
+
+        state.try_regular_proposals(sender, proposals, parent)?;
+        if strict && state == self.0 {
+            return Err(Error::NothingToDo);
+        }
+
+        *self = Self::verify(state)?;
+        #[cfg(debug_assertions)]
+        self.0.assert_invariants();
+
+        Ok(())
+    }
+
+    /// Start recording applied proposals in the audit log. The log is part of the state, so it is included when the state is encoded.
+    pub fn enable_audit_log(&mut self) {
+        self.0.audit_log.get_or_insert_with(Vec::new);
+    }
+
+    /// The proposals applied since the audit log was enabled, in order, or `None` if it is disabled.
+    pub fn audit_log(&self) -> Option<&[AuditEntry]> {
+        self.0.audit_log.as_deref()
+    }
+
+    /// Like [`VerifiedRoomState::apply_regular_proposals`], but for rooms with [`MembershipStyle::ParentDependent`] membership. Only members of the parent room can be added.
+    pub fn apply_regular_proposals_with_parent(
+        &mut self,
+        sender: &[u8],
+        proposals: &[MimiProposal],
+        parent: &VerifiedRoomState,
+    ) -> Result<()> {
+        self.apply_logged(sender, proposals, Some(&parent.0), false)
+    }
+
+    /// Remove the user from the room, independent of the self role changes of their roles.
+    ///
+    /// Banned users cannot leave, because that would lift the ban.
+    pub fn leave(&mut self, user: &[u8]) -> Result<()> {
+        let Some(roles) = self.0.users.get(user) else {
+            return Err(Error::UserNotInRoom);
+        };
+        if roles.contains(&RoleIndex::Banned) {
+            return Err(Error::Banned);
+        }
+
+        let mut state = self.0.clone();
+        state.users.remove(user);
+        state.role_expiries.remove(user);
+        state.leave_calls(user);
+
+        *self = Self::verify(state)?;
+
+        Ok(())
+    }
+
+    /// Move the user from their primary role to a less privileged role without leaving the room.
+    ///
+    /// The transition must be listed in the user's self role changes, and the vacated role must still meet its minimum member count afterwards.
+    pub fn step_down(&mut self, user: &[u8], to: RoleIndex) -> Result<()> {
+        let current_role = self.0.user_role(user);
+        if current_role == RoleIndex::Outsider {
+            return Err(Error::UserNotInRoom);
+        }
+        if current_role == to {
+            return Err(Error::NothingToDo);
+        }
+        if to == RoleIndex::Outsider || to.privilege() >= current_role.privilege() {
+            return Err(Error::InvalidRoleTransition {
+                source_role: current_role,
+                target_role: to,
+            });
+        }
+
+        self.apply_regular_proposals(
+            user,
+            &[MimiProposal::ChangeRole {
+                target: user.to_vec(),
+                role: to,
+            }],
+        )
+    }
+
+    /// The proposal that undoes an applied proposal, given the role the target had before.
+    ///
+    /// The inverse is not necessarily authorized for the sender of the applied proposal. Callers must still check it, e.g. with [`VerifiedRoomState::can_apply_regular_proposals`].
+    pub fn inverse_proposal(
+        &self,
+        applied: &MimiProposal,
+        previous_role: RoleIndex,
+    ) -> MimiProposal {
+        match applied {
+            MimiProposal::ChangeRole { target, .. }
+            | MimiProposal::ChangeRoleUntil { target, .. } => MimiProposal::ChangeRole {
+                target: target.clone(),
+                role: previous_role,
+            },
+            // Previous mutes are not known, so the inverse lifts the mute completely
+            MimiProposal::MuteUser { target, .. } => MimiProposal::MuteUser {
+                target: target.clone(),
+                capabilities: Vec::new(),
+            },
+        }
+    }
+
+    /// Compute the proposals the sender has to send to reach the target membership, where the target maps every member to their primary role.
+    ///
+    /// Promotions come first, followed by demotions, kicks and invites. A change of the sender's own role always comes last, so the sender does not lose capabilities before the other changes are made.
+    pub fn plan_to(
+        &self,
+        sender: &[u8],
+        target_members: &BTreeMap<Vec<u8>, RoleIndex>,
+    ) -> Result<Vec<MimiProposal>> {
+        let mut changes = Vec::new();
+
+        for user in self.0.users.keys() {
+            if !target_members.contains_key(user) {
+                changes.push((user.clone(), RoleIndex::Outsider));
+            }
+        }
+        for (user, role) in target_members {
+            if *role == RoleIndex::Outsider {
+                return Err(Error::Irreconcilable);
+            }
+            if self.0.user_role(user) != *role {
+                changes.push((user.clone(), *role));
+            }
+        }
+
+        let order = |(user, role): &(Vec<u8>, RoleIndex)| {
+            let current_role = self.0.user_role(user);
+            let kind = if current_role == RoleIndex::Outsider {
+                3 // Invite
+            } else if *role == RoleIndex::Outsider {
+                2 // Kick
+            } else if role.privilege() < current_role.privilege() {
+                1 // Demotion
+            } else {
+                0 // Promotion
+            };
+            (user == sender, kind)
+        };
+        changes.sort_by_key(order);
+
+        let proposals: Vec<_> = changes
+            .into_iter()
+            .map(|(target, role)| MimiProposal::ChangeRole { target, role })
+            .collect();
+
+        let mut state = self.0.clone();
+        state.try_regular_proposals(sender, &proposals, None)?;
+        Self::verify(state).map_err(|_| Error::Irreconcilable)?;
+
+        Ok(proposals)
+    }
+
+    pub fn apply_policy_proposals(
+        &mut self,
+        sender: &[u8],
+        proposals: &[PolicyProposal],
+    ) -> Result<()> {
+        if !proposals
+            .iter()
+            .all(|proposal| self.has_capability(sender, proposal.required_capability()))
+        {
+            return Err(Error::NotCapable);
+        }
+        if self.0.policy.required_approvals > 1 {
+            return Err(Error::ApprovalRequired);
+        }
+
+        let mut state = self.0.clone();
+        state.policy.try_policy_proposals(proposals)?;
+
+        *self = Self::verify(state)?;
+        #[cfg(debug_assertions)]
+        self.0.assert_invariants();
+
+        Ok(())
+    }
+
+    /// Propose a policy change that is applied once enough users approved it. The proposer approves the proposal and must have the capability required by the proposal, like every approver.
+    ///
+    /// The proposal is applied right away if the policy does not require more approvals. At most [`MAX_PENDING_POLICY_PROPOSALS`] proposals can be pending at the same time.
+    pub fn propose_policy(
+        &mut self,
+        proposer: &[u8],
+        proposal: PolicyProposal,
+    ) -> Result<ProposalId> {
+        if !self.has_capability(proposer, proposal.required_capability()) {
+            return Err(Error::NotCapable);
+        }
+        if self.0.pending_policy_proposals.len() >= MAX_PENDING_POLICY_PROPOSALS {
+            return Err(Error::TooManyProposals);
+        }
+
+        // Ids are never reused, so approvals of an earlier proposal cannot apply to a new one
+        let mut state = self.0.clone();
+        let id = state.next_proposal_id;
+        if state.pending_policy_proposals.contains_key(&id) {
+            return Err(Error::TooManyProposals);
+        }
+        state.next_proposal_id = ProposalId(id.0.checked_add(1).ok_or(Error::TooManyProposals)?);
+        state.pending_policy_proposals.insert(
+            id,
+            PendingPolicyProposal {
+                proposal,
+                approvers: BTreeSet::from([proposer.to_vec()]),
+            },
+        );
+
+        // A proposal that fails right away leaves no trace
+        let previous = self.0.clone();
+        if let Err(error) = self.apply_if_approved(state, id) {
+            self.0 = previous;
+            return Err(error);
+        }
+
+        Ok(id)
+    }
+
+    /// Approve a pending policy proposal. Returns whether the proposal reached the required approvals and was applied.
+    ///
+    /// If the proposal has enough approvals but cannot be applied, for example because the policy changed in the meantime, it is dropped and the error is returned.
+    pub fn approve_policy(&mut self, approver: &[u8], id: ProposalId) -> Result<bool> {
+        let pending = self
+            .0
+            .pending_policy_proposals
+            .get(&id)
+            .ok_or(Error::ProposalNotFound)?;
+        if !self.has_capability(approver, pending.proposal.required_capability()) {
+            return Err(Error::NotCapable);
+        }
+
+        let mut state = self.0.clone();
+        let pending = state
+            .pending_policy_proposals
+            .get_mut(&id)
+            .expect("the proposal was found above");
+        if !pending.approvers.insert(approver.to_vec()) {
+            return Err(Error::NothingToDo);
+        }
+        self.apply_if_approved(state, id)
+    }
+
+    /// Replace the state, applying the pending proposal if it has enough approvals. Returns whether it was applied.
+    fn apply_if_approved(&mut self, mut state: RoomState, id: ProposalId) -> Result<bool> {
+        let pending = &state.pending_policy_proposals[&id];
+        // Approvals of users that lost the capability in the meantime do not count
+        let approvals = pending
+            .approvers
+            .iter()
+            .filter(|approver| {
+                state.has_capability(approver, pending.proposal.required_capability())
+            })
+            .count();
+        if approvals < state.policy.required_approvals.max(1) as usize {
+            self.0 = state;
+            return Ok(false);
+        }
+
+        let pending = state
+            .pending_policy_proposals
+            .remove(&id)
+            .expect("the proposal is pending");
+        let mut applied = state.clone();
+        let result = applied
+            .policy
+            .try_policy_proposals(std::slice::from_ref(&pending.proposal))
+            .and_then(|()| Self::verify(applied));
+        match result {
+            Ok(room) => {
+                *self = room;
+                #[cfg(debug_assertions)]
+                self.0.assert_invariants();
+
+                Ok(true)
+            }
+            Err(error) => {
+                // Keep the proposal from failing the same way on every later approval
+                self.0 = state;
+                Err(error)
+            }
+        }
+    }
+
+    /// Withdraw a pending policy proposal. Every user who could approve the proposal can withdraw it.
+    pub fn withdraw_policy(&mut self, sender: &[u8], id: ProposalId) -> Result<()> {
+        let pending = self
+            .0
+            .pending_policy_proposals
+            .get(&id)
+            .ok_or(Error::ProposalNotFound)?;
+        if !self.has_capability(sender, pending.proposal.required_capability()) {
+            return Err(Error::NotCapable);
+        }
+        self.0.pending_policy_proposals.remove(&id);
+
+        Ok(())
+    }
+
+    pub fn users(&self) -> &BTreeMap<Vec<u8>, BTreeSet<RoleIndex>> {
+        &self.0.users
+    }
+
+    /// Check that the id of every member has the format expected by the application, for example a fixed length. Empty ids are always rejected by [`VerifiedRoomState::verify`].
+    pub fn check_user_ids(&self, is_valid: impl Fn(&[u8]) -> bool) -> Result<()> {
+        if self.0.users.keys().all(|user| is_valid(user)) {
+            Ok(())
+        } else {
+            Err(Error::InvalidUserId)
+        }
+    }
+
+    /// All members with their primary role, sorted by user id. The user ids are borrowed from the state.
+    pub fn members(&self) -> impl Iterator<Item = (&[u8], RoleIndex)> + '_ {
+        self.0
+            .users
+            .keys()
+            .map(|user| (user.as_slice(), self.0.user_role(user)))
+    }
+
+    /// The raw user ids of all members, sorted. Together with [`VerifiedRoomState::user_roles`] this is enough to [`restore`](VerifiedRoomState::restore) the membership.
+    pub fn raw_member_keys(&self) -> Vec<&[u8]> {
+        self.0.users.keys().map(Vec::as_slice).collect()
+    }
+
+    pub fn room_name(&self) -> &str {
+        &self.0.room_name
+    }
+
+    pub fn room_description(&self) -> &str {
+        &self.0.room_description
+    }
+
+    pub fn room_avatar(&self) -> &str {
+        &self.0.room_avatar
+    }
+
+    /// Check whether the sender may share the room history with new members.
+    ///
+    /// If sharing is optional, every member can share. If it is required, only members holding a role listed in the history policy can share.
+    pub fn authorize_history_share(&self, sender: &[u8]) -> Result<()> {
+        let history_policy = &self.0.policy.history_sharing;
+        let sender_roles = self.0.users.get(sender).ok_or(Error::UserNotInRoom)?;
+
+        match history_policy.history_sharing {
+            Optionality::Forbidden => Err(Error::NotCapable),
+            Optionality::Optional => Ok(()),
+            Optionality::Required => {
+                if sender_roles
+                    .iter()
+                    .any(|role| history_policy.who_can_share.contains(role))
+                {
+                    Ok(())
+                } else {
+                    Err(Error::NotCapable)
+                }
+            }
+        }
+    }
+
+    /// Whether history is shared with new members automatically, without an explicit action by a member.
+    pub fn shares_history_automatically(&self) -> bool {
+        let history_policy = &self.0.policy.history_sharing;
+        history_policy.history_sharing != Optionality::Forbidden
+            && history_policy.automatically_share
+    }
+
+    /// Whether the room is listed in room directories.
+    pub fn is_discoverable(&self) -> bool {
+        self.0.policy.discoverable
+    }
+
+    /// Whether strangers can find the room and join it without an invitation, by moving themselves from [`RoleIndex::Outsider`] to [`RoleIndex::Regular`].
+    pub fn is_publicly_joinable(&self) -> bool {
+        let policy = &self.0.policy;
+        if !policy.discoverable || policy.membership_style != MembershipStyle::Ordinary {
+            return false;
+        }
+
+        policy.reachable_by_self_changes(RoleIndex::Outsider, RoleIndex::Regular)
+    }
+
+    /// Whether members must send read receipts.
+    pub fn read_receipts_required(&self) -> bool {
+        self.0.policy.read_receipts == Optionality::Required
+    }
+
+    /// Whether members must send delivery notifications.
+    pub fn delivery_notifications_required(&self) -> bool {
+        self.0.policy.delivery_notifications == Optionality::Required
+    }
+
+    /// Check whether the sender may send a read receipt. Fails if read receipts are forbidden in this room.
+    pub fn authorize_read_receipt(&self, sender: &[u8]) -> Result<()> {
+        self.check_member(sender)?;
+        match self.0.policy.read_receipts {
+            Optionality::Forbidden => Err(Error::NotCapable),
+            Optionality::Optional | Optionality::Required => Ok(()),
+        }
+    }
+
+    /// Check whether the sender may upload an attachment of the given MIME type and size. Images and videos require [`Capability::UploadImage`] and [`Capability::UploadVideo`], other types [`Capability::UploadAttachment`].
+    pub fn authorize_upload(&self, sender: &[u8], mime: &str, bytes: u64) -> Result<()> {
+        let mime_lowercase = mime.to_ascii_lowercase();
+        let capability = if mime_lowercase.starts_with("image/") {
+            Capability::UploadImage
+        } else if mime_lowercase.starts_with("video/") {
+            Capability::UploadVideo
+        } else {
+            Capability::UploadAttachment
+        };
+        if self.membership_status(sender) == MembershipStatus::Banned {
+            return Err(Error::Banned);
+        }
+        if !self.has_capability(sender, capability) {
+            return Err(Error::NotCapable);
+        }
+        if !self.0.policy.media_policy.allows(mime, bytes) {
+            return Err(Error::MediaPolicyViolated);
+        }
+        Ok(())
+    }
+
+    /// Check whether the sender may send a delivery notification. Fails if delivery notifications are forbidden in this room.
+    pub fn authorize_delivery_notification(&self, sender: &[u8]) -> Result<()> {
+        self.check_member(sender)?;
+        match self.0.policy.delivery_notifications {
+            Optionality::Forbidden => Err(Error::NotCapable),
+            Optionality::Optional | Optionality::Required => Ok(()),
+        }
+    }
+
+    /// The active calls and their participants.
+    pub fn calls(&self) -> &BTreeMap<CallId, BTreeSet<Vec<u8>>> {
+        &self.0.calls
+    }
+
+    /// Start a new call and join it. Requires [`Capability::StartCall`].
+    pub fn start_call(&mut self, sender: &[u8]) -> Result<CallId> {
+        if !self.has_capability(sender, Capability::StartCall) {
+            return Err(Error::NotCapable);
+        }
+        if !self.0.policy.allow_concurrent_calls && !self.0.calls.is_empty() {
+            return Err(Error::CallInProgress);
+        }
+
+        let call = self.0.next_call_id;
+        self.0.next_call_id = CallId(call.0.wrapping_add(1));
+        self.0.calls.insert(call, BTreeSet::from([sender.to_vec()]));
+
+        Ok(call)
+    }
+
+    /// Join an active call. Requires [`Capability::JoinCall`].
+    pub fn join_call(&mut self, user: &[u8], call: CallId) -> Result<()> {
+        if !self.has_capability(user, Capability::JoinCall) {
+            return Err(Error::NotCapable);
+        }
+        let Some(participants) = self.0.calls.get_mut(&call) else {
+            return Err(Error::CallNotFound);
+        };
+        if !participants.insert(user.to_vec()) {
+            return Err(Error::NothingToDo);
+        }
+
+        Ok(())
+    }
+
+    /// Leave an active call. The call ends when the last participant leaves.
+    pub fn leave_call(&mut self, user: &[u8], call: CallId) -> Result<()> {
+        let Some(participants) = self.0.calls.get_mut(&call) else {
+            return Err(Error::CallNotFound);
+        };
+        if !participants.remove(user) {
+            return Err(Error::NothingToDo);
+        }
+        if participants.is_empty() {
+            self.0.calls.remove(&call);
+        }
+
+        Ok(())
+    }
+
+    /// Change the room name. Requires [`Capability::ChangeRoomName`].
+    pub fn set_room_name(&mut self, sender: &[u8], name: &str) -> Result<()> {
+        let capable = self.has_capability(sender, Capability::ChangeRoomName);
+        RoomState::try_set_string(capable, &mut self.0.room_name, name, MAX_ROOM_NAME_LENGTH)
+    }
+
+    /// Change the room description. Requires [`Capability::ChangeRoomDescription`].
+    pub fn set_room_description(&mut self, sender: &[u8], description: &str) -> Result<()> {
+        let capable = self.has_capability(sender, Capability::ChangeRoomDescription);
+        RoomState::try_set_string(
+            capable,
+            &mut self.0.room_description,
+            description,
+            MAX_ROOM_DESCRIPTION_LENGTH,
+        )
+    }
+
+    /// Change the room avatar. Requires [`Capability::ChangeRoomAvatar`].
+    pub fn set_room_avatar(&mut self, sender: &[u8], avatar: &str) -> Result<()> {
+        let capable = self.has_capability(sender, Capability::ChangeRoomAvatar);
+        RoomState::try_set_string(
+            capable,
+            &mut self.0.room_avatar,
+            avatar,
+            MAX_ROOM_AVATAR_LENGTH,
+        )
+    }
+
+    /// The most privileged role of the user, or [`RoleIndex::Outsider`] if the user is not in the room.
+    pub fn user_role(&self, user_id: &[u8]) -> RoleIndex {
+        self.0.user_role(user_id)
+    }
+
+    /// The most permissive send rate limit of the roles of the user. `None` if one of the roles is not limited or the user has no defined role.
+    pub fn send_rate_limit(&self, user_id: &[u8]) -> Option<RateLimit> {
+        let mut most_permissive: Option<RateLimit> = None;
+        for role in self.0.user_roles(user_id) {
+            let Some(role_info) = self.0.policy.roles.get(&role) else {
+                continue;
+            };
+            let limit = role_info.send_rate_limit?;
+            if most_permissive.is_none_or(|current| limit.allows_more_than(&current)) {
+                most_permissive = Some(limit);
+            }
+        }
+        most_permissive
+    }
+
+    /// The number of users holding each role, as counted for the participant constraints. Roles without counted holders are omitted.
+    pub fn role_member_counts(&self) -> BTreeMap<RoleIndex, u32> {
+        self.0.role_member_counts()
+    }
+
+    /// Whether the user is a member, is banned or is not in the room. Banned users are listed in the room, but are not members.
+    pub fn membership_status(&self, user_id: &[u8]) -> MembershipStatus {
+        match self.0.users.get(user_id) {
+            None => MembershipStatus::Outsider,
+            Some(roles) if roles.contains(&RoleIndex::Banned) => MembershipStatus::Banned,
+            Some(_) => MembershipStatus::Member(self.0.user_role(user_id)),
+        }
+    }
+
+    /// Fail with [`Error::UserNotInRoom`] or [`Error::Banned`] if the user is not a member.
+    fn check_member(&self, user_id: &[u8]) -> Result<()> {
+        match self.membership_status(user_id) {
+            MembershipStatus::Member(_) => Ok(()),
+            MembershipStatus::Banned => Err(Error::Banned),
+            MembershipStatus::Outsider => Err(Error::UserNotInRoom),
+        }
+    }
+
+    /// The capabilities of the user, taking all roles and mutes into account. Checking several capabilities against the set is cheaper than calling [`VerifiedRoomState::has_capability`] for each of them.
+    pub fn capability_set(&self, user_id: &[u8]) -> CapabilitySet {
+        self.0.user_capabilities(user_id)
+    }
+
+    /// All roles held by the user.
+    pub fn user_roles(&self, user_id: &[u8]) -> BTreeSet<RoleIndex> {
+        self.0.user_roles(user_id)
+    }
+
+    /// Move all holders of the role `from` to the role `into` and remove `from` from the policy. Requires [`Capability::ChangeRoleDefinitions`].
+    ///
+    /// Transitions and other references to `from` refer to `into` afterwards. Special roles cannot be merged. Like [`VerifiedRoomState::apply_policy_proposals`], this fails with [`Error::ApprovalRequired`] if policy changes need the approval of several users.
+    pub fn merge_roles(&mut self, sender: &[u8], from: RoleIndex, into: RoleIndex) -> Result<()> {
+        if !self.has_capability(sender, Capability::ChangeRoleDefinitions) {
+            return Err(Error::NotCapable);
+        }
+        if self.0.policy.required_approvals > 1 {
+            return Err(Error::ApprovalRequired);
+        }
+        let special = |role: RoleIndex| matches!(role, RoleIndex::Outsider | RoleIndex::Banned);
+        if special(from) || special(into) {
+            return Err(Error::SpecialRole);
+        }
+        if from == into {
+            return Err(Error::NothingToDo);
+        }
+        if !self.0.policy.roles.contains_key(&into) {
+            return Err(Error::RoleNotDefined);
+        }
+
+        let mut state = self.0.clone();
+        state.policy.merge_role(from, into)?;
+        for roles in state.users.values_mut() {
+            if roles.remove(&from) {
+                roles.insert(into);
+            }
+        }
+        for expiry in state.role_expiries.values_mut() {
+            if expiry.role == from {
+                expiry.role = into;
+            }
+            if expiry.fallback_role == from {
+                expiry.fallback_role = into;
+            }
+        }
+
+        *self = Self::verify(state)?;
+
+        Ok(())
+    }
+
+    /// Destroy the room. Requires [`Capability::DestroyRoom`].
+    pub fn destroy(self, sender: &[u8]) -> Result<DestroyedRoom> {
+        if !self.has_capability(sender, Capability::DestroyRoom) {
+            return Err(Error::NotCapable);
+        }
+
+        Ok(DestroyedRoom {
+            destroyed_by: sender.to_vec(),
+        })
+    }
+
+    /// How far back the user can see the history. This is the shortest retention override of the roles of the user, or the room-wide maximum time period if none of their roles overrides it.
+    pub fn retention_for(&self, user_id: &[u8]) -> Duration {
+        let seconds = self
+            .0
+            .user_roles(user_id)
+            .iter()
+            .filter_map(|role| self.0.policy.roles.get(role)?.retention_override)
+            .min()
+            .unwrap_or(self.0.policy.history_sharing.max_time_period);
+        Duration::from_secs(seconds.into())
+    }
+
+    /// Whether the user can change the room policy at all, i.e. has [`Capability::ChangeRoleDefinitions`].
+    pub fn can_edit_policy(&self, user_id: &[u8]) -> bool {
+        self.has_capability(user_id, Capability::ChangeRoleDefinitions)
+    }
+
+    /// Whether the viewer may see the real identity of the target. In rooms with semi-anonymous ids this requires [`Capability::ResolveIdentity`], except for the viewer's own identity.
+    pub fn can_see_real_identity(&self, viewer: &[u8], target: &[u8]) -> bool {
+        !self.0.policy.semi_anonymous_ids
+            || viewer == target
+            || self.has_capability(viewer, Capability::ResolveIdentity)
+    }
+
+    /// Pairs of members that the pseudonymization scheme maps to the same display id, sorted. Such members would be indistinguishable in rooms with semi-anonymous ids.
+    pub fn anonymity_collisions(
+        &self,
+        display_id: impl Fn(&[u8]) -> Vec<u8>,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut members_by_display_id: BTreeMap<Vec<u8>, Vec<&Vec<u8>>> = BTreeMap::new();
+        for user in self.0.users.keys() {
+            members_by_display_id
+                .entry(display_id(user))
+                .or_default()
+                .push(user);
+        }
+
+        let mut collisions = Vec::new();
+        for members in members_by_display_id.values() {
+            for (index, first) in members.iter().enumerate() {
+                for second in &members[index + 1..] {
+                    collisions.push(((*first).clone(), (*second).clone()));
+                }
+            }
+        }
+        collisions.sort();
+        collisions
+    }
+
+    /// The SHA-256 digest of the TLS encoding of the state. Peers with the same state compute the same digest.
+    pub fn canonical_hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        tls_codec::Serialize::tls_serialize(self, &mut hasher)
+            .expect("hashing does not fail for states within the TLS size limits");
+        hasher.finalize().into()
+    }
+
+    /// Encode the state with its policy stored as a patch against the base policy. This is much smaller than the full encoding if the policy is close to the base. Decode with [`VerifiedRoomState::from_delta_bytes`] and the same base.
+    pub fn to_delta_bytes(&self, base: &RoomPolicy) -> Vec<u8> {
+        let state = self.0.clone();
+        let delta = RoomStateDelta {
+            patch: base.patch(&state.policy),
+            users: state.users,
+            room_name: state.room_name,
+            room_description: state.room_description,
+            room_avatar: state.room_avatar,
+            calls: state.calls,
+            next_call_id: state.next_call_id,
+            role_expiries: state.role_expiries,
+            capability_overrides: state.capability_overrides,
+            audit_log: state.audit_log,
+            pending_policy_proposals: state.pending_policy_proposals,
+            next_proposal_id: state.next_proposal_id,
+        };
+        tls_codec::Serialize::tls_serialize_detached(&delta)
+            .expect("encoding does not fail for states within the TLS size limits")
+    }
+
+    /// Decode a state encoded with [`VerifiedRoomState::to_delta_bytes`] against the same base policy.
+    pub fn from_delta_bytes(bytes: &[u8], base: &RoomPolicy) -> Result<Self> {
+        let delta =
+            RoomStateDelta::tls_deserialize_exact_bytes(bytes).map_err(|_| Error::DecodingError)?;
+
+        let mut policy = base.clone();
+        policy.apply_patch(&delta.patch)?;
+
+        Self::verify(RoomState {
+            policy,
+            users: delta.users,
+            room_name: delta.room_name,
+            room_description: delta.room_description,
+            room_avatar: delta.room_avatar,
+            calls: delta.calls,
+            next_call_id: delta.next_call_id,
+            role_expiries: delta.role_expiries,
+            capability_overrides: delta.capability_overrides,
+            audit_log: delta.audit_log,
+            pending_policy_proposals: delta.pending_policy_proposals,
+            next_proposal_id: delta.next_proposal_id,
+        })
+    }
+
+    /// Check that the user may report abuse. Requires [`Capability::ReportAbuse`].
+    pub fn authorize_report(&self, reporter: &[u8]) -> Result<()> {
+        if !self.has_capability(reporter, Capability::ReportAbuse) {
+            return Err(Error::NotCapable);
+        }
+        Ok(())
+    }
+
+    /// Check that the user may create subgroups of the room. Requires [`Capability::CreateSubgroup`].
+    pub fn authorize_subgroup_creation(&self, creator: &[u8]) -> Result<()> {
+        if !self.has_capability(creator, Capability::CreateSubgroup) {
+            return Err(Error::NotCapable);
+        }
+        Ok(())
+    }
+
+    /// The policy of a new subgroup created by the user. It is a copy of the room policy whose membership depends on the room at `parent_room_uri`, which should be the URI of this room.
+    pub fn subgroup_policy(&self, creator: &[u8], parent_room_uri: &str) -> Result<RoomPolicy> {
+        self.authorize_subgroup_creation(creator)?;
+
+        let mut policy = self.0.policy.clone();
+        policy.membership_style = MembershipStyle::ParentDependent;
+        policy.parent_room_uri = TlsString(parent_room_uri.to_owned());
+        policy.validate()?;
+
+        Ok(policy)
+    }
+
+    /// Whether the user is banned from the room.
+    pub fn is_banned(&self, user_id: &[u8]) -> bool {
+        self.0.user_roles(user_id).contains(&RoleIndex::Banned)
+    }
+
+    /// The most privileged role the user will hold at time `at`, assuming scheduled role changes are applied once they are due.
+    pub fn role_at(&self, user_id: &[u8], at: u64) -> RoleIndex {
+        match self.0.role_expiries.get(user_id) {
+            Some(expiry) if at >= expiry.expires_at => {
+                if expiry.fallback_role == RoleIndex::Outsider {
+                    return RoleIndex::Outsider;
+                }
+                let mut roles = self.0.user_roles(user_id);
+                roles.remove(&expiry.role);
+                roles.insert(expiry.fallback_role);
+                roles
+                    .into_iter()
+                    .max_by_key(|role| role.privilege())
+                    .unwrap_or(RoleIndex::Outsider)
+            }
+            _ => self.0.user_role(user_id),
+        }
+    }
+
+    /// Apply the scheduled role changes that are due at time `now`. Each expired role is replaced by its fallback role, and users without any role left are removed from the room. Returns every user with a due change and whether it was applied, sorted by user id.
+    ///
+    /// Each change is applied on its own, and only if the result still satisfies the policy, including the participant constraints of the roles. Changes that fail stay scheduled and are tried again by later calls.
+    ///
+    /// The result carries the outcome for each user instead of only listing the affected users, so that callers can tell applied changes from those that stay scheduled and see why they failed. `now` is a `u64` because it is compared with the `expires_at` of [`MimiProposal::ChangeRoleUntil`], which is a `u64` on the wire and does not run out in 2106 like a `u32` timestamp.
+    pub fn expire_roles(&mut self, now: u64) -> Vec<(Vec<u8>, Result<()>)> {
+        let due: Vec<_> = self
+            .0
+            .role_expiries
+            .iter()
+            .filter(|(_, expiry)| now >= expiry.expires_at)
+            .map(|(user, expiry)| (user.clone(), expiry.clone()))
+            .collect();
+
+        due.into_iter()
+            .map(|(user, expiry)| {
+                let mut state = self.0.clone();
+                state.role_expiries.remove(&user);
+                if let Some(roles) = state.users.get_mut(&user) {
+                    roles.remove(&expiry.role);
+                    if expiry.fallback_role != RoleIndex::Outsider {
+                        roles.insert(expiry.fallback_role);
+                    }
+                    if roles.is_empty() {
+                        state.users.remove(&user);
+                    }
+                }
+                state.leave_calls_if_incapable(&user);
+
+                let result = match Self::verify(state) {
+                    Ok(room) => {
+                        *self = room;
+                        #[cfg(debug_assertions)]
+                        self.0.assert_invariants();
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                };
+                (user, result)
+            })
+            .collect()
+    }
+
+    /// Remove the user regardless of the role changes of the policy, e.g. because they were removed from the underlying MLS group. The participant constraints still apply, so the sole required owner cannot be removed.
+    ///
+    /// Banned users stay banned and are not removed.
+    pub fn force_remove_user(&mut self, user_id: &[u8]) -> Result<()> {
+        match self.membership_status(user_id) {
+            MembershipStatus::Outsider => return Err(Error::UserNotInRoom),
+            MembershipStatus::Banned => return Err(Error::NothingToDo),
+            MembershipStatus::Member(_) => {}
+        }
+
+        let mut state = self.0.clone();
+        state.users.remove(user_id);
+        state.role_expiries.remove(user_id);
+        state.leave_calls(user_id);
+
+        *self = Self::verify(state)?;
+        #[cfg(debug_assertions)]
+        self.0.assert_invariants();
+
+        Ok(())
+    }
+}
+
+/// The terminal state of a room that was destroyed. It rejects all proposals.
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct DestroyedRoom {
+    #[tls_codec(with = "tls::vec")]
+    destroyed_by: Vec<u8>,
+}
+
+impl DestroyedRoom {
+    /// The user that destroyed the room.
+    pub fn destroyed_by(&self) -> &[u8] {
+        &self.destroyed_by
+    }
+
+    pub fn apply_regular_proposals(
+        &mut self,
+        _sender: &[u8],
+        _proposals: &[MimiProposal],
+    ) -> Result<()> {
+        Err(Error::RoomDestroyed)
+    }
+
+    pub fn apply_policy_proposals(
+        &mut self,
+        _sender: &[u8],
+        _proposals: &[PolicyProposal],
+    ) -> Result<()> {
+        Err(Error::RoomDestroyed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
     use std::io::Cursor;
 
-    use serde::de::DeserializeOwned;
-    use tls_codec::DeserializeBytes;
+    use serde::de::DeserializeOwned;
+    use tls_codec::DeserializeBytes;
+
+    use super::*;
+
+    fn tls_serialize<T: tls_codec::Serialize>(val: &T) -> Vec<u8> {
+        let mut vec = Vec::new();
+        val.tls_serialize(&mut vec).unwrap();
+        vec
+    }
+
+    fn tls_deserialize<T: DeserializeBytes>(val: &[u8]) -> T {
+        T::tls_deserialize_exact_bytes(val).unwrap()
+    }
+
+    fn cbor_serialize<T: Serialize>(val: T) -> Vec<u8> {
+        let mut result = Vec::new();
+        ciborium::ser::into_writer(&val, &mut result).unwrap();
+        result
+    }
+
+    fn cbor_deserialize<T: DeserializeOwned>(input: &[u8]) -> T {
+        ciborium::de::from_reader(Cursor::new(input)).unwrap()
+    }
+
+    #[test]
+    fn invalid_user_ids() {
+        let room = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .room()
+            .clone();
+        assert_eq!(room.check_user_ids(|user| !user.is_empty()), Ok(()));
+        assert_eq!(
+            room.check_user_ids(|user| user.len() == 5),
+            Err(Error::InvalidUserId)
+        );
+
+        // An empty user id is rejected, also when the state is imported directly
+        let mut state = room.unverified().clone();
+        state
+            .users
+            .insert(Vec::new(), BTreeSet::from([RoleIndex::Regular]));
+        assert_eq!(
+            VerifiedRoomState::verify(state.clone()),
+            Err(Error::InvalidUserId)
+        );
+        let bytes = cbor_serialize(&state);
+        assert!(ciborium::de::from_reader::<VerifiedRoomState, _>(Cursor::new(&bytes)).is_err());
+
+        // So is an explicitly listed outsider
+        let mut state = room.unverified().clone();
+        state
+            .users
+            .insert(b"carol".to_vec(), BTreeSet::from([RoleIndex::Outsider]));
+        let bytes = cbor_serialize(&state);
+        assert!(ciborium::de::from_reader::<VerifiedRoomState, _>(Cursor::new(&bytes)).is_err());
+        assert_eq!(
+            cbor_deserialize::<VerifiedRoomState>(&cbor_serialize(&room)),
+            room
+        );
+    }
+
+    #[test]
+    fn classify_proposal() {
+        let scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob");
+        let mut room = scenario.room().clone();
+
+        assert_eq!(
+            room.classify_proposal(b"alice", &change_role("bob", RoleIndex::Regular), None),
+            ProposalOutcome::NoOp
+        );
+        assert_eq!(
+            room.classify_proposal(b"alice", &change_role("bob", RoleIndex::Admin), None),
+            ProposalOutcome::WouldApply
+        );
+        assert_eq!(
+            room.classify_proposal(b"bob", &change_role("alice", RoleIndex::Outsider), None),
+            ProposalOutcome::Denied(Error::NotCapable)
+        );
+        // Proposals that break the participant constraints are denied as well
+        assert_eq!(
+            room.classify_proposal(b"alice", &change_role("alice", RoleIndex::Regular), None),
+            ProposalOutcome::Denied(Error::WouldRemoveLastOwner)
+        );
+
+        // The strict variant rejects batches that do nothing
+        assert_eq!(
+            room.apply_regular_proposals_strict(
+                b"alice",
+                &[
+                    change_role("bob", RoleIndex::Regular),
+                    change_role("alice", RoleIndex::Owner)
+                ]
+            ),
+            Err(Error::NothingToDo)
+        );
+        room.apply_regular_proposals_strict(
+            b"alice",
+            &[
+                change_role("bob", RoleIndex::Regular),
+                change_role("bob", RoleIndex::Admin),
+            ],
+        )
+        .unwrap();
+        assert_eq!(room.user_role(b"bob"), RoleIndex::Admin);
+    }
+
+    #[test]
+    fn allowed_transitions() {
+        let scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .promote("alice", "carol", RoleIndex::Admin);
+        let room = scenario.room();
+
+        // Admins can kick, ban and promote regular users
+        assert_eq!(
+            room.allowed_transitions(b"carol", b"bob", None),
+            vec![RoleIndex::Outsider, RoleIndex::Banned, RoleIndex::Admin]
+        );
+        // Regular users can only leave by themselves
+        assert_eq!(
+            room.allowed_transitions(b"bob", b"bob", None),
+            vec![RoleIndex::Outsider]
+        );
+        assert_eq!(room.allowed_transitions(b"bob", b"carol", None), vec![]);
+        assert_eq!(room.allowed_transitions(b"carol", b"alice", None), vec![]);
+    }
+
+    #[test]
+    fn partial_application() {
+        let mut scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol");
+        let room = scenario.room.as_mut().unwrap();
+
+        let results = room.apply_regular_proposals_partial(
+            b"alice",
+            &[
+                change_role("bob", RoleIndex::Admin),
+                change_role("alice", RoleIndex::Regular),
+                kick("carol"),
+            ],
+        );
+        assert_eq!(
+            results,
+            vec![Ok(()), Err(Error::WouldRemoveLastOwner), Ok(())]
+        );
+        assert_eq!(room.user_role(b"alice"), RoleIndex::Owner);
+        assert_eq!(room.user_role(b"bob"), RoleIndex::Admin);
+        assert_eq!(room.user_role(b"carol"), RoleIndex::Outsider);
+    }
+
+    #[test]
+    fn audit_log() {
+        let mut scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol");
+        assert_eq!(scenario.room().audit_log(), None);
+        let disabled = scenario.room().clone();
+
+        scenario.room.as_mut().unwrap().enable_audit_log();
+        let scenario =
+            scenario
+                .apply("alice", kick("bob"))
+                .promote("alice", "carol", RoleIndex::Admin);
+        let mut room = scenario.room().clone();
+        assert_eq!(
+            room.audit_log().unwrap(),
+            [
+                AuditEntry {
+                    sender: b"alice".to_vec(),
+                    proposal: kick("bob"),
+                    applied: true,
+                },
+                AuditEntry {
+                    sender: b"alice".to_vec(),
+                    proposal: change_role("carol", RoleIndex::Admin),
+                    applied: true,
+                },
+            ]
+        );
+
+        // Rejected proposals do not change the state, including the log
+        let before = room.clone();
+        room.apply_regular_proposals(b"carol", &[kick("alice")])
+            .unwrap_err();
+        assert_eq!(room, before);
+
+        // The log is only encoded if it is enabled
+        assert_eq!(
+            cbor_deserialize::<VerifiedRoomState>(&cbor_serialize(&room)),
+            room
+        );
+        let contains_log = |bytes: Vec<u8>| bytes.windows(9).any(|window| window == b"audit_log");
+        assert!(contains_log(cbor_serialize(&room)));
+        assert!(!contains_log(cbor_serialize(&disabled)));
+        assert_eq!(
+            cbor_deserialize::<VerifiedRoomState>(&cbor_serialize(&disabled)),
+            disabled
+        );
+    }
+
+    #[test]
+    fn untrusted_bytes() {
+        let room = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .room()
+            .clone();
+
+        let tls_bytes = tls_codec::Serialize::tls_serialize_detached(&room).unwrap();
+        assert_eq!(
+            VerifiedRoomState::try_from_tls_bytes(&tls_bytes).unwrap(),
+            room
+        );
+        let cbor_bytes = cbor_serialize(&room);
+        assert_eq!(
+            VerifiedRoomState::try_from_cbor_bytes(&cbor_bytes).unwrap(),
+            room
+        );
+
+        for len in 0..tls_bytes.len() {
+            assert!(matches!(
+                VerifiedRoomState::try_from_tls_bytes(&tls_bytes[..len]),
+                Err(Error::Decode(_))
+            ));
+        }
+        for len in 0..cbor_bytes.len() {
+            assert!(matches!(
+                VerifiedRoomState::try_from_cbor_bytes(&cbor_bytes[..len]),
+                Err(Error::Decode(_))
+            ));
+        }
+
+        let garbage: &[&[u8]] = &[
+            b"not a room state",
+            &[0x01, 0x02, 0x03],
+            &[0xff; 16],
+            &[0xc0; 16],
+        ];
+        for bytes in garbage {
+            assert!(VerifiedRoomState::try_from_cbor_bytes(bytes).is_err());
+            assert!(matches!(
+                VerifiedRoomState::try_from_tls_bytes(bytes),
+                Err(Error::Decode(_))
+            ));
+        }
+
+        // Malformed length prefixes anywhere in the encoding are rejected without panicking
+        let mut state = room.unverified().clone();
+        state
+            .policy
+            .preauthorized
+            .insert(b"carol".to_vec(), RoleIndex::Regular);
+        state
+            .policy
+            .policy_extensions
+            .push(PolicyExtension::utf8("topic", "rust").unwrap());
+        state
+            .calls
+            .insert(CallId(1), BTreeSet::from([b"bob".to_vec()]));
+        state.role_expiries.insert(
+            b"bob".to_vec(),
+            RoleExpiry {
+                role: RoleIndex::Regular,
+                expires_at: 10,
+                fallback_role: RoleIndex::Outsider,
+            },
+        );
+        state.capability_overrides.insert(
+            b"bob".to_vec(),
+            CapabilityOverride {
+                removed: CapabilitySet::from_iter([Capability::SendMessage]),
+            },
+        );
+        state.audit_log = Some(vec![AuditEntry {
+            sender: b"alice".to_vec(),
+            proposal: kick("bob"),
+            applied: true,
+        }]);
+        state.pending_policy_proposals.insert(
+            ProposalId(0),
+            PendingPolicyProposal {
+                proposal: PolicyProposal::RemovePreauthorized {
+                    user: b"carol".to_vec(),
+                },
+                approvers: BTreeSet::from([b"alice".to_vec()]),
+            },
+        );
+        let bytes = tls_codec::Serialize::tls_serialize_detached(&state).unwrap();
+        assert_eq!(
+            RoomState::tls_deserialize_exact_bytes(&bytes).unwrap(),
+            state
+        );
+        for position in 0..bytes.len() {
+            for byte in [0xc0, 0xff] {
+                let mut mutated = bytes.clone();
+                mutated[position] = byte;
+                let _ = VerifiedRoomState::try_from_tls_bytes(&mutated);
+            }
+        }
+
+        // Well-formed states that violate the policy are rejected
+        let mut state = room.unverified().clone();
+        state.users.clear();
+        let bytes = tls_codec::Serialize::tls_serialize_detached(&state).unwrap();
+        assert!(matches!(
+            VerifiedRoomState::try_from_tls_bytes(&bytes),
+            Err(Error::RoleMinMaxViolated { .. })
+        ));
+    }
+
+    #[test]
+    fn dm_room() {
+        let alice = b"alice";
+
+        // Alice creates an invite-only room
+        let room = VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_dm()).unwrap();
+
+        let room2 = tls_deserialize(&tls_serialize(&room));
+        assert_eq!(room, room2);
+        let room3 = cbor_deserialize(&cbor_serialize(&room));
+        assert_eq!(room, room3);
+    }
+
+    #[test]
+    fn trusted_private_room() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        // Alice creates an invite-only room
+        let mut room =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_trusted_private()).unwrap();
+
+        // Bob cannot join
+        assert_eq!(
+            room.apply_regular_proposals(
+                bob,
+                &[MimiProposal::ChangeRole {
+                    target: bob.to_vec(),
+                    role: RoleIndex::Regular,
+                }],
+            ),
+            Err(Error::NotCapable)
+        );
+
+        // Bob cannot send messages
+        assert!(!room.has_capability(bob, Capability::SendMessage));
+
+        // Alice can add Bob
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }],
+        )
+        .unwrap();
+
+        // Bob can now send messages
+        assert!(room.has_capability(bob, Capability::SendMessage));
+
+        // Bob can kick Alice
+        room.apply_regular_proposals(
+            bob,
+            &[MimiProposal::ChangeRole {
+                target: alice.to_vec(),
+                role: RoleIndex::Outsider,
+            }],
+        )
+        .unwrap();
+
+        // Alice cannot send messages
+        assert!(!room.has_capability(alice, Capability::SendMessage));
+
+        let room2 = tls_deserialize(&tls_serialize(&room));
+        assert_eq!(room, room2);
+        let room3 = cbor_deserialize(&cbor_serialize(&room));
+        assert_eq!(room, room3);
+    }
+
+    #[test]
+    fn invite_only_room() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        // Alice creates an invite-only room
+        let mut room =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_private()).unwrap();
+
+        // Bob cannot join
+        assert_eq!(
+            room.apply_regular_proposals(
+                bob,
+                &[MimiProposal::ChangeRole {
+                    target: bob.to_vec(),
+                    role: RoleIndex::Regular,
+                }],
+            ),
+            Err(Error::NotCapable)
+        );
+
+        // Bob cannot send messages
+        assert!(!room.has_capability(bob, Capability::SendMessage));
+
+        // Alice can add Bob
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }],
+        )
+        .unwrap();
+
+        // Bob can now send messages
+        assert!(room.has_capability(bob, Capability::SendMessage));
+
+        // Bob cannot kick Alice
+        assert_eq!(
+            room.apply_regular_proposals(
+                bob,
+                &[MimiProposal::ChangeRole {
+                    target: alice.to_vec(),
+                    role: RoleIndex::Outsider,
+                }],
+            ),
+            Err(Error::NotCapable)
+        );
+
+        // Alice can kick bob
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Outsider,
+            }],
+        )
+        .unwrap();
+
+        // Bob cannot send messages
+        assert!(!room.has_capability(bob, Capability::SendMessage));
+
+        let room2 = tls_deserialize(&tls_serialize(&room));
+        assert_eq!(room, room2);
+        let room3 = cbor_deserialize(&cbor_serialize(&room));
+        assert_eq!(room, room3);
+    }
+
+    /// Drives a room through a sequence of proposals and checks the outcome of every step.
+    struct Scenario {
+        policy: RoomPolicy,
+        room: Option<VerifiedRoomState>,
+    }
+
+    impl Scenario {
+        fn new(policy: RoomPolicy) -> Self {
+            Self { policy, room: None }
+        }
+
+        #[track_caller]
+        fn create_owner(mut self, owner: &str) -> Self {
+            let room = VerifiedRoomState::new(owner.as_bytes().to_vec(), self.policy.clone());
+            self.room = Some(room.unwrap());
+            self
+        }
+
+        #[track_caller]
+        fn room(&self) -> &VerifiedRoomState {
+            self.room.as_ref().expect("no owner was created")
+        }
+
+        #[track_caller]
+        fn apply(mut self, sender: &str, proposal: MimiProposal) -> Self {
+            let room = self.room.as_mut().expect("no owner was created");
+            room.apply_regular_proposals(sender.as_bytes(), &[proposal])
+                .unwrap();
+            self
+        }
+
+        #[track_caller]
+        fn join(self, user: &str) -> Self {
+            self.apply(user, change_role(user, RoleIndex::Regular))
+        }
+
+        #[track_caller]
+        fn invite(self, sender: &str, target: &str) -> Self {
+            self.apply(sender, change_role(target, RoleIndex::Regular))
+        }
+
+        #[track_caller]
+        fn promote(self, sender: &str, target: &str, role: RoleIndex) -> Self {
+            self.apply(sender, change_role(target, role))
+        }
+
+        #[track_caller]
+        fn expect_err(self, sender: &str, proposal: MimiProposal, error: Error) -> Self {
+            assert_eq!(
+                self.room()
+                    .can_apply_regular_proposals(sender.as_bytes(), &[proposal]),
+                Err(error)
+            );
+            self
+        }
+
+        #[track_caller]
+        fn expect_role(self, user: &str, role: RoleIndex) -> Self {
+            assert_eq!(self.room().user_role(user.as_bytes()), role);
+            self
+        }
+    }
+
+    fn change_role(target: &str, role: RoleIndex) -> MimiProposal {
+        MimiProposal::ChangeRole {
+            target: target.as_bytes().to_vec(),
+            role,
+        }
+    }
+
+    fn kick(target: &str) -> MimiProposal {
+        change_role(target, RoleIndex::Outsider)
+    }
+
+    fn ban(target: &str) -> MimiProposal {
+        change_role(target, RoleIndex::Banned)
+    }
+
+    #[test]
+    fn public_room() {
+        // Alice creates a public room, bob can join, alice can kick bob and bob can rejoin
+        let scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .apply("alice", kick("bob"))
+            .expect_role("bob", RoleIndex::Outsider)
+            .join("bob")
+            // Alice can ban bob and bob cannot rejoin
+            .apply("alice", ban("bob"))
+            .expect_err(
+                "bob",
+                change_role("bob", RoleIndex::Regular),
+                Error::NotCapable,
+            )
+            // Alice can invite carol and promote her, but carol cannot kick alice
+            .invite("alice", "carol")
+            .promote("alice", "carol", RoleIndex::Admin)
+            .expect_err("carol", kick("alice"), Error::NotCapable);
+
+        let room = scenario.room();
+        let room2: VerifiedRoomState = tls_deserialize(&tls_serialize(room));
+        assert_eq!(*room, room2);
+        let room3: VerifiedRoomState = cbor_deserialize(&cbor_serialize(room));
+        assert_eq!(*room, room3);
+    }
+
+    fn billing_policy() -> RoomPolicy {
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .authorized_role_changes
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .push(RoleIndex::Custom(5));
+        policy.roles.insert(
+            RoleIndex::Custom(5),
+            RoleInfo::new("Billing")
+                .with_capabilities([Capability::ChangeRoomAvatar])
+                .with_max_participants(1)
+                .with_max_active_participants(1)
+                .with_self_role_changes([RoleIndex::Outsider]),
+        );
+        policy
+    }
+
+    #[test]
+    fn authorized_role_changes_are_keyed_by_target_role() {
+        let moderator = RoleIndex::Custom(7);
+        let muted = RoleIndex::Custom(8);
+        let custom_role = |name| {
+            RoleInfo::new(name)
+                .with_capabilities([Capability::ReceiveMessage])
+                .with_self_role_changes([RoleIndex::Outsider])
+        };
+
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .authorized_role_changes
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .push(moderator);
+        policy.roles.insert(
+            moderator,
+            custom_role("Moderator")
+                .with_role_changes(RoleIndex::Regular, [muted])
+                .with_role_changes(muted, [RoleIndex::Regular]),
+        );
+        policy.roles.insert(muted, custom_role("Muted"));
+
+        Scenario::new(policy.clone())
+            .create_owner("alice")
+            .invite("alice", "bob")
+            .promote("alice", "bob", moderator)
+            .invite("alice", "carol")
+            .invite("alice", "dave")
+            .promote("alice", "dave", moderator)
+            // Carol is regular, so Bob may mute and unmute Carol
+            .promote("bob", "carol", muted)
+            .expect_role("carol", muted)
+            .promote("bob", "carol", RoleIndex::Regular)
+            .expect_role("carol", RoleIndex::Regular)
+            // Bob also holds the moderator role, but it is not a key of the moderator changes
+            .expect_err("bob", change_role("dave", muted), Error::NotCapable)
+            .expect_err(
+                "bob",
+                change_role("dave", RoleIndex::Regular),
+                Error::NotCapable,
+            );
+
+        // A key cannot list itself
+        policy
+            .roles
+            .get_mut(&moderator)
+            .unwrap()
+            .authorized_role_changes
+            .get_mut(&muted)
+            .unwrap()
+            .push(muted);
+        assert_eq!(
+            policy.validate(),
+            Err(Error::InvalidRoleTransition {
+                source_role: muted,
+                target_role: muted,
+            })
+        );
+    }
+
+    #[test]
+    fn mute_user() {
+        let mute = |target: &str, capabilities: &[Capability]| MimiProposal::MuteUser {
+            target: target.as_bytes().to_vec(),
+            capabilities: capabilities.to_vec(),
+        };
+
+        let mut scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .promote("alice", "carol", RoleIndex::Admin)
+            .expect_err(
+                "bob",
+                mute("carol", &[Capability::SendMessage]),
+                Error::NotCapable,
+            )
+            .expect_err(
+                "carol",
+                mute("alice", &[Capability::SendMessage]),
+                Error::NotCapable,
+            )
+            .expect_err(
+                "carol",
+                mute("dave", &[Capability::SendMessage]),
+                Error::UserNotInRoom,
+            )
+            .apply("carol", mute("bob", &[Capability::SendMessage]));
+
+        // Bob can still read, but no longer write
+        let room = scenario.room();
+        assert!(!room.has_capability(b"bob", Capability::SendMessage));
+        assert!(room.has_capability(b"bob", Capability::ReceiveMessage));
+        assert!(room.has_capability(b"carol", Capability::SendMessage));
+        assert!(mute("bob", &[Capability::SendMessage]).is_reversible(room, None));
+
+        // The mute survives leaving and joining again
+        scenario = scenario.apply("bob", kick("bob")).join("bob");
+        assert!(!scenario
+            .room()
+            .has_capability(b"bob", Capability::SendMessage));
+
+        scenario = scenario.apply("carol", mute("bob", &[]));
+        assert!(scenario
+            .room()
+            .has_capability(b"bob", Capability::SendMessage));
+
+        // Mutes can be lifted while the user is away
+        scenario = scenario
+            .apply("carol", mute("bob", &[Capability::SendMessage]))
+            .apply("bob", kick("bob"))
+            .apply("carol", mute("bob", &[]))
+            .join("bob");
+        assert!(scenario
+            .room()
+            .has_capability(b"bob", Capability::SendMessage));
+
+        // Only lifted mutes of users that left are dropped
+        let mut state = scenario.room().unverified().clone();
+        state.capability_overrides.insert(
+            b"dave".to_vec(),
+            CapabilityOverride {
+                removed: CapabilitySet::from([Capability::SendMessage]),
+            },
+        );
+        assert_eq!(state.diagnose(), []);
+        state.capability_overrides.insert(
+            b"erin".to_vec(),
+            CapabilityOverride {
+                removed: CapabilitySet::default(),
+            },
+        );
+        assert_eq!(
+            state.diagnose(),
+            [Diagnostic::new(Error::UserNotInRoom).user(b"erin")]
+        );
+    }
+
+    #[test]
+    fn protected_roles() {
+        let mut policy = RoomPolicy::default_public();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .max_participants_constraint = None;
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .authorized_role_changes
+            .insert(RoleIndex::Owner, vec![RoleIndex::Regular]);
+        let members = [
+            (&b"alice"[..], BTreeSet::from([RoleIndex::Owner])),
+            (&b"bob"[..], BTreeSet::from([RoleIndex::Owner])),
+            (&b"carol"[..], BTreeSet::from([RoleIndex::Admin])),
+            (&b"dave"[..], BTreeSet::from([RoleIndex::Regular])),
+        ];
+        let demote_bob = change_role("bob", RoleIndex::Regular);
+
+        let room = VerifiedRoomState::restore(policy.clone(), members.clone()).unwrap();
+        assert_eq!(
+            room.can_apply_regular_proposals(b"carol", std::slice::from_ref(&demote_bob)),
+            Ok(())
+        );
+
+        // The protection wins over the allowed role change
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .protected_roles
+            .push(RoleIndex::Owner);
+        let room = VerifiedRoomState::restore(policy.clone(), members).unwrap();
+        assert_eq!(
+            room.can_apply_regular_proposals(b"carol", &[demote_bob]),
+            Err(Error::NotCapable)
+        );
+        assert_eq!(
+            room.can_apply_regular_proposals(b"carol", &[kick("dave")]),
+            Ok(())
+        );
+
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .protected_roles
+            .push(RoleIndex::Custom(9));
+        assert_eq!(policy.validate(), Err(Error::RoleNotDefined));
+    }
+
+    #[test]
+    fn change_own_role() {
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .self_role_changes
+            .push(RoleIndex::Admin);
+
+        Scenario::new(policy.clone())
+            .create_owner("alice")
+            .invite("alice", "bob")
+            .expect_err(
+                "bob",
+                change_role("bob", RoleIndex::Admin),
+                Error::NotCapable,
+            )
+            // Leaving never needs the capability
+            .apply("bob", kick("bob"))
+            .expect_role("bob", RoleIndex::Outsider);
+
+        policy
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::ChangeOwnRole);
+        Scenario::new(policy)
+            .create_owner("alice")
+            .invite("alice", "bob")
+            .promote("bob", "bob", RoleIndex::Admin)
+            .expect_role("bob", RoleIndex::Admin)
+            // Admins can step down
+            .promote("bob", "bob", RoleIndex::Regular)
+            .expect_role("bob", RoleIndex::Regular);
+    }
+
+    #[test]
+    fn participant_capabilities() {
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .role_capabilities
+            .remove(&Capability::AddParticipant);
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .role_capabilities
+            .remove(&Capability::RemoveParticipant);
+
+        Scenario::new(policy)
+            .create_owner("alice")
+            .invite("alice", "bob")
+            .invite("alice", "carol")
+            .promote("alice", "carol", RoleIndex::Admin)
+            // Regular users may no longer invite, even though the role change is authorized
+            .expect_err(
+                "bob",
+                change_role("dave", RoleIndex::Regular),
+                Error::NotCapable,
+            )
+            // Admins can still invite and promote, but not kick
+            .invite("carol", "dave")
+            .promote("carol", "dave", RoleIndex::Admin)
+            .expect_err("carol", kick("bob"), Error::NotCapable)
+            .apply("alice", kick("bob"))
+            .expect_role("bob", RoleIndex::Outsider);
+    }
+
+    #[test]
+    fn multi_role_capabilities() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let mut users = BTreeMap::new();
+        users.insert(
+            alice.to_vec(),
+            BTreeSet::from([RoleIndex::Owner, RoleIndex::Custom(5)]),
+        );
+        users.insert(bob.to_vec(), BTreeSet::from([RoleIndex::Regular]));
+        let mut room = VerifiedRoomState::verify(RoomState::new(billing_policy(), users)).unwrap();
+
+        // Alice has the capabilities of both roles
+        assert_eq!(room.user_role(alice), RoleIndex::Owner);
+        assert!(room.has_capability(alice, Capability::SendMessage));
+        assert!(room.has_capability(alice, Capability::ChangeRoomAvatar));
+        assert!(!room.has_capability(bob, Capability::ChangeRoomAvatar));
+
+        // Alice can promote Bob to Admin, which replaces his primary role
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Admin,
+            }],
+        )
+        .unwrap();
+        assert_eq!(room.user_roles(bob), BTreeSet::from([RoleIndex::Admin]));
+
+        // Alice can step down to Admin and keeps the Billing role
+        let mut users = room.users().clone();
+        users.insert(bob.to_vec(), BTreeSet::from([RoleIndex::Owner]));
+        users.insert(
+            alice.to_vec(),
+            BTreeSet::from([RoleIndex::Admin, RoleIndex::Custom(5)]),
+        );
+        let room = VerifiedRoomState::verify(RoomState::new(billing_policy(), users)).unwrap();
+        assert!(room.has_capability(alice, Capability::ChangeRoomAvatar));
+
+        let room2 = tls_deserialize(&tls_serialize(&room));
+        assert_eq!(room, room2);
+        let room3 = cbor_deserialize(&cbor_serialize(&room));
+        assert_eq!(room, room3);
+    }
+
+    #[test]
+    fn multi_role_counts_and_invariants() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        // The Billing role can only be held by one user
+        let mut users = BTreeMap::new();
+        users.insert(
+            alice.to_vec(),
+            BTreeSet::from([RoleIndex::Owner, RoleIndex::Custom(5)]),
+        );
+        users.insert(
+            bob.to_vec(),
+            BTreeSet::from([RoleIndex::Regular, RoleIndex::Custom(5)]),
+        );
+        assert_eq!(
+            VerifiedRoomState::verify(RoomState::new(billing_policy(), users.clone())),
+            Err(Error::RoleMinMaxViolated {
+                role: RoleIndex::Custom(5),
+                kind: MinMaxKind::Max,
+                limit: 1,
+                actual: 2,
+            })
+        );
+
+        // Users must hold at least one role
+        users.insert(bob.to_vec(), BTreeSet::new());
+        assert_eq!(
+            VerifiedRoomState::verify(RoomState::new(billing_policy(), users.clone())),
+            Err(Error::UserNotInRoom)
+        );
+
+        // Banned users cannot hold other roles
+        users.insert(alice.to_vec(), BTreeSet::from([RoleIndex::Owner]));
+        users.insert(
+            bob.to_vec(),
+            BTreeSet::from([RoleIndex::Banned, RoleIndex::Regular]),
+        );
+        assert_eq!(
+            VerifiedRoomState::verify(RoomState::new(RoomPolicy::default_public(), users)),
+            Err(Error::Banned)
+        );
+    }
+
+    #[test]
+    fn change_room_name() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let mut room =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_private()).unwrap();
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }],
+        )
+        .unwrap();
+
+        // Bob is a regular user and cannot change the room name
+        assert_eq!(
+            room.set_room_name(bob, "Bob's room"),
+            Err(Error::NotCapable)
+        );
+        assert_eq!(
+            room.set_room_description(bob, "A room"),
+            Err(Error::NotCapable)
+        );
+        assert_eq!(
+            room.set_room_avatar(bob, "https://example.com/bob.png"),
+            Err(Error::NotCapable)
+        );
+
+        // Alice is the owner and can
+        room.set_room_name(alice, "Alice's room").unwrap();
+        room.set_room_description(alice, "A room").unwrap();
+        room.set_room_avatar(alice, "https://example.com/alice.png")
+            .unwrap();
+        assert_eq!(room.room_name(), "Alice's room");
+        assert_eq!(room.room_description(), "A room");
+        assert_eq!(room.room_avatar(), "https://example.com/alice.png");
+
+        // But not with names that are too long
+        let long_name = "a".repeat(MAX_ROOM_NAME_LENGTH + 1);
+        assert_eq!(
+            room.set_room_name(alice, &long_name),
+            Err(Error::StringTooLong)
+        );
+        assert_eq!(room.room_name(), "Alice's room");
+
+        let room2 = tls_deserialize(&tls_serialize(&room));
+        assert_eq!(room, room2);
+        let room3 = cbor_deserialize(&cbor_serialize(&room));
+        assert_eq!(room, room3);
+    }
+
+    #[test]
+    fn plan_to_target_membership() {
+        let alice = b"alice";
+        let bob = b"bob";
+        let charlie = b"charlie";
+        let dave = b"dave";
+
+        let mut room =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_private()).unwrap();
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }],
+        )
+        .unwrap();
+
+        let mut target = BTreeMap::new();
+        target.insert(alice.to_vec(), RoleIndex::Owner);
+        target.insert(bob.to_vec(), RoleIndex::Admin);
+        target.insert(charlie.to_vec(), RoleIndex::Regular);
+        target.insert(dave.to_vec(), RoleIndex::Admin);
+
+        // Bob cannot promote himself or invite admins
+        assert_eq!(room.plan_to(bob, &target), Err(Error::NotCapable));
+
+        let proposals = room.plan_to(alice, &target).unwrap();
+        assert_eq!(
+            proposals,
+            vec![
+                MimiProposal::ChangeRole {
+                    target: bob.to_vec(),
+                    role: RoleIndex::Admin,
+                },
+                MimiProposal::ChangeRole {
+                    target: charlie.to_vec(),
+                    role: RoleIndex::Regular,
+                },
+                MimiProposal::ChangeRole {
+                    target: dave.to_vec(),
+                    role: RoleIndex::Admin,
+                },
+            ]
+        );
+
+        room.apply_regular_proposals(alice, &proposals).unwrap();
+        let users: BTreeMap<_, _> = room
+            .users()
+            .keys()
+            .map(|user| (user.clone(), room.user_role(user)))
+            .collect();
+        assert_eq!(users, target);
+        assert_eq!(room.plan_to(alice, &target), Ok(Vec::new()));
+
+        // The room cannot be left without an owner
+        target.remove(alice.as_slice());
+        assert_eq!(
+            room.plan_to(alice, &target),
+            Err(Error::WouldRemoveLastOwner)
+        );
+    }
+
+    #[test]
+    fn history_sharing_requires_receive_message() {
+        let alice = b"alice";
+
+        let mut policy = RoomPolicy::default_public();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .role_capabilities
+            .remove(&Capability::ReceiveMessage);
+        assert_eq!(
+            VerifiedRoomState::new(alice.to_vec(), policy),
+            Err(Error::InvalidRoleDefinition)
+        );
+
+        let mut policy = RoomPolicy::default_public();
+        policy
+            .history_sharing
+            .who_can_share
+            .push(RoleIndex::Custom(7));
+        assert_eq!(
+            VerifiedRoomState::new(alice.to_vec(), policy),
+            Err(Error::RoleNotDefined)
+        );
+    }
+
+    #[test]
+    fn string_length_limits() {
+        let alice = b"alice";
+
+        assert_eq!(
+            TlsString::try_new("abc", 3),
+            Ok(TlsString("abc".to_owned()))
+        );
+        assert_eq!(TlsString::try_new("abcd", 3), Err(Error::StringTooLong));
+
+        let with_role_name = |length| {
+            let mut policy = RoomPolicy::default_private();
+            policy.roles.get_mut(&RoleIndex::Admin).unwrap().role_name =
+                TlsString("a".repeat(length));
+            VerifiedRoomState::new(alice.to_vec(), policy).map(|_| ())
+        };
+        assert_eq!(with_role_name(MAX_ROLE_NAME_LENGTH), Ok(()));
+        assert_eq!(
+            with_role_name(MAX_ROLE_NAME_LENGTH + 1),
+            Err(Error::StringTooLong)
+        );
+
+        let with_description = |length| {
+            let mut policy = RoomPolicy::default_private();
+            policy
+                .roles
+                .get_mut(&RoleIndex::Admin)
+                .unwrap()
+                .role_description = TlsString("a".repeat(length));
+            VerifiedRoomState::new(alice.to_vec(), policy).map(|_| ())
+        };
+        assert_eq!(with_description(MAX_ROLE_DESCRIPTION_LENGTH), Ok(()));
+        assert_eq!(
+            with_description(MAX_ROLE_DESCRIPTION_LENGTH + 1),
+            Err(Error::StringTooLong)
+        );
+
+        let with_parent_room = |length| {
+            let mut policy = RoomPolicy::default_private();
+            policy.parent_room_uri = TlsString("a".repeat(length));
+            VerifiedRoomState::new(alice.to_vec(), policy).map(|_| ())
+        };
+        assert_eq!(with_parent_room(MAX_POLICY_STRING_LENGTH), Ok(()));
+        assert_eq!(
+            with_parent_room(MAX_POLICY_STRING_LENGTH + 1),
+            Err(Error::StringTooLong)
+        );
+    }
+
+    #[test]
+    fn calls() {
+        let alice = b"alice";
+        let bob = b"bob";
+        let charlie = b"charlie";
+
+        let mut policy = RoomPolicy::default_private();
+        for role in [RoleIndex::Regular, RoleIndex::Owner] {
+            let role_info = policy.roles.get_mut(&role).unwrap();
+            role_info.role_capabilities.insert(Capability::JoinCall);
+        }
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::StartCall);
+
+        let mut room = VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }],
+        )
+        .unwrap();
+
+        // Bob cannot start a call
+        assert_eq!(room.start_call(bob), Err(Error::NotCapable));
+
+        // Alice can start a call, but only one at a time
+        let call = room.start_call(alice).unwrap();
+        assert_eq!(room.start_call(alice), Err(Error::CallInProgress));
+
+        // Bob can join the call, Charlie is not in the room and cannot
+        room.join_call(bob, call).unwrap();
+        assert_eq!(room.join_call(charlie, call), Err(Error::NotCapable));
+        assert_eq!(
+            room.calls()[&call],
+            BTreeSet::from([alice.to_vec(), bob.to_vec()])
+        );
+
+        let room2 = tls_deserialize(&tls_serialize(&room));
+        assert_eq!(room, room2);
+        let room3 = cbor_deserialize(&cbor_serialize(&room));
+        assert_eq!(room, room3);
+
+        // Bob is removed from the call when he is kicked
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Outsider,
+            }],
+        )
+        .unwrap();
+        assert_eq!(room.calls()[&call], BTreeSet::from([alice.to_vec()]));
+
+        // Bob is also removed when he moves to a role that cannot take part in calls
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }],
+        )
+        .unwrap();
+        room.join_call(bob, call).unwrap();
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Admin,
+            }],
+        )
+        .unwrap();
+        assert_eq!(room.calls()[&call], BTreeSet::from([alice.to_vec()]));
+
+        // Banned users leave calls as well
+        let mut state = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .room()
+            .unverified()
+            .clone();
+        state
+            .calls
+            .insert(call, BTreeSet::from([alice.to_vec(), bob.to_vec()]));
+        let mut public_room = VerifiedRoomState::verify(state).unwrap();
+        public_room
+            .apply_regular_proposals(alice, &[ban("bob")])
+            .unwrap();
+        assert_eq!(public_room.calls()[&call], BTreeSet::from([alice.to_vec()]));
+
+        // Call participants must be members
+        let mut state = room.unverified().clone();
+        state.calls.get_mut(&call).unwrap().insert(charlie.to_vec());
+        assert_eq!(
+            state.diagnose(),
+            vec![Diagnostic::new(Error::UserNotInRoom).user(charlie)]
+        );
+
+        // The call ends when Alice leaves
+        room.leave_call(alice, call).unwrap();
+        assert!(room.calls().is_empty());
+        assert_eq!(room.join_call(alice, call), Err(Error::CallNotFound));
+        assert_ne!(room.start_call(alice).unwrap(), call);
+    }
+
+    #[test]
+    fn canonical_policy_id() {
+        let policy = RoomPolicy::default_private();
+        assert_eq!(
+            policy.canonical_id(),
+            RoomPolicy::default_private().canonical_id()
+        );
+        assert_eq!(policy.canonical_id().len(), 32);
+        assert_ne!(
+            policy.canonical_id(),
+            RoomPolicy::default_public().canonical_id()
+        );
+
+        // Order and duplicates in set-like lists do not matter
+        let mut reordered = policy.clone();
+        let owner = reordered.roles.get_mut(&RoleIndex::Owner).unwrap();
+        owner.role_capabilities.insert(Capability::SendMessage);
+        owner.self_role_changes.reverse();
+        owner
+            .authorized_role_changes
+            .insert(RoleIndex::Custom(9), Vec::new());
+        assert_ne!(reordered, policy);
+        assert_eq!(reordered.canonical_id(), policy.canonical_id());
+    }
+
+    #[test]
+    fn step_down() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .max_participants_constraint = Some(2);
+        let mut room = VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
+
+        // Alice is the only owner and cannot step down
+        assert_eq!(
+            room.step_down(alice, RoleIndex::Admin),
+            Err(Error::WouldRemoveLastOwner)
+        );
+
+        // Stepping down does not mean leaving or moving up
+        assert_eq!(
+            room.step_down(alice, RoleIndex::Outsider),
+            Err(Error::InvalidRoleTransition {
+                source_role: RoleIndex::Owner,
+                target_role: RoleIndex::Outsider,
+            })
+        );
+        assert_eq!(
+            room.step_down(bob, RoleIndex::Admin),
+            Err(Error::UserNotInRoom)
+        );
+
+        // Once Bob is an owner too, Alice can step down
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Owner,
+            }],
+        )
+        .unwrap();
+        room.step_down(alice, RoleIndex::Admin).unwrap();
+        assert_eq!(room.user_role(alice), RoleIndex::Admin);
+        assert_eq!(room.user_role(bob), RoleIndex::Owner);
+    }
+
+    #[test]
+    fn unreachable_custom_role() {
+        let alice = b"alice";
+
+        // The billing role can be assigned by the owner
+        VerifiedRoomState::new(alice.to_vec(), billing_policy()).unwrap();
+
+        // An island role cannot
+        let mut policy = billing_policy();
+        let mut island_role = policy.roles[&RoleIndex::Custom(5)].clone();
+        island_role.role_name = TlsString("Island".to_owned());
+        policy.roles.insert(RoleIndex::Custom(6), island_role);
+        assert_eq!(
+            VerifiedRoomState::new(alice.to_vec(), policy),
+            Err(Error::UnreachableRole {
+                role: RoleIndex::Custom(6)
+            })
+        );
+    }
+
+    #[test]
+    fn ban_reachability() {
+        let policy = RoomPolicy::default_public();
+        assert!(policy.roles.contains_key(&RoleIndex::Banned));
+        assert!(policy.ban_reachability());
+
+        let policy = RoomPolicy::default_private();
+        assert!(!policy.roles.contains_key(&RoleIndex::Banned));
+
+        // A banned role that nobody can assign
+        let mut policy = RoomPolicy::default_public();
+        for role_info in policy.roles.values_mut() {
+            for targets in role_info.authorized_role_changes.values_mut() {
+                targets.retain(|target| *target != RoleIndex::Banned);
+            }
+        }
+        assert!(policy.roles.contains_key(&RoleIndex::Banned));
+        assert!(!policy.ban_reachability());
+    }
+
+    #[test]
+    fn redundant_capabilities() {
+        let mut policy = RoomPolicy::default_private();
+        assert!(policy.redundant_capabilities(RoleIndex::Admin).is_empty());
+        assert!(policy
+            .redundant_capabilities(RoleIndex::Custom(9))
+            .is_empty());
+
+        // Listing a capability twice has no effect
+        let admin = policy.roles.get_mut(&RoleIndex::Admin).unwrap();
+        admin.role_capabilities.insert(Capability::SendMessage);
+        admin.role_capabilities.insert(Capability::SendMessage);
+        assert!(policy.redundant_capabilities(RoleIndex::Admin).is_empty());
+    }
+
+    #[test]
+    fn role_inheritance() {
+        let moderator = RoleIndex::Custom(7);
+        let senior_moderator = RoleIndex::Custom(8);
+
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .authorized_role_changes
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .extend([moderator, senior_moderator]);
+        policy.roles.insert(
+            moderator,
+            RoleInfo::new("Moderator")
+                .with_capabilities([Capability::DeleteAnyMessage])
+                .with_inherits_from(RoleIndex::Regular)
+                .with_self_role_changes([RoleIndex::Outsider]),
+        );
+        policy.roles.insert(
+            senior_moderator,
+            RoleInfo::new("Senior moderator")
+                .with_capabilities([Capability::MuteUser, Capability::DeleteAnyMessage])
+                .with_inherits_from(moderator)
+                .with_self_role_changes([RoleIndex::Outsider]),
+        );
+        policy.validate().unwrap();
+        assert_eq!(
+            policy.redundant_capabilities(senior_moderator),
+            vec![Capability::DeleteAnyMessage]
+        );
+        assert!(policy
+            .roles_with_capability(Capability::DeleteAnyMessage)
+            .contains(&senior_moderator));
+
+        // Senior moderators have their own capabilities and the ones of both ancestors
+        let room = Scenario::new(policy.clone())
+            .create_owner("alice")
+            .invite("alice", "bob")
+            .promote("alice", "bob", senior_moderator)
+            .room()
+            .clone();
+        assert!(room.has_capability(b"bob", Capability::MuteUser));
+        assert!(room.has_capability(b"bob", Capability::DeleteAnyMessage));
+        assert!(room.has_capability(b"bob", Capability::SendMessage));
+        assert!(!room.has_capability(b"bob", Capability::DestroyRoom));
+
+        // Cycles and undefined parents are rejected
+        let mut cyclic = policy.clone();
+        cyclic.roles.get_mut(&moderator).unwrap().inherits_from = Some(senior_moderator);
+        assert_eq!(
+            cyclic.validate_all(),
+            vec![
+                Error::InheritanceCycle { role: moderator },
+                Error::InheritanceCycle {
+                    role: senior_moderator
+                },
+            ]
+        );
+        policy.roles.get_mut(&moderator).unwrap().inherits_from = Some(RoleIndex::Custom(9));
+        assert_eq!(policy.validate(), Err(Error::RoleNotDefined));
+    }
+
+    #[test]
+    fn merge_policies() {
+        let private = RoomPolicy::default_private();
+        let public = RoomPolicy::default_public();
+
+        let merged = private
+            .merge(&public, MergeStrategy::MostRestrictive)
+            .unwrap();
+        assert_eq!(
+            merged.history_sharing.history_sharing,
+            Optionality::Forbidden
+        );
+        assert!(merged.history_sharing.who_can_share.is_empty());
+        assert!(!merged.discoverable);
+        // The banned role is only defined in the public policy and kept
+        assert!(merged.roles.contains_key(&RoleIndex::Banned));
+        assert_eq!(
+            merged.roles[&RoleIndex::Owner].max_participants_constraint,
+            public.roles[&RoleIndex::Owner].max_participants_constraint
+        );
+        for (role, info) in &merged.roles {
+            for capability in info.role_capabilities.iter() {
+                if let Some(private_role) = private.roles.get(role) {
+                    assert!(private_role.role_capabilities.contains(capability.clone()));
+                }
+                if let Some(public_role) = public.roles.get(role) {
+                    assert!(public_role.role_capabilities.contains(capability));
+                }
+            }
+        }
+
+        let merged = public.merge(&private, MergeStrategy::PreferSelf).unwrap();
+        assert_eq!(merged.history_sharing, public.history_sharing);
+        for (role, info) in &private.roles {
+            for capability in info.role_capabilities.iter() {
+                assert!(merged.roles[role].role_capabilities.contains(capability));
+            }
+        }
+
+        // Merging with itself changes nothing
+        assert_eq!(
+            public.merge(&public, MergeStrategy::MostRestrictive),
+            Ok(public.clone())
+        );
+        assert_eq!(
+            public.merge(&public, MergeStrategy::PreferSelf),
+            Ok(public.clone())
+        );
+
+        // A role is only counted toward the membership if both policies count it
+        let mut uncounted = public.clone();
+        uncounted
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .counts_toward_membership = false;
+        for (first, second) in [(&public, &uncounted), (&uncounted, &public)] {
+            let merged = first.merge(second, MergeStrategy::MostRestrictive).unwrap();
+            assert!(!merged.roles[&RoleIndex::Regular].counts_toward_membership);
+        }
+
+        // Only the MIME types both policies allow are allowed
+        let media = |prefixes: &[&str]| RoomPolicy {
+            media_policy: MediaPolicy::new(None, prefixes).unwrap(),
+            ..public.clone()
+        };
+        let merged = media(&["image/", "text/plain"])
+            .merge(
+                &media(&["Image/png", "text/"]),
+                MergeStrategy::MostRestrictive,
+            )
+            .unwrap();
+        assert!(merged.media_policy.allows("image/png", 0));
+        assert!(merged.media_policy.allows("text/plain", 0));
+        assert!(!merged.media_policy.allows("image/jpeg", 0));
+        assert!(!merged.media_policy.allows("text/html", 0));
+        assert_eq!(
+            media(&["image/"]).merge(&media(&["video/"]), MergeStrategy::MostRestrictive),
+            Err(Error::Irreconcilable)
+        );
+    }
+
+    #[test]
+    fn upload_limits() {
+        let mut unlimited = RoomPolicy::default_private();
+        unlimited
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .role_capabilities
+            .extend([
+                Capability::UploadImage,
+                Capability::UploadVideo,
+                Capability::UploadAttachment,
+            ]);
+        let mut policy = unlimited.clone();
+        policy.media_policy = MediaPolicy::new(Some(1024), &["image/", "text/plain"]).unwrap();
+        let room = Scenario::new(policy)
+            .create_owner("alice")
+            .invite("alice", "bob")
+            .room()
+            .clone();
+        let bob = b"bob".as_slice();
+
+        assert_eq!(room.authorize_upload(bob, "image/png", 1024), Ok(()));
+        assert_eq!(room.authorize_upload(bob, "Text/Plain", 10), Ok(()));
+        assert_eq!(
+            room.authorize_upload(bob, "image/png", 1025),
+            Err(Error::MediaPolicyViolated)
+        );
+        assert_eq!(
+            room.authorize_upload(bob, "application/pdf", 10),
+            Err(Error::MediaPolicyViolated)
+        );
+        assert_eq!(
+            room.authorize_upload(b"carol", "image/png", 10),
+            Err(Error::NotCapable)
+        );
+
+        // Without limits, only the capabilities are checked
+        let room = Scenario::new(unlimited)
+            .create_owner("alice")
+            .invite("alice", "bob")
+            .room()
+            .clone();
+        assert_eq!(
+            room.authorize_upload(bob, "application/pdf", u64::MAX),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn outsider_preview() {
+        let mut policy = RoomPolicy::default_public();
+        let outsider_role = policy.roles.get_mut(&RoleIndex::Outsider).unwrap();
+        outsider_role
+            .role_capabilities
+            .insert(Capability::ReceiveMessage);
+        policy.validate().unwrap();
+
+        let room = Scenario::new(policy.clone())
+            .create_owner("alice")
+            .room()
+            .clone();
+        assert!(room.has_capability(b"stranger", Capability::ReceiveMessage));
+        assert!(!room.has_capability(b"stranger", Capability::SendMessage));
+
+        // Outsiders cannot get capabilities that change the room
+        let mut writing = policy.clone();
+        writing
+            .roles
+            .get_mut(&RoleIndex::Outsider)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::SendMessage);
+        assert_eq!(writing.validate(), Err(Error::SpecialRole));
+
+        // Outsiders still cannot be listed in the room
+        let mut crowded = policy;
+        crowded
+            .roles
+            .get_mut(&RoleIndex::Outsider)
+            .unwrap()
+            .max_participants_constraint = None;
+        assert_eq!(crowded.validate(), Err(Error::SpecialRole));
+    }
+
+    #[test]
+    fn required_capability() {
+        let room = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .apply("alice", ban("carol"))
+            .room()
+            .clone();
+        let alice = b"alice".as_slice();
+
+        let cases = [
+            (kick("bob"), Some(Capability::RemoveParticipant)),
+            (ban("bob"), None),
+            (
+                change_role("dave", RoleIndex::Regular),
+                Some(Capability::AddParticipant),
+            ),
+            (
+                change_role("carol", RoleIndex::Regular),
+                Some(Capability::UnBan),
+            ),
+            (kick("carol"), Some(Capability::UnBan)),
+            (change_role("bob", RoleIndex::Admin), None),
+            (change_role("bob", RoleIndex::Regular), None),
+            (
+                change_role("alice", RoleIndex::Admin),
+                Some(Capability::ChangeOwnRole),
+            ),
+            (kick("alice"), None),
+            (
+                MimiProposal::ChangeRoleUntil {
+                    target: b"bob".to_vec(),
+                    role: RoleIndex::Banned,
+                    expires_at: 100,
+                    fallback_role: RoleIndex::Regular,
+                },
+                Some(Capability::UnBan),
+            ),
+            (
+                MimiProposal::ChangeRoleUntil {
+                    target: b"bob".to_vec(),
+                    role: RoleIndex::Admin,
+                    expires_at: 100,
+                    fallback_role: RoleIndex::Regular,
+                },
+                None,
+            ),
+            (
+                MimiProposal::MuteUser {
+                    target: b"bob".to_vec(),
+                    capabilities: vec![Capability::SendMessage],
+                },
+                Some(Capability::MuteUser),
+            ),
+        ];
+        for (proposal, capability) in cases {
+            assert_eq!(
+                room.required_capability(alice, &proposal),
+                capability,
+                "{proposal:?}"
+            );
+        }
+
+        // Joining by themselves needs no capability
+        assert_eq!(
+            room.required_capability(b"dave", &change_role("dave", RoleIndex::Regular)),
+            None
+        );
+    }
+
+    #[test]
+    fn serde_keys() {
+        let keys = |value: ciborium::Value| -> Vec<String> {
+            value
+                .into_map()
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key.into_text().unwrap())
+                .collect()
+        };
+
+        let role_info = RoleInfo::new("Moderator")
+            .with_capabilities([Capability::DeleteAnyMessage])
+            .with_max_participants(3)
+            .with_role_changes(RoleIndex::Regular, [RoleIndex::Outsider])
+            .with_inherits_from(RoleIndex::Regular);
+        assert_eq!(
+            keys(ciborium::Value::serialized(&role_info).unwrap()),
+            [
+                "name",
+                "description",
+                "caps",
+                "min",
+                "max",
+                "min_active",
+                "max_active",
+                "role_changes",
+                "self_role_changes",
+                "retention",
+                "protected",
+                "inherits",
+                "send_rate_limit",
+                "counts_toward_membership",
+            ]
+        );
+        assert_eq!(
+            cbor_deserialize::<RoleInfo>(&cbor_serialize(&role_info)),
+            role_info
+        );
+
+        let policy = RoomPolicy::default_public();
+        assert_eq!(
+            keys(ciborium::Value::serialized(&policy).unwrap()),
+            [
+                "roles",
+                "membership",
+                "multi_device",
+                "parent_room",
+                "persistent",
+                "delivery_notifications",
+                "read_receipts",
+                "semi_anonymous_ids",
+                "discoverable",
+                "links",
+                "logging",
+                "history",
+                "bots",
+                "extensions",
+                "concurrent_calls",
+                "preauthorized",
+                "media",
+                "required_approvals",
+            ]
+        );
+        assert_eq!(
+            cbor_deserialize::<RoomPolicy>(&cbor_serialize(&policy)),
+            policy
+        );
+    }
+
+    #[test]
+    fn membership_status() {
+        let room = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .apply("alice", ban("carol"))
+            .room()
+            .clone();
+
+        assert_eq!(
+            room.membership_status(b"alice"),
+            MembershipStatus::Member(RoleIndex::Owner)
+        );
+        assert_eq!(
+            room.membership_status(b"bob"),
+            MembershipStatus::Member(RoleIndex::Regular)
+        );
+        assert_eq!(room.membership_status(b"carol"), MembershipStatus::Banned);
+        assert_eq!(room.membership_status(b"dave"), MembershipStatus::Outsider);
+
+        // Banned users are rejected specifically
+        assert_eq!(room.authorize_read_receipt(b"bob"), Ok(()));
+        assert_eq!(room.authorize_read_receipt(b"carol"), Err(Error::Banned));
+        assert_eq!(
+            room.authorize_delivery_notification(b"carol"),
+            Err(Error::Banned)
+        );
+        assert_eq!(
+            room.authorize_read_receipt(b"dave"),
+            Err(Error::UserNotInRoom)
+        );
+        assert_eq!(
+            room.authorize_upload(b"carol", "image/png", 10),
+            Err(Error::Banned)
+        );
+    }
+
+    #[test]
+    fn policy_extensions() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Retention {
+            days: u32,
+            legal_hold: bool,
+        }
+
+        let scenario = Scenario::new(RoomPolicy::default_private())
+            .create_owner("alice")
+            .invite("alice", "bob");
+        let mut room = scenario.room().clone();
+        let retention = Retention {
+            days: 30,
+            legal_hold: true,
+        };
+        let extension = PolicyExtension::cbor("example.com/retention", &retention).unwrap();
+
+        // Regular users cannot change extensions
+        assert_eq!(
+            room.apply_policy_proposals(b"bob", &[PolicyProposal::SetExtension(extension.clone())]),
+            Err(Error::NotCapable)
+        );
+        room.apply_policy_proposals(b"alice", &[PolicyProposal::SetExtension(extension)])
+            .unwrap();
+
+        let stored = room.0.policy.extension("example.com/retention").unwrap();
+        assert_eq!(stored.value_type(), ExtensionType::Cbor);
+        assert_eq!(stored.as_cbor::<Retention>(), Ok(retention));
+        assert_eq!(
+            stored.as_utf8(),
+            Err(Error::Decode(
+                "extension example.com/retention has type Cbor, not Utf8".to_owned()
+            ))
+        );
+        assert!(matches!(stored.as_cbor::<String>(), Err(Error::Decode(_))));
+        assert!(room.0.policy.extension("example.com/other").is_none());
+
+        // Setting an extension with the same name replaces it
+        room.apply_policy_proposals(
+            b"alice",
+            &[PolicyProposal::SetExtension(
+                PolicyExtension::utf8("example.com/retention", "forever").unwrap(),
+            )],
+        )
+        .unwrap();
+        let stored = room.0.policy.extension("example.com/retention").unwrap();
+        assert_eq!(stored.as_utf8(), Ok("forever"));
+        assert_eq!(room.0.policy.policy_extensions.len(), 1);
+
+        let remove = [PolicyProposal::RemoveExtension {
+            name: TlsString("example.com/retention".to_owned()),
+        }];
+        room.apply_policy_proposals(b"alice", &remove).unwrap();
+        assert!(room.0.policy.policy_extensions.is_empty());
+        assert_eq!(
+            room.apply_policy_proposals(b"alice", &remove),
+            Err(Error::NothingToDo)
+        );
+    }
+
+    #[test]
+    fn self_escalation() {
+        // The default policies only allow joining and self-demotion
+        for policy in [
+            RoomPolicy::default_dm(),
+            RoomPolicy::default_private(),
+            RoomPolicy::default_public(),
+        ] {
+            assert_eq!(policy.validate_strict(), Ok(()));
+        }
+
+        let mut policy = RoomPolicy::default_public();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .self_role_changes
+            .push(RoleIndex::Admin);
+        assert_eq!(policy.validate(), Ok(()));
+        assert_eq!(
+            policy.validate_strict(),
+            Err(Error::SelfEscalation {
+                from: RoleIndex::Regular,
+                to: RoleIndex::Admin
+            })
+        );
+    }
+
+    #[test]
+    fn force_remove_user() {
+        let mut room = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .apply("alice", ban("carol"))
+            .room()
+            .clone();
+
+        room.force_remove_user(b"bob").unwrap();
+        assert_eq!(room.membership_status(b"bob"), MembershipStatus::Outsider);
+        assert_eq!(room.force_remove_user(b"bob"), Err(Error::UserNotInRoom));
+
+        // The sole owner is required by the policy
+        let before = room.clone();
+        assert_eq!(
+            room.force_remove_user(b"alice"),
+            Err(Error::RoleMinMaxViolated {
+                role: RoleIndex::Owner,
+                kind: MinMaxKind::Min,
+                limit: 1,
+                actual: 0,
+            })
+        );
+        assert_eq!(room, before);
+
+        // Bans are kept
+        assert_eq!(room.force_remove_user(b"carol"), Err(Error::NothingToDo));
+        assert_eq!(room.membership_status(b"carol"), MembershipStatus::Banned);
+    }
+
+    #[test]
+    fn user_capability_set() {
+        let room = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .apply(
+                "alice",
+                MimiProposal::MuteUser {
+                    target: b"bob".to_vec(),
+                    capabilities: vec![Capability::SendMessage],
+                },
+            )
+            .room()
+            .clone();
+
+        // Each `has_capability` call collects the capabilities of all roles of the user again. Timeline code checking several capabilities per message should compute the set once, after which every check is a single bit test.
+        for user in [b"alice".as_slice(), b"bob", b"carol"] {
+            let set = room.capability_set(user);
+            let expected: Vec<_> = Capability::ALL
+                .iter()
+                .filter(|capability| room.has_capability(user, (*capability).clone()))
+                .cloned()
+                .collect();
+            assert_eq!(set.iter().collect::<Vec<_>>(), expected);
+            assert_eq!(set.len(), expected.len());
+        }
+        assert!(!room
+            .capability_set(b"bob")
+            .contains(Capability::SendMessage));
+        assert!(room
+            .capability_set(b"bob")
+            .contains(Capability::ReceiveMessage));
+        assert!(room.capability_set(b"carol").is_empty());
+    }
+
+    #[test]
+    fn announcement_room() {
+        let policy = RoomPolicy::default_announcement();
+        policy.validate().unwrap();
+
+        let room = Scenario::new(policy)
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .promote("alice", "carol", RoleIndex::Admin)
+            .room()
+            .clone();
+        assert!(room.has_capability(b"bob", Capability::ReceiveMessage));
+        assert!(!room.has_capability(b"bob", Capability::SendMessage));
+        assert!(room.has_capability(b"carol", Capability::SendMessage));
+        assert!(room.has_capability(b"alice", Capability::SendMessage));
+    }
+
+    #[test]
+    fn absent_targets() {
+        let mut policy = RoomPolicy::default_public();
+        // Admins can only ban members
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .authorized_role_changes
+            .get_mut(&RoleIndex::Outsider)
+            .unwrap()
+            .retain(|role| *role != RoleIndex::Banned);
+        let mut scenario = Scenario::new(policy)
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .promote("alice", "carol", RoleIndex::Admin)
+            .apply("bob", kick("bob"));
+
+        scenario = scenario
+            .expect_err("carol", kick("bob"), Error::UserNotInRoom)
+            .expect_err("carol", ban("bob"), Error::UserNotInRoom)
+            .expect_err("alice", kick("dave"), Error::UserNotInRoom);
+        // Regular users cannot ban anyone, present or not
+        scenario = scenario
+            .join("dave")
+            .expect_err("dave", ban("bob"), Error::NotCapable);
+        // The owner can still ban users before they join
+        scenario
+            .apply("alice", ban("bob"))
+            .expect_role("bob", RoleIndex::Banned);
+    }
+
+    #[test]
+    fn policy_approvals() {
+        let mut policy = RoomPolicy::default_private();
+        policy.required_approvals = 2;
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::ChangeRoleDefinitions);
+        let mut room = Scenario::new(policy)
+            .create_owner("alice")
+            .invite("alice", "bob")
+            .promote("alice", "bob", RoleIndex::Admin)
+            .invite("alice", "carol")
+            .room()
+            .clone();
+        let proposal = PolicyProposal::ChangeSetting(RoomSetting::Discoverable(true));
+
+        assert_eq!(
+            room.apply_policy_proposals(b"alice", std::slice::from_ref(&proposal)),
+            Err(Error::ApprovalRequired)
+        );
+        assert_eq!(
+            room.merge_roles(b"alice", RoleIndex::Admin, RoleIndex::Regular),
+            Err(Error::ApprovalRequired)
+        );
+        assert_eq!(
+            room.propose_policy(b"carol", proposal.clone()),
+            Err(Error::NotCapable)
+        );
+
+        // The approval of the proposer is not enough
+        let id = room.propose_policy(b"alice", proposal).unwrap();
+        assert!(!room.0.policy.discoverable);
+        assert_eq!(room.approve_policy(b"alice", id), Err(Error::NothingToDo));
+        assert_eq!(room.approve_policy(b"carol", id), Err(Error::NotCapable));
+        assert!(!room.0.policy.discoverable);
+
+        // A second approval applies the proposal
+        assert_eq!(room.approve_policy(b"bob", id), Ok(true));
+        assert!(room.0.policy.discoverable);
+        assert!(room.0.pending_policy_proposals.is_empty());
+        assert_eq!(
+            room.approve_policy(b"bob", id),
+            Err(Error::ProposalNotFound)
+        );
+
+        // Pending proposals survive encoding
+        let id = room
+            .propose_policy(
+                b"bob",
+                PolicyProposal::ChangeSetting(RoomSetting::Discoverable(false)),
+            )
+            .unwrap();
+        let mut room: VerifiedRoomState = tls_deserialize(&tls_serialize(&room));
+        assert_eq!(room.approve_policy(b"alice", id), Ok(true));
+        assert!(!room.0.policy.discoverable);
+
+        // A proposal that can no longer be applied is dropped
+        let discoverable = PolicyProposal::ChangeSetting(RoomSetting::Discoverable(true));
+        let first = room.propose_policy(b"alice", discoverable.clone()).unwrap();
+        let second = room.propose_policy(b"bob", discoverable.clone()).unwrap();
+        assert_eq!(room.approve_policy(b"bob", first), Ok(true));
+        assert_eq!(
+            room.approve_policy(b"alice", second),
+            Err(Error::NothingToDo)
+        );
+        assert!(room.0.pending_policy_proposals.is_empty());
+        assert_eq!(
+            room.approve_policy(b"alice", second),
+            Err(Error::ProposalNotFound)
+        );
+
+        // Users who could approve a proposal can withdraw it
+        let hidden = PolicyProposal::ChangeSetting(RoomSetting::Discoverable(false));
+        let id = room.propose_policy(b"alice", hidden.clone()).unwrap();
+        assert_eq!(room.withdraw_policy(b"carol", id), Err(Error::NotCapable));
+        assert_eq!(room.withdraw_policy(b"bob", id), Ok(()));
+        assert_eq!(
+            room.approve_policy(b"alice", id),
+            Err(Error::ProposalNotFound)
+        );
+        assert_eq!(
+            room.withdraw_policy(b"bob", id),
+            Err(Error::ProposalNotFound)
+        );
+
+        // The number of pending proposals is bounded
+        for _ in 0..MAX_PENDING_POLICY_PROPOSALS {
+            room.propose_policy(b"alice", hidden.clone()).unwrap();
+        }
+        assert_eq!(
+            room.propose_policy(b"alice", hidden.clone()),
+            Err(Error::TooManyProposals)
+        );
+        let mut overfull = room.unverified().clone();
+        overfull.pending_policy_proposals.insert(
+            ProposalId(u32::MAX),
+            PendingPolicyProposal {
+                proposal: hidden.clone(),
+                approvers: BTreeSet::from([b"alice".to_vec()]),
+            },
+        );
+        assert_eq!(
+            VerifiedRoomState::verify(overfull),
+            Err(Error::TooManyProposals)
+        );
+
+        // Ids are not reused
+        let mut room = Scenario::new(RoomPolicy::default_private())
+            .create_owner("alice")
+            .room()
+            .clone();
+        room.0.next_proposal_id = ProposalId(u32::MAX);
+        let previous = room.clone();
+        assert_eq!(
+            room.propose_policy(b"alice", hidden),
+            Err(Error::TooManyProposals)
+        );
+        assert_eq!(room, previous);
+    }
+
+    #[test]
+    fn capability_delta() {
+        let policy = RoomPolicy::default_public();
+
+        // Promoting a regular user to owner grants the moderation and governance capabilities
+        assert_eq!(
+            policy.capability_delta(RoleIndex::Regular, RoleIndex::Owner),
+            (
+                vec![],
+                vec![
+                    Capability::ChangeRoomName,
+                    Capability::ChangeRoomDescription,
+                    Capability::ChangeRoomAvatar,
+                    Capability::ChangeRoleDefinitions,
+                    Capability::UnBan,
+                    Capability::CreateSubgroup,
+                    Capability::RemoveParticipant,
+                    Capability::MuteUser,
+                    Capability::ChangeOwnRole,
+                ]
+            )
+        );
+        assert_eq!(
+            policy.capability_delta(RoleIndex::Owner, RoleIndex::Admin),
+            (vec![Capability::ChangeRoleDefinitions], vec![])
+        );
+        assert_eq!(
+            policy.capability_delta(RoleIndex::Admin, RoleIndex::Admin),
+            (vec![], vec![])
+        );
+
+        let (regular, banned) = policy.capability_delta(RoleIndex::Regular, RoleIndex::Banned);
+        assert_eq!(
+            regular,
+            policy.roles[&RoleIndex::Regular]
+                .role_capabilities
+                .iter()
+                .collect::<Vec<_>>()
+        );
+        assert!(banned.is_empty());
+    }
+
+    #[test]
+    fn send_rate_limit() {
+        let newcomer = RoleIndex::Custom(5);
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .send_rate_limit = Some(RateLimit {
+            max_messages: 10,
+            per_seconds: 60,
+        });
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .authorized_role_changes
+            .get_mut(&RoleIndex::Outsider)
+            .unwrap()
+            .push(newcomer);
+        policy.roles.insert(
+            newcomer,
+            RoleInfo::new("Newcomer")
+                .with_capabilities([Capability::SendMessage, Capability::ReceiveMessage])
+                .with_self_role_changes([RoleIndex::Outsider])
+                .with_send_rate_limit(1, 60),
+        );
+        policy.validate().unwrap();
+        assert_eq!(
+            tls_deserialize::<RoomPolicy>(&tls_serialize(&policy)),
+            policy
+        );
+        assert_eq!(
+            cbor_deserialize::<RoomPolicy>(&cbor_serialize(&policy)),
+            policy
+        );
+
+        let mut room = Scenario::new(policy.clone())
+            .create_owner("alice")
+            .invite("alice", "bob")
+            .room()
+            .clone();
+        room.apply_regular_proposals(b"alice", &[change_role("carol", newcomer)])
+            .unwrap();
+        assert_eq!(room.send_rate_limit(b"alice"), None);
+        assert_eq!(
+            room.send_rate_limit(b"bob"),
+            Some(RateLimit {
+                max_messages: 10,
+                per_seconds: 60
+            })
+        );
+        assert_eq!(
+            room.send_rate_limit(b"carol"),
+            Some(RateLimit {
+                max_messages: 1,
+                per_seconds: 60
+            })
+        );
+
+        // The window must not be empty
+        policy.roles.get_mut(&newcomer).unwrap().send_rate_limit = Some(RateLimit {
+            max_messages: 1,
+            per_seconds: 0,
+        });
+        assert_eq!(policy.validate(), Err(Error::InvalidRoleDefinition));
+    }
+
+    #[test]
+    fn validate_role_assignment() {
+        let room = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .room()
+            .clone();
+
+        assert_eq!(
+            room.validate_role_assignment(b"alice", b"bob", RoleIndex::Admin, None),
+            Ok(())
+        );
+        assert_eq!(
+            room.validate_role_assignment(b"alice", b"bob", RoleIndex::Regular, None),
+            Ok(())
+        );
+        assert_eq!(
+            room.validate_role_assignment(b"bob", b"carol", RoleIndex::Admin, None),
+            Err(Error::NotCapable)
+        );
+        // The public policy allows a single owner
+        assert_eq!(
+            room.validate_role_assignment(b"alice", b"bob", RoleIndex::Owner, None),
+            Err(Error::RoleMinMaxViolated {
+                role: RoleIndex::Owner,
+                kind: MinMaxKind::Max,
+                limit: 1,
+                actual: 2,
+            })
+        );
+        assert_eq!(
+            room.validate_role_assignment(b"alice", b"bob", RoleIndex::Custom(3), None),
+            Err(Error::RoleNotDefined)
+        );
+        assert_eq!(
+            room.validate_role_assignment(b"alice", b"alice", RoleIndex::Outsider, None),
+            Err(Error::WouldRemoveLastOwner)
+        );
+    }
+
+    #[test]
+    fn observers_are_not_counted() {
+        let observer = RoleIndex::Custom(4);
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .max_participants_constraint = Some(1);
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .authorized_role_changes
+            .get_mut(&RoleIndex::Outsider)
+            .unwrap()
+            .push(observer);
+        policy.roles.insert(
+            observer,
+            RoleInfo::new("Observer")
+                .with_capabilities([Capability::ReceiveMessage])
+                .with_self_role_changes([RoleIndex::Outsider])
+                .with_counts_toward_membership(false),
+        );
+        policy.validate().unwrap();
+
+        let mut users = BTreeMap::new();
+        users.insert(b"alice".to_vec(), BTreeSet::from([RoleIndex::Owner]));
+        users.insert(b"bob".to_vec(), BTreeSet::from([RoleIndex::Regular]));
+        // A compliance account observes the room
+        users.insert(b"compliance".to_vec(), BTreeSet::from([observer]));
+        let room =
+            VerifiedRoomState::verify(RoomState::new(policy.clone(), users.clone())).unwrap();
+        assert_eq!(
+            room.role_member_counts(),
+            BTreeMap::from([(RoleIndex::Regular, 1), (RoleIndex::Owner, 1)])
+        );
+        assert!(room.has_capability(b"compliance", Capability::ReceiveMessage));
+
+        // Observers are still counted for their other roles, so an observing owner is an owner
+        let mut observing_owner = users.clone();
+        observing_owner.insert(
+            b"alice".to_vec(),
+            BTreeSet::from([RoleIndex::Owner, observer]),
+        );
+        let room =
+            VerifiedRoomState::verify(RoomState::new(policy.clone(), observing_owner)).unwrap();
+        assert_eq!(room.role_member_counts()[&RoleIndex::Owner], 1);
+
+        // Without the observer role, the same users exceed the maximum
+        users.insert(b"compliance".to_vec(), BTreeSet::from([RoleIndex::Regular]));
+        assert_eq!(
+            VerifiedRoomState::verify(RoomState::new(policy.clone(), users)),
+            Err(Error::RoleMinMaxViolated {
+                role: RoleIndex::Regular,
+                kind: MinMaxKind::Max,
+                limit: 1,
+                actual: 2,
+            })
+        );
+
+        // Banned users are always counted
+        let mut policy = RoomPolicy::default_public();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Banned)
+            .unwrap()
+            .counts_toward_membership = false;
+        assert_eq!(policy.validate(), Err(Error::SpecialRole));
+    }
+
+    #[test]
+    fn autodetect_format() {
+        let room = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .room()
+            .clone();
+
+        let tls_bytes = tls_serialize(&room);
+        let cbor_bytes = cbor_serialize(&room);
+        assert_eq!(
+            VerifiedRoomState::from_bytes_autodetect(&tls_bytes),
+            Ok(room.clone())
+        );
+        assert_eq!(
+            VerifiedRoomState::from_bytes_autodetect(&cbor_bytes),
+            Ok(room.clone())
+        );
+
+        assert!(matches!(
+            VerifiedRoomState::from_bytes_autodetect(&[]),
+            Err(Error::Decode(_))
+        ));
+        assert!(matches!(
+            VerifiedRoomState::from_bytes_autodetect(&cbor_bytes[..cbor_bytes.len() - 1]),
+            Err(Error::Decode(_))
+        ));
+        for garbage in [&[0xc0; 16], &[0xff; 16], &[0xa0; 16], &[0x00; 16]] {
+            assert!(matches!(
+                VerifiedRoomState::from_bytes_autodetect(garbage),
+                Err(Error::Decode(_))
+            ));
+        }
+
+        // States that decode but are inconsistent are rejected like with the other decoding functions
+        let mut state = room.unverified().clone();
+        state.users.clear();
+        assert!(matches!(
+            VerifiedRoomState::from_bytes_autodetect(&tls_serialize(&state)),
+            Err(Error::RoleMinMaxViolated { .. })
+        ));
+        assert!(matches!(
+            VerifiedRoomState::from_bytes_autodetect(&cbor_serialize(&state)),
+            Err(Error::RoleMinMaxViolated { .. })
+        ));
+    }
+
+    #[test]
+    fn last_owner_cannot_be_removed() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let mut room =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_private()).unwrap();
+
+        // Alice is the only owner
+        assert_eq!(
+            room.apply_regular_proposals(
+                alice,
+                &[MimiProposal::ChangeRole {
+                    target: alice.to_vec(),
+                    role: RoleIndex::Regular,
+                }],
+            ),
+            Err(Error::WouldRemoveLastOwner)
+        );
+        assert_eq!(
+            room.apply_regular_proposals(
+                alice,
+                &[MimiProposal::ChangeRole {
+                    target: alice.to_vec(),
+                    role: RoleIndex::Outsider,
+                }],
+            ),
+            Err(Error::WouldRemoveLastOwner)
+        );
+
+        // With a second owner, Alice can become a regular user
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .max_participants_constraint = Some(2);
+        let mut room = VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
+        room.apply_regular_proposals(
+            alice,
+            &[
+                MimiProposal::ChangeRole {
+                    target: bob.to_vec(),
+                    role: RoleIndex::Owner,
+                },
+                MimiProposal::ChangeRole {
+                    target: alice.to_vec(),
+                    role: RoleIndex::Regular,
+                },
+            ],
+        )
+        .unwrap();
+        assert_eq!(room.user_role(alice), RoleIndex::Regular);
+    }
+
+    #[test]
+    fn assemble_room() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let policy_bytes = tls_serialize(&RoomPolicy::default_private());
+        let mut members = BTreeMap::new();
+        members.insert(alice.to_vec(), RoleIndex::Owner);
+        members.insert(bob.to_vec(), RoleIndex::Admin);
+
+        let room = VerifiedRoomState::assemble(&policy_bytes, &members).unwrap();
+        assert_eq!(room.user_role(alice), RoleIndex::Owner);
+        assert_eq!(room.user_role(bob), RoleIndex::Admin);
+        assert_eq!(room.unverified().policy, RoomPolicy::default_private());
+
+        // Malformed policy
+        assert_eq!(
+            VerifiedRoomState::assemble(&policy_bytes[..policy_bytes.len() - 1], &members),
+            Err(Error::DecodingError)
+        );
+
+        // Valid policy, but invalid members
+        members.insert(bob.to_vec(), RoleIndex::Owner);
+        assert_eq!(
+            VerifiedRoomState::assemble(&policy_bytes, &members),
+            Err(Error::RoleMinMaxViolated {
+                role: RoleIndex::Owner,
+                kind: MinMaxKind::Max,
+                limit: 1,
+                actual: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn governance_connectivity() {
+        assert!(RoomPolicy::default_public()
+            .governance_connectivity()
+            .is_empty());
+
+        // The owner can promote regular users to billing
+        let mut policy = billing_policy();
+        assert!(policy.governance_connectivity().is_empty());
+
+        // Only billing can assign the auditor role
+        let mut auditor = policy.roles[&RoleIndex::Custom(5)].clone();
+        auditor.role_name = TlsString("Auditor".to_owned());
+        policy.roles.insert(RoleIndex::Custom(6), auditor);
+        policy
+            .roles
+            .get_mut(&RoleIndex::Custom(5))
+            .unwrap()
+            .authorized_role_changes
+            .insert(RoleIndex::Regular, vec![RoleIndex::Custom(6)]);
+        assert_eq!(policy.governance_connectivity(), vec![RoleIndex::Custom(6)]);
+    }
+
+    #[test]
+    fn exit_paths() {
+        let mut policy = RoomPolicy::default_private();
+        policy.validate().unwrap();
+
+        // Two custom roles that can only switch between each other
+        for (role, other) in [
+            (RoleIndex::Custom(1), RoleIndex::Custom(2)),
+            (RoleIndex::Custom(2), RoleIndex::Custom(1)),
+        ] {
+            let mut role_info = policy.roles[&RoleIndex::Regular].clone();
+            role_info.self_role_changes = vec![other];
+            policy.roles.insert(role, role_info);
+        }
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .authorized_role_changes
+            .insert(RoleIndex::Regular, vec![RoleIndex::Custom(1)]);
+        assert_eq!(
+            policy.validate(),
+            Err(Error::NoExitPath {
+                role: RoleIndex::Custom(1)
+            })
+        );
+
+        // One role can leave directly, the other through it
+        policy
+            .roles
+            .get_mut(&RoleIndex::Custom(2))
+            .unwrap()
+            .self_role_changes
+            .push(RoleIndex::Outsider);
+        policy.validate().unwrap();
+
+        // Users of rooms with fixed membership cannot leave anyway
+        RoomPolicy::default_dm().validate().unwrap();
+    }
+
+    #[test]
+    fn retention_override() {
+        let mut policy = billing_policy();
+        let billing = policy.roles.get_mut(&RoleIndex::Custom(5)).unwrap();
+        billing.retention_override = Some(60 * 60);
+        billing.max_participants_constraint = None;
+        policy.history_sharing.max_time_period = 60 * 60 * 24;
+
+        let mut users = BTreeMap::new();
+        users.insert(b"alice".to_vec(), BTreeSet::from([RoleIndex::Owner]));
+        users.insert(b"bob".to_vec(), BTreeSet::from([RoleIndex::Custom(5)]));
+        users.insert(
+            b"carol".to_vec(),
+            BTreeSet::from([RoleIndex::Regular, RoleIndex::Custom(5)]),
+        );
+        let room = VerifiedRoomState::verify(RoomState::new(policy, users)).unwrap();
+
+        assert_eq!(room.retention_for(b"bob"), Duration::from_secs(60 * 60));
+        assert_eq!(
+            room.retention_for(b"alice"),
+            Duration::from_secs(60 * 60 * 24)
+        );
+        // Users with several roles get the shortest override of them
+        assert_eq!(room.retention_for(b"carol"), Duration::from_secs(60 * 60));
+    }
+
+    #[test]
+    fn diagnose() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let mut users = BTreeMap::new();
+        users.insert(alice.to_vec(), BTreeSet::from([RoleIndex::Regular]));
+        users.insert(
+            bob.to_vec(),
+            BTreeSet::from([RoleIndex::Banned, RoleIndex::Regular]),
+        );
+        let state = RoomState::new(RoomPolicy::default_public(), users);
+
+        assert_eq!(
+            state.diagnose(),
+            vec![
+                Diagnostic {
+                    error: Error::Banned,
+                    role: Some(RoleIndex::Banned),
+                    user: Some(bob.to_vec()),
+                },
+                Diagnostic {
+                    error: Error::RoleMinMaxViolated {
+                        role: RoleIndex::Owner,
+                        kind: MinMaxKind::Min,
+                        limit: 1,
+                        actual: 0,
+                    },
+                    role: Some(RoleIndex::Owner),
+                    user: None,
+                },
+            ]
+        );
+        assert_eq!(VerifiedRoomState::verify(state), Err(Error::Banned));
+
+        let room = VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_public()).unwrap();
+        assert!(room.unverified().diagnose().is_empty());
+
+        // Every problem of the policy is reported
+        let mut state = room.unverified().clone();
+        state.policy.membership_style = MembershipStyle::ParentDependent;
+        state
+            .policy
+            .roles
+            .get_mut(&RoleIndex::Outsider)
+            .unwrap()
+            .role_name = TlsString("Stranger".to_owned());
+        assert_eq!(
+            state.diagnose(),
+            vec![
+                Diagnostic::new(Error::SpecialRole),
+                Diagnostic::new(Error::ParentRoomMissing),
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_role() {
+        let alice = b"alice";
+
+        let mut policy = billing_policy();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::ChangeRoleDefinitions);
+        let mut room = VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
+        let rename = |index, name: &str| PolicyProposal::RenameRole {
+            index,
+            name: TlsString(name.to_owned()),
+            description: TlsString("Pays the bills".to_owned()),
+        };
+
+        room.apply_policy_proposals(alice, &[rename(RoleIndex::Custom(5), "Accounting")])
+            .unwrap();
+        let role_info = &room.unverified().policy.roles[&RoleIndex::Custom(5)];
+        assert_eq!(*role_info.role_name, "Accounting");
+        assert_eq!(*role_info.role_description, "Pays the bills");
+
+        assert_eq!(
+            room.apply_policy_proposals(alice, &[rename(RoleIndex::Outsider, "Guest")]),
+            Err(Error::SpecialRole)
+        );
+        assert_eq!(
+            room.apply_policy_proposals(
+                alice,
+                &[rename(
+                    RoleIndex::Custom(5),
+                    &"a".repeat(MAX_ROLE_NAME_LENGTH + 1)
+                )]
+            ),
+            Err(Error::StringTooLong)
+        );
+        assert_eq!(
+            room.apply_policy_proposals(alice, &[rename(RoleIndex::Custom(6), "Mod")]),
+            Err(Error::RoleNotDefined)
+        );
+    }
+
+    #[test]
+    fn change_settings() {
+        let scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob");
+        let mut room = scenario.room().clone();
+        let change = |setting| [PolicyProposal::ChangeSetting(setting)];
+
+        room.apply_policy_proposals(b"alice", &change(RoomSetting::Discoverable(true)))
+            .unwrap();
+        assert!(room.unverified().policy.discoverable);
+        assert_eq!(
+            room.apply_policy_proposals(b"alice", &change(RoomSetting::Discoverable(true))),
+            Err(Error::NothingToDo)
+        );
+        assert_eq!(
+            room.apply_policy_proposals(b"bob", &change(RoomSetting::Discoverable(false))),
+            Err(Error::NotCapable)
+        );
+
+        // Fixing the membership of a room with members is fine
+        let mut fixed = room.clone();
+        fixed
+            .apply_policy_proposals(
+                b"alice",
+                &change(RoomSetting::MembershipStyle(
+                    MembershipStyle::FixedMembership,
+                )),
+            )
+            .unwrap();
+
+        // Depending on a parent requires its URI
+        let parent_dependent = RoomSetting::MembershipStyle(MembershipStyle::ParentDependent);
+        assert_eq!(
+            room.apply_policy_proposals(b"alice", &change(parent_dependent.clone())),
+            Err(Error::ParentRoomMissing)
+        );
+        room.apply_policy_proposals(
+            b"alice",
+            &[
+                PolicyProposal::ChangeSetting(RoomSetting::ParentRoomUri(TlsString(
+                    "mimi://example.com/r/parent".to_owned(),
+                ))),
+                PolicyProposal::ChangeSetting(parent_dependent),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            room.unverified().policy.membership_style,
+            MembershipStyle::ParentDependent
+        );
+    }
+
+    #[test]
+    fn validate_all() {
+        assert_eq!(RoomPolicy::default_public().validate_all(), vec![]);
+
+        let mut policy = RoomPolicy::default_public();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .authorized_role_changes
+            .insert(RoleIndex::Regular, vec![RoleIndex::Custom(9)]);
+        policy
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .min_participants_constraint = 5;
+        policy
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .max_participants_constraint = Some(2);
+
+        assert_eq!(
+            policy.validate_all(),
+            vec![Error::InvalidMinMaxConstraints, Error::RoleNotDefined]
+        );
+        assert_eq!(policy.validate(), Err(Error::InvalidMinMaxConstraints));
+    }
+
+    #[test]
+    fn extension_size_limits() {
+        let extension = |size| PolicyExtension {
+            name: TlsString("example".to_owned()),
+            value_type: ExtensionType::Bytes,
+            value: vec![0; size],
+        };
+
+        let mut policy = RoomPolicy::default_private();
+        policy.policy_extensions = vec![extension(MAX_EXTENSION_SIZE)];
+        policy.validate().unwrap();
+        assert_eq!(
+            tls_deserialize::<RoomPolicy>(&tls_serialize(&policy)),
+            policy
+        );
+
+        policy.policy_extensions = vec![extension(MAX_EXTENSION_SIZE + 1)];
+        assert_eq!(policy.validate(), Err(Error::InvalidExtension));
+        assert!(RoomPolicy::tls_deserialize_exact_bytes(&tls_serialize(&policy)).is_err());
+
+        policy.policy_extensions = vec![extension(MAX_EXTENSION_SIZE); 5];
+        assert_eq!(policy.validate(), Err(Error::PolicyTooLarge));
+    }
+
+    #[test]
+    fn merge_roles() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let mut policy = billing_policy();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::ChangeRoleDefinitions);
+        let mut users = BTreeMap::new();
+        users.insert(alice.to_vec(), BTreeSet::from([RoleIndex::Owner]));
+        users.insert(bob.to_vec(), BTreeSet::from([RoleIndex::Custom(5)]));
+        let mut room = VerifiedRoomState::verify(RoomState::new(policy, users)).unwrap();
+
+        assert_eq!(
+            room.merge_roles(bob, RoleIndex::Custom(5), RoleIndex::Regular),
+            Err(Error::NotCapable)
+        );
+        assert_eq!(
+            room.merge_roles(alice, RoleIndex::Banned, RoleIndex::Regular),
+            Err(Error::SpecialRole)
+        );
+
+        room.merge_roles(alice, RoleIndex::Custom(5), RoleIndex::Regular)
+            .unwrap();
+        assert_eq!(room.user_role(bob), RoleIndex::Regular);
+        let policy = &room.unverified().policy;
+        assert!(!policy.roles.contains_key(&RoleIndex::Custom(5)));
+        assert_eq!(
+            policy.roles[&RoleIndex::Owner].authorized_role_changes[&RoleIndex::Regular],
+            vec![RoleIndex::Outsider, RoleIndex::Admin, RoleIndex::Owner]
+        );
+
+        // The merged role is gone
+        assert_eq!(
+            room.merge_roles(alice, RoleIndex::Custom(5), RoleIndex::Regular),
+            Err(Error::RoleNotDefined)
+        );
+    }
+
+    #[test]
+    fn destroy_room() {
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::DestroyRoom);
+        let scenario = Scenario::new(policy)
+            .create_owner("alice")
+            .invite("alice", "bob")
+            .promote("alice", "bob", RoleIndex::Admin);
+        let room = scenario.room().clone();
+
+        // Bob is an admin, but cannot destroy the room
+        assert_eq!(room.clone().destroy(b"bob"), Err(Error::NotCapable));
+
+        let mut destroyed = room.destroy(b"alice").unwrap();
+        assert_eq!(destroyed.destroyed_by(), b"alice");
+        assert_eq!(
+            destroyed.apply_regular_proposals(b"alice", &[kick("bob")]),
+            Err(Error::RoomDestroyed)
+        );
+        assert_eq!(
+            destroyed.apply_policy_proposals(
+                b"alice",
+                &[PolicyProposal::RemovePreauthorized {
+                    user: b"bob".to_vec()
+                }]
+            ),
+            Err(Error::RoomDestroyed)
+        );
+    }
+
+    #[test]
+    fn preauthorized_users() {
+        let mut policy = RoomPolicy::default_public();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::ChangePreauthorizedUserList);
+        policy
+            .preauthorized
+            .insert(b"bob".to_vec(), RoleIndex::Admin);
+
+        let mut scenario = Scenario::new(policy)
+            .create_owner("alice")
+            .join("bob")
+            .expect_role("bob", RoleIndex::Admin)
+            .join("carol")
+            .expect_role("carol", RoleIndex::Regular);
+
+        // Bob is an admin, but cannot change the list
+        let preauthorize_dave = PolicyProposal::SetPreauthorized {
+            user: b"dave".to_vec(),
+            role: RoleIndex::Admin,
+        };
+        let room = scenario.room.as_mut().unwrap();
+        assert_eq!(
+            room.apply_policy_proposals(b"bob", std::slice::from_ref(&preauthorize_dave)),
+            Err(Error::NotCapable)
+        );
+        room.apply_policy_proposals(b"alice", &[preauthorize_dave])
+            .unwrap();
+        scenario = scenario.join("dave").expect_role("dave", RoleIndex::Admin);
+
+        // Invitations are not affected
+        let room = scenario.room.as_mut().unwrap();
+        room.apply_policy_proposals(
+            b"alice",
+            &[PolicyProposal::SetPreauthorized {
+                user: b"erin".to_vec(),
+                role: RoleIndex::Admin,
+            }],
+        )
+        .unwrap();
+        scenario
+            .invite("alice", "erin")
+            .expect_role("erin", RoleIndex::Regular);
+
+        // Preauthorized roles must be defined
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .preauthorized
+            .insert(b"bob".to_vec(), RoleIndex::Banned);
+        assert_eq!(policy.validate(), Err(Error::RoleNotDefined));
+    }
+
+    #[test]
+    fn complexity_metrics() {
+        let dm = RoomPolicy::default_dm().complexity_metrics();
+        let public = RoomPolicy::default_public().complexity_metrics();
+
+        assert_eq!(
+            dm,
+            PolicyMetrics {
+                roles: 3,
+                transitions: 0,
+                capabilities: 6,
+                bots: 0,
+                extensions: 0,
+            }
+        );
+        assert_eq!(
+            public,
+            PolicyMetrics {
+                roles: 5,
+                transitions: 32,
+                capabilities: 29,
+                bots: 0,
+                extensions: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn capability_removal_impact() {
+        let mut policy = RoomPolicy::default_public();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::DeleteAnyMessage);
+        let scenario = Scenario::new(policy)
+            .create_owner("alice")
+            .invite("alice", "bob")
+            .promote("alice", "bob", RoleIndex::Admin)
+            .invite("alice", "carol")
+            .promote("alice", "carol", RoleIndex::Admin)
+            .invite("alice", "dave");
+        let room = scenario.room();
+
+        // Admins are the only moderators
+        assert_eq!(
+            room.capability_removal_impact(RoleIndex::Admin, Capability::DeleteAnyMessage),
+            RemovalImpact {
+                members_losing: 2,
+                ungranted: true,
+            }
+        );
+
+        // Owners keep sending messages, but regular users lose it
+        assert_eq!(
+            room.capability_removal_impact(RoleIndex::Regular, Capability::SendMessage),
+            RemovalImpact {
+                members_losing: 1,
+                ungranted: false,
+            }
+        );
+        assert_eq!(
+            room.capability_removal_impact(RoleIndex::Regular, Capability::DeleteAnyMessage),
+            RemovalImpact {
+                members_losing: 0,
+                ungranted: false,
+            }
+        );
+    }
+
+    #[test]
+    fn validate_draft_policy() {
+        let mut draft = billing_policy();
+        draft.validate().unwrap();
+
+        draft
+            .roles
+            .get_mut(&RoleIndex::Custom(5))
+            .unwrap()
+            .self_role_changes
+            .push(RoleIndex::Custom(5));
+        assert_eq!(
+            draft.validate(),
+            Err(Error::InvalidRoleTransition {
+                source_role: RoleIndex::Custom(5),
+                target_role: RoleIndex::Custom(5),
+            })
+        );
+    }
+
+    #[test]
+    fn can_edit_policy() {
+        let scenario = |policy| {
+            Scenario::new(policy)
+                .create_owner("alice")
+                .invite("alice", "bob")
+                .promote("alice", "bob", RoleIndex::Admin)
+                .invite("alice", "carol")
+        };
+
+        // By default, only the owner can edit the policy
+        let room = scenario(RoomPolicy::default_public()).room().clone();
+        assert!(room.can_edit_policy(b"alice"));
+        assert!(!room.can_edit_policy(b"bob"));
+        assert!(!room.can_edit_policy(b"carol"));
+
+        let mut policy = RoomPolicy::default_public();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::ChangeRoleDefinitions);
+        let room = scenario(policy).room().clone();
+        assert!(room.can_edit_policy(b"alice"));
+        assert!(room.can_edit_policy(b"bob"));
+        assert!(!room.can_edit_policy(b"carol"));
+    }
+
+    #[test]
+    fn default_owner_can_edit_policy() {
+        for policy in [RoomPolicy::default_private(), RoomPolicy::default_public()] {
+            let mut room = VerifiedRoomState::new(b"alice".to_vec(), policy).unwrap();
+            room.apply_policy_proposals(
+                b"alice",
+                &[PolicyProposal::RenameRole {
+                    index: RoleIndex::Regular,
+                    name: TlsString("Member".to_owned()),
+                    description: TlsString("".to_owned()),
+                }],
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn resolve_identity() {
+        let scenario = |policy| {
+            Scenario::new(policy)
+                .create_owner("alice")
+                .invite("alice", "bob")
+                .promote("alice", "bob", RoleIndex::Admin)
+                .invite("alice", "carol")
+        };
+
+        let mut policy = RoomPolicy::default_public();
+        policy.semi_anonymous_ids = false;
+        let room = scenario(policy).room().clone();
+        assert!(room.can_see_real_identity(b"carol", b"bob"));
+
+        // The default policy uses semi-anonymous ids
+        let mut policy = RoomPolicy::default_public();
+        assert!(policy.semi_anonymous_ids);
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::ResolveIdentity);
+        let room = scenario(policy).room().clone();
+        assert!(room.can_see_real_identity(b"bob", b"carol"));
+        assert!(!room.can_see_real_identity(b"carol", b"bob"));
+        assert!(!room.can_see_real_identity(b"alice", b"carol"));
+        assert!(room.can_see_real_identity(b"carol", b"carol"));
+    }
+
+    #[test]
+    fn anonymity_collisions() {
+        let scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("anne")
+            .join("arthur");
+        let room = scenario.room();
+
+        let full_hash = |user: &[u8]| Sha256::digest(user).to_vec();
+        // With four members and a hash of a single bit, some members must collide
+        let tiny_hash = |user: &[u8]| vec![full_hash(user)[0] & 1];
+        let first_letter = |user: &[u8]| user[..1].to_vec();
+
+        assert_eq!(
+            room.anonymity_collisions(first_letter),
+            vec![
+                (b"alice".to_vec(), b"anne".to_vec()),
+                (b"alice".to_vec(), b"arthur".to_vec()),
+                (b"anne".to_vec(), b"arthur".to_vec()),
+            ]
+        );
+        assert!(room.anonymity_collisions(full_hash).is_empty());
+        assert!(!room.anonymity_collisions(tiny_hash).is_empty());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn invariants_hold() {
+        let scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .promote("alice", "bob", RoleIndex::Admin)
+            .apply("bob", kick("bob"))
+            .join("carol")
+            .apply("alice", ban("carol"));
+        scenario.room().unverified().assert_invariants();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "listed as an outsider")]
+    fn invariants_detect_outsiders() {
+        let mut users = BTreeMap::new();
+        users.insert(b"alice".to_vec(), BTreeSet::from([RoleIndex::Outsider]));
+        RoomState::new(RoomPolicy::default_public(), users).assert_invariants();
+    }
+
+    #[test]
+    fn canonical_hash() {
+        let build = || {
+            Scenario::new(RoomPolicy::default_public())
+                .create_owner("alice")
+                .join("carol")
+                .invite("alice", "bob")
+        };
+        let room = build().room().clone();
+        assert_eq!(room.canonical_hash(), build().room().canonical_hash());
+
+        let promoted = build().promote("alice", "bob", RoleIndex::Admin);
+        assert_ne!(room.canonical_hash(), promoted.room().canonical_hash());
+    }
+
+    #[test]
+    fn delta_bytes() {
+        for base in [
+            RoomPolicy::default_dm(),
+            RoomPolicy::default_private(),
+            RoomPolicy::default_public(),
+        ] {
+            let room = VerifiedRoomState::new(b"alice".to_vec(), base.clone()).unwrap();
+            let bytes = room.to_delta_bytes(&base);
+            assert!(
+                bytes.len()
+                    < tls_codec::Serialize::tls_serialize_detached(&room)
+                        .unwrap()
+                        .len()
+            );
+            assert_eq!(
+                VerifiedRoomState::from_delta_bytes(&bytes, &base).unwrap(),
+                room
+            );
+
+            let mut policy = base.clone();
+            policy.discoverable = !policy.discoverable;
+            let room = VerifiedRoomState::new(b"alice".to_vec(), policy).unwrap();
+            let bytes = room.to_delta_bytes(&base);
+            assert_eq!(
+                VerifiedRoomState::from_delta_bytes(&bytes, &base).unwrap(),
+                room
+            );
+        }
+
+        let room = VerifiedRoomState::new(b"alice".to_vec(), RoomPolicy::default_dm()).unwrap();
+        assert_eq!(
+            VerifiedRoomState::from_delta_bytes(&[], &RoomPolicy::default_dm()),
+            Err(Error::DecodingError)
+        );
+        let bytes = room.to_delta_bytes(&RoomPolicy::default_public());
+        assert_ne!(
+            VerifiedRoomState::from_delta_bytes(&bytes, &RoomPolicy::default_dm()),
+            Ok(room)
+        );
+    }
+
+    #[test]
+    fn report_abuse() {
+        let scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .apply("alice", ban("carol"));
+        let room = scenario.room();
+
+        room.authorize_report(b"alice").unwrap();
+        room.authorize_report(b"bob").unwrap();
+        assert_eq!(room.authorize_report(b"carol"), Err(Error::NotCapable));
+        assert_eq!(room.authorize_report(b"dave"), Err(Error::NotCapable));
+    }
+
+    #[test]
+    fn members() {
+        let scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .invite("alice", "carol")
+            .invite("alice", "bob")
+            .promote("alice", "bob", RoleIndex::Admin);
+
+        assert_eq!(
+            scenario.room().members().collect::<Vec<_>>(),
+            vec![
+                (&b"alice"[..], RoleIndex::Owner),
+                (&b"bob"[..], RoleIndex::Admin),
+                (&b"carol"[..], RoleIndex::Regular),
+            ]
+        );
+    }
+
+    #[test]
+    fn backup_member_keys() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let mut users = BTreeMap::new();
+        users.insert(
+            alice.to_vec(),
+            BTreeSet::from([RoleIndex::Owner, RoleIndex::Custom(5)]),
+        );
+        users.insert(bob.to_vec(), BTreeSet::from([RoleIndex::Regular]));
+        let room = VerifiedRoomState::verify(RoomState::new(billing_policy(), users)).unwrap();
+
+        let keys = room.raw_member_keys();
+        assert_eq!(keys, vec![&alice[..], &bob[..]]);
+        let backup: Vec<_> = keys
+            .into_iter()
+            .map(|key| (key, room.user_roles(key)))
+            .collect();
+
+        let restored = VerifiedRoomState::restore(billing_policy(), backup).unwrap();
+        assert_eq!(restored.users(), room.users());
+
+        // The restored membership is verified
+        assert_eq!(
+            VerifiedRoomState::restore(
+                billing_policy(),
+                [(&bob[..], BTreeSet::from([RoleIndex::Regular]))],
+            ),
+            Err(Error::RoleMinMaxViolated {
+                role: RoleIndex::Owner,
+                kind: MinMaxKind::Min,
+                limit: 1,
+                actual: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn reversible_proposals() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        // A kick can be undone by re-inviting
+        let mut room =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_private()).unwrap();
+        let invite = MimiProposal::ChangeRole {
+            target: bob.to_vec(),
+            role: RoleIndex::Regular,
+        };
+        room.apply_regular_proposals(alice, std::slice::from_ref(&invite))
+            .unwrap();
+        let kick = MimiProposal::ChangeRole {
+            target: bob.to_vec(),
+            role: RoleIndex::Outsider,
+        };
+        assert!(kick.is_reversible(&room, None));
+
+        // Nobody can lift a ban if no role is authorized to change banned users
+        let mut policy = RoomPolicy::default_public();
+        for role_info in policy.roles.values_mut() {
+            role_info.authorized_role_changes.remove(&RoleIndex::Banned);
+        }
+        let mut room = VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
+        room.apply_regular_proposals(alice, &[invite]).unwrap();
+        let ban = MimiProposal::ChangeRole {
+            target: bob.to_vec(),
+            role: RoleIndex::Banned,
+        };
+        assert!(kick.is_reversible(&room, None));
+        assert!(!ban.is_reversible(&room, None));
+
+        // In the default public room, admins and owners can lift bans
+        let mut room =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_public()).unwrap();
+        room.apply_regular_proposals(
+            bob,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }],
+        )
+        .unwrap();
+        assert!(ban.is_reversible(&room, None));
+
+        // Bans cannot be lifted if no role can unban, even if role changes of banned users are authorized
+        let mut policy = RoomPolicy::default_public();
+        for role_info in policy.roles.values_mut() {
+            role_info.role_capabilities.remove(&Capability::UnBan);
+        }
+        let mut room = VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
+        room.apply_regular_proposals(
+            bob,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }],
+        )
+        .unwrap();
+        assert!(kick.is_reversible(&room, None));
+        assert!(!ban.is_reversible(&room, None));
+    }
+
+    #[test]
+    fn roles_with_capability() {
+        let mut policy = RoomPolicy::default_public();
+        assert_eq!(
+            policy.roles_with_capability(Capability::SendMessage),
+            vec![RoleIndex::Regular, RoleIndex::Admin, RoleIndex::Owner]
+        );
+        assert_eq!(
+            policy.roles_with_capability(Capability::ChangeRoleDefinitions),
+            vec![RoleIndex::Owner]
+        );
+
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::ChangeRoleDefinitions);
+        assert_eq!(
+            policy.roles_with_capability(Capability::ChangeRoleDefinitions),
+            vec![RoleIndex::Admin, RoleIndex::Owner]
+        );
+    }
+
+    #[test]
+    fn manage_bots() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::ChangeRoleDefinitions);
+        let mut room = VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }],
+        )
+        .unwrap();
+
+        let name = TlsString("echo".to_owned());
+        let bot = Bot::new(
+            "Echoes messages",
+            "https://example.com",
+            RoleIndex::Regular,
+            true,
+            true,
+            false,
+            false,
+        )
+        .unwrap();
+        let add_bot = PolicyProposal::AddBot {
+            name: name.clone(),
+            bot: bot.clone(),
+        };
+
+        // Bob cannot add bots
+        assert_eq!(
+            room.apply_policy_proposals(bob, std::slice::from_ref(&add_bot)),
+            Err(Error::NotCapable)
+        );
+
+        // Alice can, but only once
+        room.apply_policy_proposals(alice, std::slice::from_ref(&add_bot))
+            .unwrap();
+        assert_eq!(room.unverified().policy.allowed_bots[&name], bot);
+        assert_eq!(
+            room.apply_policy_proposals(alice, &[add_bot]),
+            Err(Error::DuplicateBot)
+        );
+
+        // Bots cannot do more than their role
+        let outsider_bot =
+            Bot::new("", "", RoleIndex::Outsider, true, false, false, false).unwrap();
+        assert_eq!(
+            room.apply_policy_proposals(
+                alice,
+                &[PolicyProposal::AddBot {
+                    name: TlsString("reader".to_owned()),
+                    bot: outsider_bot,
+                }]
+            ),
+            Err(Error::InvalidBotDefinition)
+        );
+
+        let room2 = tls_deserialize(&tls_serialize(&room));
+        assert_eq!(room, room2);
+        let room3 = cbor_deserialize(&cbor_serialize(&room));
+        assert_eq!(room, room3);
+
+        // Alice can remove the bot again
+        let remove_bot = PolicyProposal::RemoveBot { name };
+        room.apply_policy_proposals(alice, std::slice::from_ref(&remove_bot))
+            .unwrap();
+        assert!(room.unverified().policy.allowed_bots.is_empty());
+        assert_eq!(
+            room.apply_policy_proposals(alice, &[remove_bot]),
+            Err(Error::NothingToDo)
+        );
+    }
+
+    #[test]
+    fn leave() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let mut room = VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_dm()).unwrap();
+        let mut users = room.users().clone();
+        users.insert(bob.to_vec(), BTreeSet::from([RoleIndex::Regular]));
+        room = VerifiedRoomState::verify(RoomState::new(RoomPolicy::default_dm(), users)).unwrap();
+
+        // The owner of a DM cannot leave
+        assert_eq!(
+            room.leave(alice),
+            Err(Error::RoleMinMaxViolated {
+                role: RoleIndex::Owner,
+                kind: MinMaxKind::Min,
+                limit: 1,
+                actual: 0,
+            })
+        );
+
+        // Bob can leave, although the DM policy has no self role changes
+        room.leave(bob).unwrap();
+        assert_eq!(room.user_role(bob), RoleIndex::Outsider);
+        assert_eq!(room.leave(bob), Err(Error::UserNotInRoom));
+
+        // Banned users cannot leave, which would lift the ban
+        let mut room = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .apply("alice", ban("bob"))
+            .room()
+            .clone();
+        assert_eq!(room.leave(bob), Err(Error::Banned));
+        assert_eq!(room.membership_status(bob), MembershipStatus::Banned);
+    }
+
+    #[test]
+    fn ban_persists() {
+        let alice = b"alice";
+        let bob = b"bob";
+        let carol = b"carol";
+
+        let mut policy = RoomPolicy::default_public();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .role_capabilities
+            .remove(&Capability::UnBan);
+        let mut room = VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
+        room.apply_regular_proposals(
+            alice,
+            &[
+                MimiProposal::ChangeRole {
+                    target: bob.to_vec(),
+                    role: RoleIndex::Banned,
+                },
+                MimiProposal::ChangeRole {
+                    target: carol.to_vec(),
+                    role: RoleIndex::Admin,
+                },
+            ],
+        )
+        .unwrap();
+        assert!(room.is_banned(bob));
+        assert!(!room.is_banned(carol));
+
+        // The ban survives encoding
+        let mut room: VerifiedRoomState = tls_deserialize(&tls_serialize(&room));
+        assert!(room.is_banned(bob));
+
+        // Bob cannot lift the ban by leaving
+        assert_eq!(room.leave(bob), Err(Error::Banned));
+        assert!(room.is_banned(bob));
+
+        // Carol is allowed to change the role of banned users, but cannot unban
+        assert_eq!(
+            room.can_apply_regular_proposals(
+                carol,
+                &[MimiProposal::ChangeRole {
+                    target: bob.to_vec(),
+                    role: RoleIndex::Outsider,
+                }],
+            ),
+            Err(Error::NotCapable)
+        );
+        assert!(room.is_banned(bob));
+
+        room.can_apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Outsider,
+            }],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn custom_role_overreach() {
+        let mut policy = billing_policy();
+        assert_eq!(policy.custom_role_overreach(true), Ok(Vec::new()));
+
+        // Billing may demote the owner
+        let billing = policy.roles.get_mut(&RoleIndex::Custom(5)).unwrap();
+        billing
+            .authorized_role_changes
+            .insert(RoleIndex::Regular, vec![RoleIndex::Outsider]);
+        billing
+            .authorized_role_changes
+            .insert(RoleIndex::Owner, vec![RoleIndex::Regular]);
+        assert_eq!(
+            policy.custom_role_overreach(false),
+            Ok(vec![(
+                RoleIndex::Custom(5),
+                RoleIndex::Owner,
+                RoleIndex::Regular
+            )])
+        );
+        assert_eq!(
+            policy.custom_role_overreach(true),
+            Err(Error::InvalidRoleTransition {
+                source_role: RoleIndex::Owner,
+                target_role: RoleIndex::Regular,
+            })
+        );
+
+        // Built-in roles are not flagged
+        assert_eq!(
+            RoomPolicy::default_private().custom_role_overreach(true),
+            Ok(Vec::new())
+        );
+    }
+
+    #[test]
+    fn all_capabilities() {
+        // Every capability is listed once, at the position of its encoding
+        for (index, capability) in Capability::ALL.iter().enumerate() {
+            assert_eq!(tls_serialize(capability), vec![index as u8]);
+            assert_eq!(
+                Capability::tls_deserialize_exact_bytes(&[index as u8]).as_ref(),
+                Ok(capability)
+            );
+        }
+
+        // No capability is missing
+        let count = Capability::ALL.len() as u8;
+        assert!(Capability::tls_deserialize_exact_bytes(&[count]).is_err());
+    }
+
+    #[test]
+    fn capability_set() {
+        let capabilities = vec![
+            Capability::EditOtherMessage,
+            Capability::SendMessage,
+            Capability::JoinCall,
+        ];
+        let set: CapabilitySet = capabilities.iter().cloned().collect();
+        assert!(set.contains(Capability::JoinCall));
+        assert!(!set.contains(Capability::StartCall));
+
+        // Iteration yields the same capabilities in the order of their encoding
+        let mut sorted = capabilities.clone();
+        sorted.sort();
+        assert_eq!(set.iter().collect::<Vec<_>>(), sorted);
+
+        // The set is encoded as a single u64 instead of a vector of capabilities. This is a deliberate break of the wire format.
+        let encoded = tls_serialize(&set);
+        assert_eq!(encoded, (1_u64 | 1 << 14 | 1 << 16).to_be_bytes());
+        assert_ne!(encoded, tls_serialize(&capabilities));
+        assert_eq!(tls_deserialize::<CapabilitySet>(&encoded), set);
+        assert_eq!(cbor_deserialize::<CapabilitySet>(&cbor_serialize(set)), set);
+
+        // Bits without a capability are rejected
+        assert!(CapabilitySet::tls_deserialize_exact_bytes(&(1_u64 << 63).to_be_bytes()).is_err());
+        assert_eq!(CapabilitySet::try_from(1 << 63), Err(Error::DecodingError));
+    }
+
+    #[test]
+    fn timed_ban() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let mut room =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_public()).unwrap();
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }],
+        )
+        .unwrap();
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRoleUntil {
+                target: bob.to_vec(),
+                role: RoleIndex::Banned,
+                expires_at: 100,
+                fallback_role: RoleIndex::Outsider,
+            }],
+        )
+        .unwrap();
+
+        assert_eq!(room.user_role(bob), RoleIndex::Banned);
+        assert_eq!(room.role_at(bob, 99), RoleIndex::Banned);
+        assert_eq!(room.role_at(bob, 100), RoleIndex::Outsider);
+        assert_eq!(room.role_at(alice, 100), RoleIndex::Owner);
+
+        // Bob cannot schedule his own unban
+        assert_eq!(
+            room.can_apply_regular_proposals(
+                bob,
+                &[MimiProposal::ChangeRoleUntil {
+                    target: bob.to_vec(),
+                    role: RoleIndex::Banned,
+                    expires_at: 0,
+                    fallback_role: RoleIndex::Outsider,
+                }],
+            ),
+            Err(Error::NotCapable)
+        );
+
+        // A later role change drops the scheduled change
+        room.apply_regular_proposals(
+            alice,
+            &[MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }],
+        )
+        .unwrap();
+        assert_eq!(room.role_at(bob, 100), RoleIndex::Regular);
+    }
+
+    #[test]
+    fn expire_roles() {
+        let mut scenario = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .apply(
+                "alice",
+                MimiProposal::ChangeRoleUntil {
+                    target: b"bob".to_vec(),
+                    role: RoleIndex::Banned,
+                    expires_at: 100,
+                    fallback_role: RoleIndex::Outsider,
+                },
+            )
+            .apply(
+                "alice",
+                MimiProposal::ChangeRoleUntil {
+                    target: b"carol".to_vec(),
+                    role: RoleIndex::Admin,
+                    expires_at: 200,
+                    fallback_role: RoleIndex::Regular,
+                },
+            );
+        let room = scenario.room.as_mut().unwrap();
+
+        assert_eq!(room.expire_roles(99), vec![]);
+        assert!(room.is_banned(b"bob"));
+
+        // The temporary ban is over
+        assert_eq!(room.expire_roles(100), vec![(b"bob".to_vec(), Ok(()))]);
+        assert!(!room.is_banned(b"bob"));
+        assert_eq!(room.user_role(b"bob"), RoleIndex::Outsider);
+        assert_eq!(room.user_role(b"carol"), RoleIndex::Admin);
+
+        assert_eq!(room.expire_roles(300), vec![(b"carol".to_vec(), Ok(()))]);
+        assert_eq!(room.user_role(b"carol"), RoleIndex::Regular);
+        assert_eq!(room.expire_roles(300), vec![]);
+
+        // Expiries that violate the participant constraints are not applied, but do not block other expiries
+        let mut state = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .room()
+            .unverified()
+            .clone();
+        let call = CallId(0);
+        state
+            .calls
+            .insert(call, BTreeSet::from([b"carol".to_vec()]));
+        state
+            .users
+            .get_mut(b"bob".as_slice())
+            .unwrap()
+            .insert(RoleIndex::Admin);
+        for (user, role) in [
+            ("alice", RoleIndex::Owner),
+            ("bob", RoleIndex::Admin),
+            ("carol", RoleIndex::Regular),
+        ] {
+            state.role_expiries.insert(
+                user.as_bytes().to_vec(),
+                RoleExpiry {
+                    role,
+                    expires_at: 100,
+                    fallback_role: RoleIndex::Outsider,
+                },
+            );
+        }
+        let room = &mut VerifiedRoomState::verify(state).unwrap();
+        let results = room.expire_roles(100);
+        assert!(matches!(
+            results[0],
+            (ref user, Err(Error::RoleMinMaxViolated { .. })) if user == b"alice"
+        ));
+        assert_eq!(
+            results[1..],
+            [(b"bob".to_vec(), Ok(())), (b"carol".to_vec(), Ok(()))]
+        );
+        assert_eq!(room.user_role(b"alice"), RoleIndex::Owner);
+        assert!(room
+            .unverified()
+            .role_expiries
+            .contains_key(b"alice".as_slice()));
+
+        // Only the expired role is removed, and users without roles leave their calls
+        assert_eq!(
+            room.user_roles(b"bob"),
+            BTreeSet::from([RoleIndex::Regular])
+        );
+        assert_eq!(room.user_role(b"carol"), RoleIndex::Outsider);
+        assert_eq!(room.unverified().calls.get(&call), None);
+    }
 
-    use super::*;
+    #[test]
+    fn sensitive_grants() {
+        assert!(Capability::ChangeRoleDefinitions.is_sensitive());
+        assert!(Capability::DeleteAnyMessage.is_sensitive());
+        assert!(Capability::DestroyRoom.is_sensitive());
+        assert!(!Capability::DeleteOwnMessage.is_sensitive());
 
-    fn tls_serialize<T: tls_codec::Serialize>(val: &T) -> Vec<u8> {
-        let mut vec = Vec::new();
-        val.tls_serialize(&mut vec).unwrap();
-        vec
-    }
+        let mut policy = RoomPolicy::default_public();
+        assert_eq!(
+            policy.sensitive_grants(),
+            vec![(RoleIndex::Owner, Capability::ChangeRoleDefinitions)]
+        );
 
-    fn tls_deserialize<T: DeserializeBytes>(val: &[u8]) -> T {
-        T::tls_deserialize_exact_bytes(val).unwrap()
+        for role in [RoleIndex::Admin, RoleIndex::Owner] {
+            let role_info = policy.roles.get_mut(&role).unwrap();
+            role_info
+                .role_capabilities
+                .insert(Capability::DeleteAnyMessage);
+            role_info
+                .role_capabilities
+                .insert(Capability::DeleteOwnMessage);
+        }
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::ChangeRoleDefinitions);
+        assert_eq!(
+            policy.sensitive_grants(),
+            vec![
+                (RoleIndex::Admin, Capability::DeleteAnyMessage),
+                (RoleIndex::Owner, Capability::DeleteAnyMessage),
+                (RoleIndex::Owner, Capability::ChangeRoleDefinitions),
+            ]
+        );
     }
 
-    fn cbor_serialize<T: Serialize>(val: T) -> Vec<u8> {
-        let mut result = Vec::new();
-        ciborium::ser::into_writer(&val, &mut result).unwrap();
-        result
+    #[test]
+    fn publicly_joinable() {
+        let mut room = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .room()
+            .clone();
+        assert!(!room.is_discoverable());
+        assert!(!room.is_publicly_joinable());
+
+        room.apply_policy_proposals(
+            b"alice",
+            &[PolicyProposal::ChangeSetting(RoomSetting::Discoverable(
+                true,
+            ))],
+        )
+        .unwrap();
+        assert!(room.is_discoverable());
+        assert!(room.is_publicly_joinable());
+
+        // Private rooms need an invitation, even if they are discoverable
+        let mut policy = RoomPolicy::default_private();
+        policy.discoverable = true;
+        let room = Scenario::new(policy).create_owner("alice").room().clone();
+        assert!(room.is_discoverable());
+        assert!(!room.is_publicly_joinable());
     }
 
-    fn cbor_deserialize<T: DeserializeOwned>(input: &[u8]) -> T {
-        ciborium::de::from_reader(Cursor::new(input)).unwrap()
+    #[test]
+    fn receipt_gating() {
+        for (optionality, required, allowed) in [
+            (Optionality::Forbidden, false, false),
+            (Optionality::Optional, false, true),
+            (Optionality::Required, true, true),
+        ] {
+            let mut policy = RoomPolicy::default_private();
+            policy.read_receipts = optionality.clone();
+            policy.delivery_notifications = optionality;
+            let room = Scenario::new(policy).create_owner("alice").room().clone();
+
+            assert_eq!(room.read_receipts_required(), required);
+            assert_eq!(room.delivery_notifications_required(), required);
+            assert_eq!(room.authorize_read_receipt(b"alice").is_ok(), allowed);
+            assert_eq!(
+                room.authorize_delivery_notification(b"alice").is_ok(),
+                allowed
+            );
+            assert_eq!(
+                room.authorize_read_receipt(b"bob"),
+                Err(Error::UserNotInRoom)
+            );
+        }
     }
 
     #[test]
-    fn dm_room() {
+    fn history_sharing() {
         let alice = b"alice";
+        let bob = b"bob";
+        let charlie = b"charlie";
+        let make_regular = MimiProposal::ChangeRole {
+            target: bob.to_vec(),
+            role: RoleIndex::Regular,
+        };
 
-        // Alice creates an invite-only room
-        let room = VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_dm()).unwrap();
+        // Only admins and owners can share history in public rooms
+        let mut room =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_public()).unwrap();
+        room.apply_regular_proposals(bob, std::slice::from_ref(&make_regular))
+            .unwrap();
+        assert_eq!(room.authorize_history_share(alice), Ok(()));
+        assert_eq!(room.authorize_history_share(bob), Err(Error::NotCapable));
+        assert_eq!(
+            room.authorize_history_share(charlie),
+            Err(Error::UserNotInRoom)
+        );
+        assert!(room.shares_history_automatically());
 
-        let room2 = tls_deserialize(&tls_serialize(&room));
-        assert_eq!(room, room2);
-        let room3 = cbor_deserialize(&cbor_serialize(&room));
-        assert_eq!(room, room3);
+        // Nobody can share history in private rooms
+        let mut room =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_private()).unwrap();
+        room.apply_regular_proposals(alice, &[make_regular])
+            .unwrap();
+        assert_eq!(room.authorize_history_share(alice), Err(Error::NotCapable));
+        assert_eq!(room.authorize_history_share(bob), Err(Error::NotCapable));
+        assert!(!room.shares_history_automatically());
     }
 
     #[test]
-    fn trusted_private_room() {
+    fn undefined_role_has_no_capabilities() {
         let alice = b"alice";
         let bob = b"bob";
 
-        // Alice creates an invite-only room
-        let mut room =
-            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_trusted_private()).unwrap();
+        // This state would not pass verification
+        let mut users = BTreeMap::new();
+        users.insert(alice.to_vec(), BTreeSet::from([RoleIndex::Custom(99)]));
+        let mut state = RoomState::new(RoomPolicy::default_private(), users);
 
-        // Bob cannot join
+        assert!(state.user_capabilities(alice).is_empty());
+        assert!(!state.has_capability(alice, Capability::ReceiveMessage));
         assert_eq!(
-            room.apply_regular_proposals(
-                bob,
+            state.try_regular_proposals(
+                alice,
                 &[MimiProposal::ChangeRole {
                     target: bob.to_vec(),
                     role: RoleIndex::Regular,
                 }],
+                None,
             ),
             Err(Error::NotCapable)
         );
+    }
 
-        // Bob cannot send messages
-        assert!(!room.has_capability(bob, Capability::SendMessage));
+    #[test]
+    fn outsider_cannot_change_roles_of_others() {
+        let alice = b"alice";
 
-        // Alice can add Bob
+        let mut policy = RoomPolicy::default_public();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Outsider)
+            .unwrap()
+            .authorized_role_changes
+            .insert(RoleIndex::Regular, vec![RoleIndex::Banned]);
+        assert_eq!(
+            VerifiedRoomState::new(alice.to_vec(), policy),
+            Err(Error::SpecialRole)
+        );
+
+        // Self-join is still allowed
+        let policy = RoomPolicy::default_public();
+        assert!(!policy.roles[&RoleIndex::Outsider]
+            .self_role_changes
+            .is_empty());
+        VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
+    }
+
+    #[test]
+    fn inverse_proposal() {
+        let alice = b"alice";
+        let bob = b"bob";
+
+        let mut room =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_private()).unwrap();
         room.apply_regular_proposals(
             alice,
             &[MimiProposal::ChangeRole {
@@ -1233,78 +9358,78 @@ mod tests {
         )
         .unwrap();
 
-        // Bob can now send messages
-        assert!(room.has_capability(bob, Capability::SendMessage));
-
-        // Bob can kick Alice
-        room.apply_regular_proposals(
-            bob,
-            &[MimiProposal::ChangeRole {
-                target: alice.to_vec(),
-                role: RoleIndex::Outsider,
-            }],
-        )
-        .unwrap();
-
-        // Alice cannot send messages
-        assert!(!room.has_capability(alice, Capability::SendMessage));
+        let promotion = MimiProposal::ChangeRole {
+            target: bob.to_vec(),
+            role: RoleIndex::Admin,
+        };
+        let previous_role = room.user_role(bob);
+        room.apply_regular_proposals(alice, std::slice::from_ref(&promotion))
+            .unwrap();
 
-        let room2 = tls_deserialize(&tls_serialize(&room));
-        assert_eq!(room, room2);
-        let room3 = cbor_deserialize(&cbor_serialize(&room));
-        assert_eq!(room, room3);
+        let inverse = room.inverse_proposal(&promotion, previous_role);
+        assert_eq!(
+            inverse,
+            MimiProposal::ChangeRole {
+                target: bob.to_vec(),
+                role: RoleIndex::Regular,
+            }
+        );
+        room.apply_regular_proposals(alice, &[inverse]).unwrap();
+        assert_eq!(room.user_role(bob), RoleIndex::Regular);
     }
 
     #[test]
-    fn invite_only_room() {
+    fn fixed_membership() {
         let alice = b"alice";
         let bob = b"bob";
+        let charlie = b"charlie";
 
-        // Alice creates an invite-only room
-        let mut room =
-            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_private()).unwrap();
-
-        // Bob cannot join
+        let mut policy = RoomPolicy::default_dm();
+        let mut owner_role_changes = BTreeMap::new();
+        owner_role_changes.insert(RoleIndex::Outsider, vec![RoleIndex::Regular]);
+        owner_role_changes.insert(RoleIndex::Regular, vec![RoleIndex::Outsider]);
+        let owner_role = policy.roles.get_mut(&RoleIndex::Owner).unwrap();
+        owner_role.authorized_role_changes = owner_role_changes;
+        owner_role
+            .role_capabilities
+            .insert(Capability::AddParticipant);
+        owner_role
+            .role_capabilities
+            .insert(Capability::RemoveParticipant);
+
+        let mut members = BTreeMap::new();
+        members.insert(alice.to_vec(), RoleIndex::Owner);
+        members.insert(bob.to_vec(), RoleIndex::Regular);
+        let mut room = VerifiedRoomState::assemble(&tls_serialize(&policy), &members).unwrap();
+
+        // Even though the policy would allow it, Alice can neither add Charlie nor kick Bob
         assert_eq!(
             room.apply_regular_proposals(
-                bob,
+                alice,
                 &[MimiProposal::ChangeRole {
-                    target: bob.to_vec(),
+                    target: charlie.to_vec(),
                     role: RoleIndex::Regular,
                 }],
             ),
-            Err(Error::NotCapable)
+            Err(Error::MembershipFixed)
         );
-
-        // Bob cannot send messages
-        assert!(!room.has_capability(bob, Capability::SendMessage));
-
-        // Alice can add Bob
-        room.apply_regular_proposals(
-            alice,
-            &[MimiProposal::ChangeRole {
-                target: bob.to_vec(),
-                role: RoleIndex::Regular,
-            }],
-        )
-        .unwrap();
-
-        // Bob can now send messages
-        assert!(room.has_capability(bob, Capability::SendMessage));
-
-        // Bob cannot kick Alice
         assert_eq!(
             room.apply_regular_proposals(
-                bob,
+                alice,
                 &[MimiProposal::ChangeRole {
-                    target: alice.to_vec(),
+                    target: bob.to_vec(),
                     role: RoleIndex::Outsider,
                 }],
             ),
-            Err(Error::NotCapable)
+            Err(Error::MembershipFixed)
         );
 
-        // Alice can kick bob
+        // In an ordinary room she could. Users of ordinary rooms must be able to leave.
+        policy.membership_style = MembershipStyle::Ordinary;
+        for role in [RoleIndex::Regular, RoleIndex::Owner] {
+            policy.roles.get_mut(&role).unwrap().self_role_changes = vec![RoleIndex::Outsider];
+        }
+        let mut room = VerifiedRoomState::assemble(&tls_serialize(&policy), &members).unwrap();
         room.apply_regular_proposals(
             alice,
             &[MimiProposal::ChangeRole {
@@ -1313,80 +9438,366 @@ mod tests {
             }],
         )
         .unwrap();
+    }
 
-        // Bob cannot send messages
-        assert!(!room.has_capability(bob, Capability::SendMessage));
+    #[test]
+    fn create_subgroup() {
+        let uri = "mimi://example.com/r/parent";
+        let parent = Scenario::new(RoomPolicy::default_public())
+            .create_owner("alice")
+            .join("bob")
+            .join("carol")
+            .promote("alice", "carol", RoleIndex::Admin);
+        let parent = parent.room();
 
-        let room2 = tls_deserialize(&tls_serialize(&room));
-        assert_eq!(room, room2);
-        let room3 = cbor_deserialize(&cbor_serialize(&room));
-        assert_eq!(room, room3);
+        assert_eq!(
+            parent.authorize_subgroup_creation(b"bob"),
+            Err(Error::NotCapable)
+        );
+        assert_eq!(parent.subgroup_policy(b"bob", uri), Err(Error::NotCapable));
+        assert_eq!(parent.authorize_subgroup_creation(b"carol"), Ok(()));
+
+        let policy = parent.subgroup_policy(b"carol", uri).unwrap();
+        assert_eq!(policy.membership_style, MembershipStyle::ParentDependent);
+        assert_eq!(policy.parent_room_uri, TlsString(uri.to_owned()));
+        assert_eq!(
+            policy.effective_membership_style(&[&parent.unverified().policy]),
+            Ok(MembershipStyle::Ordinary)
+        );
+
+        // Only members of the parent room can join the subgroup
+        let mut subgroup = VerifiedRoomState::new(b"carol".to_vec(), policy).unwrap();
+        subgroup
+            .apply_regular_proposals_with_parent(
+                b"bob",
+                &[change_role("bob", RoleIndex::Regular)],
+                parent,
+            )
+            .unwrap();
+        assert_eq!(
+            subgroup.apply_regular_proposals_with_parent(
+                b"dave",
+                &[change_role("dave", RoleIndex::Regular)],
+                parent
+            ),
+            Err(Error::UserNotInRoom)
+        );
     }
 
     #[test]
-    fn public_room() {
+    fn parent_chain_depth() {
+        let mut subgroup = RoomPolicy::default_private();
+        subgroup.membership_style = MembershipStyle::ParentDependent;
+        let root = RoomPolicy::default_private();
+
+        assert_eq!(
+            subgroup.effective_membership_style(&[]),
+            Err(Error::ParentRoomMissing)
+        );
+        assert_eq!(
+            subgroup.effective_membership_style(&[&root]),
+            Ok(MembershipStyle::Ordinary)
+        );
+
+        // A chain of MAX_PARENT_DEPTH parents is resolved
+        let mut ancestors = vec![&subgroup; MAX_PARENT_DEPTH - 1];
+        ancestors.push(&root);
+        assert_eq!(
+            subgroup.effective_membership_style(&ancestors),
+            Ok(MembershipStyle::Ordinary)
+        );
+
+        // A longer one is not
+        let mut ancestors = vec![&subgroup; MAX_PARENT_DEPTH];
+        ancestors.push(&root);
+        assert_eq!(
+            subgroup.effective_membership_style(&ancestors),
+            Err(Error::ParentChainTooDeep)
+        );
+    }
+
+    #[test]
+    fn parent_dependent_membership() {
         let alice = b"alice";
         let bob = b"bob";
+        let charlie = b"charlie";
+        let invite = |user: &[u8]| MimiProposal::ChangeRole {
+            target: user.to_vec(),
+            role: RoleIndex::Regular,
+        };
 
-        // Alice creates a public room
-        let mut room =
-            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_public()).unwrap();
+        let mut parent =
+            VerifiedRoomState::new(alice.to_vec(), RoomPolicy::default_private()).unwrap();
+        parent
+            .apply_regular_proposals(alice, &[invite(bob)])
+            .unwrap();
 
-        // Bob can join
-        room.apply_regular_proposals(
-            bob,
-            &[MimiProposal::ChangeRole {
-                target: bob.to_vec(),
+        let mut policy = RoomPolicy::default_private();
+        policy.membership_style = MembershipStyle::ParentDependent;
+        assert_eq!(
+            VerifiedRoomState::new(alice.to_vec(), policy.clone()),
+            Err(Error::ParentRoomMissing)
+        );
+        policy.parent_room_uri = TlsString("mimi://example.com/r/parent".to_owned());
+        let mut room = VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
+
+        // The parent room is required to add users
+        assert_eq!(
+            room.apply_regular_proposals(alice, &[invite(bob)]),
+            Err(Error::ParentRoomMissing)
+        );
+
+        // Bob is a member of the parent room, Charlie is not
+        assert_eq!(
+            room.classify_proposal(alice, &invite(bob), None),
+            ProposalOutcome::Denied(Error::ParentRoomMissing)
+        );
+        assert_eq!(
+            room.classify_proposal(alice, &invite(bob), Some(&parent)),
+            ProposalOutcome::WouldApply
+        );
+        assert_eq!(
+            room.validate_role_assignment(alice, charlie, RoleIndex::Regular, Some(&parent)),
+            Err(Error::UserNotInRoom)
+        );
+        assert_eq!(room.allowed_transitions(alice, bob, None), vec![]);
+        assert!(room
+            .allowed_transitions(alice, bob, Some(&parent))
+            .contains(&RoleIndex::Regular));
+        room.apply_regular_proposals_with_parent(alice, &[invite(bob)], &parent)
+            .unwrap();
+        assert_eq!(room.user_role(bob), RoleIndex::Regular);
+        assert_eq!(
+            room.apply_regular_proposals_with_parent(alice, &[invite(charlie)], &parent),
+            Err(Error::UserNotInRoom)
+        );
+    }
+
+    #[test]
+    fn policy_diff() {
+        let private = RoomPolicy::default_private();
+        let public = RoomPolicy::default_public();
+        assert!(private.diff(&private).is_empty());
+
+        let diff = private.diff(&public);
+        assert_eq!(diff.added_roles, vec![RoleIndex::Banned]);
+        assert!(diff.removed_roles.is_empty());
+        assert_eq!(diff.changed_fields, vec![PolicyField::HistorySharing]);
+        // The transitions changed, and admins and owners can unban
+        assert_eq!(
+            diff.changed_roles
+                .iter()
+                .map(|role_diff| role_diff.role)
+                .collect::<Vec<_>>(),
+            vec![RoleIndex::Outsider, RoleIndex::Admin, RoleIndex::Owner]
+        );
+        assert!(diff.changed_roles.iter().all(|role_diff| {
+            let expected = if role_diff.role == RoleIndex::Outsider {
+                vec![]
+            } else {
+                vec![Capability::UnBan]
+            };
+            role_diff.added_capabilities == expected && role_diff.removed_capabilities.is_empty()
+        }));
+
+        let reverse = public.diff(&private);
+        assert_eq!(reverse.removed_roles, vec![RoleIndex::Banned]);
+
+        let mut changed = private.clone();
+        changed.discoverable = true;
+        let regular = changed.roles.get_mut(&RoleIndex::Regular).unwrap();
+        regular.role_capabilities.remove(&Capability::SendMessage);
+        regular.role_capabilities.insert(Capability::ReactToMessage);
+        let diff = private.diff(&changed);
+        assert_eq!(diff.changed_fields, vec![PolicyField::Discoverable]);
+        assert_eq!(
+            diff.changed_roles,
+            vec![RoleDiff {
                 role: RoleIndex::Regular,
-            }],
-        )
-        .unwrap();
+                added_capabilities: vec![Capability::ReactToMessage],
+                removed_capabilities: vec![Capability::SendMessage],
+            }]
+        );
 
-        // Alice can kick bob
+        assert_eq!(diff, tls_deserialize(&tls_serialize(&diff)));
+        assert_eq!(diff, cbor_deserialize(&cbor_serialize(&diff)));
+    }
+
+    #[test]
+    fn policy_requirements() {
+        let requirements = PolicyRequirements {
+            required: vec![Capability::ReceiveMessage, Capability::DeleteAnyMessage],
+            forbidden: vec![Capability::ChangeRoomAvatar],
+        };
+
+        // The default private policy does not allow moderation
+        assert_eq!(
+            RoomPolicy::default_private().satisfies_requirements(&requirements),
+            Err(vec![
+                Unmet::Missing(Capability::DeleteAnyMessage),
+                Unmet::Forbidden {
+                    role: RoleIndex::Admin,
+                    capability: Capability::ChangeRoomAvatar,
+                },
+                Unmet::Forbidden {
+                    role: RoleIndex::Owner,
+                    capability: Capability::ChangeRoomAvatar,
+                },
+            ])
+        );
+
+        // The default DM policy only lacks moderation
+        let mut policy = RoomPolicy::default_dm();
+        assert_eq!(
+            policy.satisfies_requirements(&requirements),
+            Err(vec![Unmet::Missing(Capability::DeleteAnyMessage)])
+        );
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::DeleteAnyMessage);
+        assert_eq!(policy.satisfies_requirements(&requirements), Ok(()));
+    }
+
+    #[test]
+    fn delete_messages() {
+        let alice = b"alice";
+        let bob = b"bob";
+        let charlie = b"charlie";
+
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::DeleteOwnMessage);
+        policy
+            .roles
+            .get_mut(&RoleIndex::Admin)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::DeleteAnyMessage);
+        let mut room = VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
         room.apply_regular_proposals(
             alice,
-            &[MimiProposal::ChangeRole {
-                target: bob.to_vec(),
-                role: RoleIndex::Outsider,
-            }],
+            &[
+                MimiProposal::ChangeRole {
+                    target: bob.to_vec(),
+                    role: RoleIndex::Regular,
+                },
+                MimiProposal::ChangeRole {
+                    target: charlie.to_vec(),
+                    role: RoleIndex::Admin,
+                },
+            ],
         )
         .unwrap();
 
-        // Bob can rejoin
-        room.apply_regular_proposals(
-            bob,
-            &[MimiProposal::ChangeRole {
-                target: bob.to_vec(),
-                role: RoleIndex::Regular,
+        // Bob can only delete his own messages
+        assert!(room.can_delete_message(bob, bob));
+        assert!(!room.can_delete_message(bob, charlie));
+
+        // Charlie can only delete messages of others
+        assert!(room.can_delete_message(charlie, bob));
+        assert!(!room.can_delete_message(charlie, charlie));
+
+        // Alice can do neither
+        assert!(!room.can_delete_message(alice, alice));
+        assert!(!room.can_delete_message(alice, bob));
+    }
+
+    #[test]
+    fn policy_patch() {
+        let policy = RoomPolicy::default_private();
+        let mut discoverable = policy.clone();
+        discoverable.discoverable = true;
+
+        let patch = policy.patch(&discoverable);
+        assert_eq!(patch.changes(), &[PolicyChange::Discoverable(true)]);
+
+        // The patch is much smaller than the policy
+        let patch_bytes = tls_serialize(&patch);
+        assert_eq!(patch_bytes.len(), 3);
+        assert!(patch_bytes.len() < tls_serialize(&discoverable).len());
+
+        let patch: PolicyPatch = tls_deserialize(&patch_bytes);
+        let mut patched = policy.clone();
+        patched.apply_patch(&patch).unwrap();
+        assert_eq!(patched, discoverable);
+
+        // Role changes are patched as well
+        let public = RoomPolicy::default_public();
+        let mut patched = policy.clone();
+        patched.apply_patch(&policy.patch(&public)).unwrap();
+        assert_eq!(patched, public);
+        let mut patched = public.clone();
+        patched.apply_patch(&public.patch(&policy)).unwrap();
+        assert_eq!(patched, policy);
+
+        // Patches that make the policy inconsistent are rejected
+        let patch = PolicyPatch {
+            changes: vec![PolicyChange::RemoveRole {
+                role: RoleIndex::Outsider,
             }],
-        )
-        .unwrap();
+        };
+        let mut patched = policy.clone();
+        assert_eq!(patched.apply_patch(&patch), Err(Error::SpecialRole));
+        assert_eq!(patched, policy);
+    }
+
+    #[test]
+    fn min_active_exceeds_max_participants() {
+        let alice = b"alice";
+
+        let mut policy = RoomPolicy::default_private();
+        let admin = policy.roles.get_mut(&RoleIndex::Admin).unwrap();
+        admin.min_active_participants_constraint = 5;
+        admin.max_participants_constraint = Some(3);
+        assert_eq!(
+            VerifiedRoomState::new(alice.to_vec(), policy),
+            Err(Error::InvalidMinMaxConstraints)
+        );
+    }
+
+    #[test]
+    fn edit_messages() {
+        let alice = b"alice";
+        let bob = b"bob";
 
-        // Alice can ban bob
+        let mut policy = RoomPolicy::default_private();
+        policy
+            .roles
+            .get_mut(&RoleIndex::Regular)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::EditOwnMessage);
+        policy
+            .roles
+            .get_mut(&RoleIndex::Owner)
+            .unwrap()
+            .role_capabilities
+            .insert(Capability::EditOtherMessage);
+        let mut room = VerifiedRoomState::new(alice.to_vec(), policy).unwrap();
         room.apply_regular_proposals(
             alice,
             &[MimiProposal::ChangeRole {
                 target: bob.to_vec(),
-                role: RoleIndex::Banned,
+                role: RoleIndex::Regular,
             }],
         )
         .unwrap();
 
-        // Bob cannot rejoin
-        assert_eq!(
-            room.apply_regular_proposals(
-                bob,
-                &[MimiProposal::ChangeRole {
-                    target: bob.to_vec(),
-                    role: RoleIndex::Regular,
-                }],
-            ),
-            Err(Error::NotCapable)
-        );
+        assert!(room.can_edit_message(bob, bob));
+        assert!(!room.can_edit_message(bob, alice));
+        assert!(room.can_edit_message(alice, bob));
+        assert!(!room.can_edit_message(alice, alice));
 
-        let room2 = tls_deserialize(&tls_serialize(&room));
-        assert_eq!(room, room2);
-        let room3 = cbor_deserialize(&cbor_serialize(&room));
-        assert_eq!(room, room3);
+        // The new capability does not change the encoding of the existing ones
+        assert_eq!(tls_serialize(&Capability::SendMessage), vec![0]);
+        assert_eq!(tls_serialize(&Capability::ChangeRoleDefinitions), vec![15]);
+        assert_eq!(tls_serialize(&Capability::EditOtherMessage), vec![16]);
     }
 }
@@ -6,6 +6,40 @@ use std::{fmt, ops::Deref};
 
 use tls_codec::{DeserializeBytes, Error, Serialize, Size};
 
+/// Reject a variable-length prefix with the top bits `0b11`. Such prefixes are invalid with the `mls` feature of `tls_codec`, but debug builds of `tls_codec` panic on them instead of returning an error, so they must be rejected before untrusted input reaches `tls_codec`.
+pub(crate) fn check_length_prefix(bytes: &[u8]) -> Result<(), Error> {
+    match bytes.first() {
+        Some(byte) if byte >> 6 == 0b11 => Err(Error::InvalidVectorLength),
+        _ => Ok(()),
+    }
+}
+
+/// Decoding of map keys, map values and set elements from untrusted input. Vectors check their length prefix with [`check_length_prefix`], all other types are expected to decode their own vectors that way.
+pub trait DeserializeChecked: DeserializeBytes + Sized {
+    fn tls_deserialize_checked(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        Self::tls_deserialize_bytes(bytes)
+    }
+}
+
+impl<T: DeserializeBytes> DeserializeChecked for Vec<T> {
+    fn tls_deserialize_checked(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        check_length_prefix(bytes)?;
+        Self::tls_deserialize_bytes(bytes)
+    }
+}
+
+impl DeserializeChecked for u32 {}
+impl DeserializeChecked for u64 {}
+impl DeserializeChecked for TlsString {}
+impl DeserializeChecked for crate::RoleIndex {}
+impl DeserializeChecked for crate::RoleInfo {}
+impl DeserializeChecked for crate::Bot {}
+impl DeserializeChecked for crate::CallId {}
+impl DeserializeChecked for crate::ProposalId {}
+impl DeserializeChecked for crate::RoleExpiry {}
+impl DeserializeChecked for crate::CapabilityOverride {}
+impl DeserializeChecked for crate::PendingPolicyProposal {}
+
 pub mod bool {
     use tls_codec::{DeserializeBytes, Serialize};
 
@@ -53,6 +87,16 @@ impl Deref for TlsString {
     }
 }
 
+impl TlsString {
+    /// Create a string of at most `max` bytes.
+    pub fn try_new(s: &str, max: usize) -> Result<Self, crate::Error> {
+        if s.len() > max {
+            return Err(crate::Error::StringTooLong);
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+
 impl fmt::Display for TlsString {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -67,7 +111,7 @@ impl Size for TlsString {
 
 impl DeserializeBytes for TlsString {
     fn tls_deserialize_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
-        let (string, rest) = <Vec<u8>>::tls_deserialize_bytes(bytes)?;
+        let (string, rest) = <Vec<u8>>::tls_deserialize_checked(bytes)?;
         let string = String::from_utf8(string)
             .map_err(|_| Error::DecodingError("Couldn't decode string.".to_owned()))?;
         Ok((Self(string), rest))
@@ -80,13 +124,284 @@ impl Serialize for TlsString {
     }
 }
 
+/// Byte vectors of at most [`MAX_EXTENSION_SIZE`](crate::MAX_EXTENSION_SIZE) bytes. Longer vectors are rejected before they are copied.
+pub mod extension_value {
+    use tls_codec::{vlen::read_length, Serialize, Size};
+
+    pub fn tls_serialized_len(v: &[u8]) -> usize {
+        v.tls_serialized_len()
+    }
+
+    pub fn tls_serialize<W: std::io::Write>(
+        v: &[u8],
+        writer: &mut W,
+    ) -> Result<usize, tls_codec::Error> {
+        v.tls_serialize(writer)
+    }
+
+    pub fn tls_deserialize_bytes(mut bytes: &[u8]) -> Result<(Vec<u8>, &[u8]), tls_codec::Error> {
+        super::check_length_prefix(bytes)?;
+        let (len, _) = read_length(&mut bytes)?;
+        if len > crate::MAX_EXTENSION_SIZE {
+            return Err(tls_codec::Error::DecodingError(
+                "Policy extension too large.".to_owned(),
+            ));
+        }
+        if bytes.len() < len {
+            return Err(tls_codec::Error::EndOfStream);
+        }
+        let (value, rest) = bytes.split_at(len);
+        Ok((value.to_vec(), rest))
+    }
+}
+
+/// Vectors decoded with [`DeserializeChecked`].
+pub mod vec {
+    use tls_codec::{DeserializeBytes, Serialize, Size};
+
+    use super::DeserializeChecked;
+
+    pub fn tls_serialized_len<T: Size>(v: &[T]) -> usize {
+        v.tls_serialized_len()
+    }
+
+    pub fn tls_serialize<T, W>(v: &[T], writer: &mut W) -> Result<usize, tls_codec::Error>
+    where
+        T: Serialize + std::fmt::Debug,
+        W: std::io::Write,
+    {
+        v.tls_serialize(writer)
+    }
+
+    pub fn tls_deserialize_bytes<T: DeserializeBytes>(
+        bytes: &[u8],
+    ) -> Result<(Vec<T>, &[u8]), tls_codec::Error> {
+        Vec::tls_deserialize_checked(bytes)
+    }
+}
+
+/// Optional vectors decoded with [`DeserializeChecked`].
+pub mod optional_vec {
+    use tls_codec::{DeserializeBytes, Serialize, Size};
+
+    pub fn tls_serialized_len<T: Size>(v: &Option<Vec<T>>) -> usize {
+        v.tls_serialized_len()
+    }
+
+    pub fn tls_serialize<T, W>(
+        v: &Option<Vec<T>>,
+        writer: &mut W,
+    ) -> Result<usize, tls_codec::Error>
+    where
+        T: Serialize + std::fmt::Debug,
+        W: std::io::Write,
+    {
+        v.tls_serialize(writer)
+    }
+
+    pub fn tls_deserialize_bytes<T: DeserializeBytes>(
+        bytes: &[u8],
+    ) -> Result<(Option<Vec<T>>, &[u8]), tls_codec::Error> {
+        let (present, rest) = u8::tls_deserialize_bytes(bytes)?;
+        match present {
+            0 => Ok((None, rest)),
+            1 => {
+                let (v, rest) = super::vec::tls_deserialize_bytes(rest)?;
+                Ok((Some(v), rest))
+            }
+            _ => Err(tls_codec::Error::DecodingError(
+                "Invalid option presence byte.".to_owned(),
+            )),
+        }
+    }
+}
+
+pub mod btreeset {
+    use std::{collections::BTreeSet, io};
+    use tls_codec::{
+        vlen::{read_length, write_length},
+        Serialize, Size,
+    };
+
+    use super::DeserializeChecked;
+
+    pub fn tls_serialized_len<T>(v: &BTreeSet<T>) -> usize
+    where
+        T: Size,
+    {
+        let content_len = v.iter().map(|e| e.tls_serialized_len()).sum();
+        let len_len = write_length(&mut io::empty(), content_len).unwrap_or(0);
+        content_len + len_len
+    }
+
+    pub fn tls_serialize<T, W>(v: &BTreeSet<T>, writer: &mut W) -> Result<usize, tls_codec::Error>
+    where
+        T: Serialize,
+        W: io::Write,
+    {
+        // Same wire format as a vector of the elements in ascending order.
+        let content_length = v.iter().map(|e| e.tls_serialized_len()).sum();
+        let len_len = write_length(writer, content_length)?;
+
+        let mut written = 0;
+        for e in v.iter() {
+            written += e.tls_serialize(writer)?;
+        }
+        if written != content_length {
+            return Err(tls_codec::Error::LibraryError);
+        }
+
+        Ok(content_length + len_len)
+    }
+
+    pub fn tls_deserialize_bytes<T>(
+        mut bytes: &[u8],
+    ) -> Result<(BTreeSet<T>, &[u8]), tls_codec::Error>
+    where
+        T: DeserializeChecked + Ord,
+    {
+        super::check_length_prefix(bytes)?;
+        let (len, len_len) = read_length(&mut bytes)?;
+        if len == 0 {
+            return Ok((BTreeSet::new(), bytes));
+        }
+
+        let mut result = BTreeSet::new();
+        let mut read = len_len;
+        while (read - len_len) < len {
+            let (element, remainder) = T::tls_deserialize_checked(bytes)?;
+            bytes = remainder;
+            read += element.tls_serialized_len();
+
+            result.insert(element);
+        }
+        Ok((result, bytes))
+    }
+}
+
+/// Like [`btreemap`], but for maps whose values are [`BTreeSet`](std::collections::BTreeSet)s.
+pub mod btreemap_btreeset {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        io,
+    };
+    use tls_codec::{
+        vlen::{read_length, write_length},
+        Serialize, Size,
+    };
+
+    use super::DeserializeChecked;
+
+    use super::btreeset;
+
+    type Map<K, V> = BTreeMap<K, BTreeSet<V>>;
+
+    fn content_len<K, V>(v: &BTreeMap<K, BTreeSet<V>>) -> usize
+    where
+        K: Size,
+        V: Size,
+    {
+        v.iter()
+            .map(|(k, v)| k.tls_serialized_len() + btreeset::tls_serialized_len(v))
+            .sum()
+    }
+
+    pub fn tls_serialized_len<K, V>(v: &BTreeMap<K, BTreeSet<V>>) -> usize
+    where
+        K: Size,
+        V: Size,
+    {
+        let content_len = content_len(v);
+        let len_len = write_length(&mut io::empty(), content_len).unwrap_or(0);
+        content_len + len_len
+    }
+
+    pub fn tls_serialize<K, V, W>(
+        v: &BTreeMap<K, BTreeSet<V>>,
+        writer: &mut W,
+    ) -> Result<usize, tls_codec::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        W: io::Write,
+    {
+        let content_length = content_len(v);
+        let len_len = write_length(writer, content_length)?;
+
+        let mut written = 0;
+        for (k, v) in v.iter() {
+            written += k.tls_serialize(writer)?;
+            written += btreeset::tls_serialize(v, writer)?;
+        }
+        if written != content_length {
+            return Err(tls_codec::Error::LibraryError);
+        }
+
+        Ok(content_length + len_len)
+    }
+
+    pub fn tls_deserialize_bytes<K, V>(
+        mut bytes: &[u8],
+    ) -> Result<(Map<K, V>, &[u8]), tls_codec::Error>
+    where
+        K: DeserializeChecked + Ord,
+        V: DeserializeChecked + Ord,
+    {
+        super::check_length_prefix(bytes)?;
+        let (len, len_len) = read_length(&mut bytes)?;
+        if len == 0 {
+            return Ok((BTreeMap::new(), bytes));
+        }
+
+        let mut result = BTreeMap::new();
+        let mut read = len_len;
+        while (read - len_len) < len {
+            let (key, key_remainder) = K::tls_deserialize_checked(bytes)?;
+            bytes = key_remainder;
+            read += key.tls_serialized_len();
+
+            let (value, value_remainder) = btreeset::tls_deserialize_bytes(bytes)?;
+            bytes = value_remainder;
+            read += btreeset::tls_serialized_len(&value);
+
+            result.insert(key, value);
+        }
+        Ok((result, bytes))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_tls_serde_btreemap_btreeset() {
+            let mut map: BTreeMap<u64, BTreeSet<u32>> = Default::default();
+            map.insert(1u64, [3u32, 1].into_iter().collect());
+            map.insert(2u64, BTreeSet::new());
+
+            let mut buf = Vec::new();
+            let len = tls_serialize(&map, &mut buf).unwrap();
+            // varint(4) + 1 + varint(8) + 1 + 3 + 2 + varint(0)
+            // 1 + 8 + 1 + 4 + 4 + 8 + 1
+            assert_eq!(len, 27);
+            assert_eq!(len, tls_serialized_len(&map));
+            let (map2, remainder) = tls_deserialize_bytes(&buf).unwrap();
+            assert_eq!(map, map2);
+            assert_eq!(remainder.len(), 0);
+        }
+    }
+}
+
+/// TLS encoding for [`BTreeMap`](std::collections::BTreeMap)s. Entries are encoded in ascending key order, so equal maps have the same encoding regardless of the order the entries were inserted in. Canonical hashes and diffs of policies rely on this.
 pub mod btreemap {
     use std::{collections::BTreeMap, io};
     use tls_codec::{
         vlen::{read_length, write_length},
-        DeserializeBytes, Serialize, Size,
+        Serialize, Size,
     };
 
+    use super::DeserializeChecked;
+
     pub fn tls_serialized_len<K, V>(v: &BTreeMap<K, V>) -> usize
     where
         K: Size,
@@ -147,9 +462,10 @@ pub mod btreemap {
         mut bytes: &[u8],
     ) -> Result<(BTreeMap<K, V>, &[u8]), tls_codec::Error>
     where
-        K: DeserializeBytes + Ord,
-        V: DeserializeBytes,
+        K: DeserializeChecked + Ord,
+        V: DeserializeChecked,
     {
+        super::check_length_prefix(bytes)?;
         let (len, len_len) = read_length(&mut bytes)?;
         if len == 0 {
             return Ok((BTreeMap::new(), bytes));
@@ -158,11 +474,11 @@ pub mod btreemap {
         let mut result = BTreeMap::new();
         let mut read = len_len;
         while (read - len_len) < len {
-            let (key, key_remainder) = K::tls_deserialize_bytes(bytes)?;
+            let (key, key_remainder) = K::tls_deserialize_checked(bytes)?;
             bytes = key_remainder;
             read += key.tls_serialized_len();
 
-            let (value, value_remainder) = V::tls_deserialize_bytes(bytes)?;
+            let (value, value_remainder) = V::tls_deserialize_checked(bytes)?;
             bytes = value_remainder;
             read += value.tls_serialized_len();
 
@@ -173,7 +489,7 @@ pub mod btreemap {
 
     #[cfg(test)]
     mod tests {
-        use crate::tls::TlsString;
+        use crate::{tls::TlsString, RoleIndex};
 
         use super::*;
 
@@ -204,5 +520,39 @@ pub mod btreemap {
             assert_eq!(map, map2);
             assert_eq!(remainder.len(), 0);
         }
+
+        #[test]
+        fn test_tls_btreemap_insertion_order() {
+            let entries = [
+                (RoleIndex::Custom(17), TlsString("seventeen".to_owned())),
+                (RoleIndex::Owner, TlsString("owner".to_owned())),
+                (RoleIndex::Outsider, TlsString("outsider".to_owned())),
+                (RoleIndex::Custom(2), TlsString("two".to_owned())),
+                (RoleIndex::Regular, TlsString("regular".to_owned())),
+                (RoleIndex::Custom(u32::MAX), TlsString("max".to_owned())),
+                (RoleIndex::Banned, TlsString("banned".to_owned())),
+                (RoleIndex::Admin, TlsString("admin".to_owned())),
+            ];
+            let encode = |entries: &[(RoleIndex, TlsString)]| {
+                let mut map = BTreeMap::new();
+                for (key, value) in entries {
+                    map.insert(*key, value.clone());
+                }
+                let mut buf = Vec::new();
+                tls_serialize(&map, &mut buf).unwrap();
+                buf
+            };
+
+            let expected = encode(&entries);
+            let mut reversed = entries.clone();
+            reversed.reverse();
+            for scrambled in [entries.clone(), reversed] {
+                for rotation in 0..scrambled.len() {
+                    let mut scrambled = scrambled.clone();
+                    scrambled.rotate_left(rotation);
+                    assert_eq!(encode(&scrambled), expected);
+                }
+            }
+        }
     }
 }